@@ -1,39 +1,123 @@
 //! This module implements fibonnaci sequence
-use clap::Parser;
+use clap::{CommandFactory, Parser};
+use clap_complete::Shell;
+use std::fs;
+use std::io::{self, Read};
+use std::path::{Path, PathBuf};
 
 ///Implemente parsers functionnalities
 #[derive(Parser)]
-#[clap(author, version, about, long_about = None)]
-
+#[clap(author, version, about, long_about = None, after_help = EXAMPLES)]
 ///Args structure to implement flags options and input value
 struct Args {
     #[clap(short, long)]
-
     verbose: bool, //type for flags is bool
-    value: u32, //type for <VALUE> is u32
+    #[clap(required_unless_present_any = ["stdin", "completions"])]
+    value: Option<u32>, //type for <VALUE> is u32, not needed when indices are read on stdin
 
     #[clap(short='m', long="min", value_name="NUMBER")] //min value of the sequence
     min: Option<u32>, //min value of sequence 
+
+    #[clap(short, long, conflicts_with = "approx")] //prints fibo(value+1)/fibo(value) instead of fibo(value)
+    ratio: bool,
+
+    #[clap(short, long, value_name="DIGITS", default_value_t = 10)] //number of decimals printed in ratio mode
+    precision: usize,
+
+    #[clap(short, long)] //approximates fibo(value) with Binet's formula
+    approx: bool,
+
+    #[clap(long, requires = "approx")] //allows approximations beyond APPROX_MAX_N
+    force: bool,
+
+    #[clap(long, conflicts_with_all = &["value", "ratio", "approx"])] //reads whitespace separated indices on standard input and prints one term per index
+    stdin: bool,
+
+    #[clap(long, value_name="PATH")] //file where computed terms are kept between invocations
+    cache: Option<PathBuf>,
+
+    #[clap(long, value_name="SHELL", value_parser, exclusive = true)] //prints a completion script for bash, zsh, fish, elvish or powershell
+    completions: Option<Shell>,
 }
 
+///Examples shown at the end of --help
+const EXAMPLES: &str = "EXAMPLES:
+    fibo 20                  prints fibo(20)
+    fibo -v -m 10 20         prints fibo(10) to fibo(20)
+    fibo -r -p 30 40         prints fibo(41)/fibo(40) with 30 decimals
+    fibo -a 60               compares Binet's formula with fibo(60)
+    echo 3 10 40 | fibo --stdin --cache fibo.cache
+                             prints the requested terms, keeping them in fibo.cache
+    fibo --completions bash > /etc/bash_completion.d/fibo";
+
 ///Main function prints fibonnaci terms which are available
 fn main() {
     let args = Args::parse(); //import structure Args
 
-    let min : u32; //min value must be sent as an u32
+    if let Some(shell) = args.completions { //completion scripts are generated from the clap definition of Args
+        clap_complete::generate(shell, &mut Args::command(), "fibo", &mut io::stdout());
+        return;
+    }
 
     let verbose = args.verbose;
 
-    match args.min {
-        Some(m) => min = m, 
-        None => min = 0, //if user doesn't set a min the default value is 0
+    let min = args.min.unwrap_or(0); //if user doesn't set a min the default value is 0
+
+    if args.stdin { //batch mode answers every query through the memo table
+        let mut memo = match &args.cache {
+            Some(path) => Memo::load(path),
+            None => Memo::new(),
+        };
+        let mut input = String::new();
+        if let Err(e) = io::stdin().read_to_string(&mut input) {
+            eprintln!("Could not read standard input: {}", e);
+            std::process::exit(1);
+        }
+        for query in input.split_whitespace() {
+            match query.parse::<u32>() {
+                Ok(i) => match memo.get(i) {
+                    Some(x) => println!("fibo({:?}) = {:?}", i, Some(x)),
+                    None => println!("fibo({:?}) could not be calculated due to an overflow", i),
+                },
+                Err(_) => eprintln!("Ignoring invalid index {:?}", query),
+            }
+        }
+        if verbose {
+            eprintln!("{} queries answered from the memo table", memo.hits());
+        }
+        if let Some(path) = &args.cache {
+            if let Err(e) = memo.save(path) {
+                eprintln!("warning: could not write cache {}: {}", path.display(), e);
+            }
+        }
+        return;
     }
 
-    let value = args.value; //value is max term of fibonnaci user has sent
+    let value = args.value.unwrap(); //value is max term of fibonnaci user has sent, required by clap outside of stdin mode
 
-    if verbose { //if verbose true intermediar values are printed
+    if args.approx { //approx mode compares Binet's formula with the exact value
+        let approx = match fibo_approx(value, args.force) {
+            Ok(approx) => approx,
+            Err(e) => {
+                eprintln!("{}", e);
+                std::process::exit(1);
+            }
+        };
+        match fibo_wide(value) {
+            Some(exact) => println!("fibo({:?}) ~ {:?} (exact {:?}, error {:e})", value, approx, exact, (approx - exact as f64).abs()),
+            None => println!("fibo({:?}) ~ {:?}", value, approx),
+        }
+    } else if args.ratio { //ratio mode approximates the golden ratio
+        match fibo_ratio(value, args.precision) {
+            Ok(ratio) => println!("fibo({:?})/fibo({:?}) = {}", u64::from(value) + 1, value, ratio), //widened so value + 1 cannot overflow
+            Err(e) => {
+                eprintln!("{}", e);
+                std::process::exit(1);
+            }
+        }
+    } else if verbose { //if verbose true intermediar values are printed
         for i in min..=value{
-            if fibo(i)==None {
+            if fibo(i).is_none() {
                 continue; //None are not printed
             }
             else {
@@ -41,7 +125,7 @@ fn main() {
             }
         }
     } else { //if verbose is false only the last value (if existing) is returned
-        if fibo(value) == None {
+        if fibo(value).is_none() {
             println!("Result could not be calculated due to an overflow")
         } else {
             println!("fibo({:?}) = {:?}",value,fibo(value)) 
@@ -72,3 +156,283 @@ fn fibo(n: u32) -> Option<u32> {
     }
     v //return value is v which is Option<u32> type
 }
+
+///This function returns the fibonacci sum computed on 128 bits or None if an overflow occurs
+fn fibo_wide(n: u32) -> Option<u128> {
+    let mut x1: u128 = 0;
+    let mut x2: u128 = 1;
+    for _ in 0..n {
+        let x = x1.checked_add(x2)?; //same recurrence as fibo() with a wider integer
+        x2 = x1;
+        x1 = x;
+    }
+    Some(x1)
+}
+
+///This function returns fibo(n+1)/fibo(n) written as a decimal with `precision` digits
+///Digits are computed by long division on integers so they are all exact (truncated, not rounded)
+fn fibo_ratio(n: u32, precision: usize) -> Result<String, String> {
+    if n == 0 {
+        return Err(String::from("Ratio could not be calculated: fibo(0) = 0 (division by zero)"));
+    }
+    let overflow = || format!("Ratio could not be calculated due to an overflow for n = {}", n);
+    let num = n.checked_add(1).and_then(fibo_wide).ok_or_else(overflow)?; //n + 1 itself may overflow
+    let den = fibo_wide(n).ok_or_else(overflow)?;
+
+    let mut ratio = format!("{}", num / den); //integer part
+    let mut rem = num % den;
+    if precision > 0 {
+        ratio.push('.');
+    }
+    for _ in 0..precision {
+        rem = rem.checked_mul(10).ok_or_else(overflow)?; //next digit of the long division
+        ratio.push(char::from(b'0' + (rem / den) as u8));
+        rem %= den;
+    }
+    Ok(ratio)
+}
+
+///Largest n for which Binet's formula computed with f64 still rounds to the exact term
+///
+///Around fibo(70) ~ 1.9e14 the absolute error of the f64 powers gets close to 0.5, so the
+///nearest integer of the approximation may be wrong (the first wrong rounding is at n = 76).
+const APPROX_MAX_N: u32 = 70;
+
+///This function approximates fibo(n) with Binet's closed form phi^n / sqrt(5)
+///The (-1/phi)^n term is kept although it is negligible for large n
+///None is returned if n is above i32::MAX, the largest exponent accepted by powi
+fn fibo_binet(n: u32) -> Option<f64> {
+    let n = i32::try_from(n).ok()?;
+    let sqrt5 = 5f64.sqrt();
+    let phi = (1.0 + sqrt5) / 2.0;
+    let psi = (1.0 - sqrt5) / 2.0;
+    Some((phi.powi(n) - psi.powi(n)) / sqrt5)
+}
+
+///This function returns fibo_binet(n), refusing n above APPROX_MAX_N unless `force` is set
+fn fibo_approx(n: u32, force: bool) -> Result<f64, String> {
+    if n > APPROX_MAX_N && !force {
+        return Err(format!("Approximation refused for n > {}: f64 rounding is unreliable (use --force)", APPROX_MAX_N));
+    }
+    fibo_binet(n).ok_or_else(|| format!("Approximation refused for n > {}: the exponent does not fit in an i32", i32::MAX))
+}
+
+///Magic number at the beginning of a cache file
+const CACHE_MAGIC: &[u8; 4] = b"FIBC";
+///Version of the cache file format
+const CACHE_VERSION: u8 = 1;
+///Width in bits of the terms stored in a cache file
+const CACHE_WIDTH: u8 = 32;
+
+///Table of the terms already computed, reused across queries of one invocation
+///
+///The cache file format is the magic `FIBC`, a version byte, a width byte, the number of
+///terms as a little endian u32 and then every term as a little endian u32.
+struct Memo {
+    terms: Vec<u32>, //terms[i] is fibo(i)
+    hits: u64, //number of queries answered without computing anything
+}
+
+impl Memo {
+    ///Creates an empty table only containing fibo(0) and fibo(1)
+    fn new() -> Self {
+        Memo { terms: vec![0, 1], hits: 0 }
+    }
+
+    ///Creates a table from a cache file, or an empty one with a warning if the file is unusable
+    fn load(path: &Path) -> Self {
+        let bytes = match fs::read(path) {
+            Ok(bytes) => bytes,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Memo::new(), //first run
+            Err(e) => {
+                eprintln!("warning: ignoring cache {}: {}", path.display(), e);
+                return Memo::new();
+            }
+        };
+        match Memo::decode(&bytes) {
+            Some(terms) => Memo { terms, hits: 0 },
+            None => {
+                eprintln!("warning: ignoring corrupt cache {}", path.display());
+                Memo::new()
+            }
+        }
+    }
+
+    ///Decodes the terms of a cache file, returns None if the header or the terms are invalid
+    fn decode(bytes: &[u8]) -> Option<Vec<u32>> {
+        if bytes.len() < 10 || &bytes[..4] != CACHE_MAGIC || bytes[4] != CACHE_VERSION || bytes[5] != CACHE_WIDTH {
+            return None;
+        }
+        let len = u32::from_le_bytes(bytes[6..10].try_into().unwrap()) as usize;
+        let payload = &bytes[10..];
+        if len < 2 || payload.len() != len * 4 {
+            return None;
+        }
+        let terms: Vec<u32> = payload
+            .chunks_exact(4)
+            .map(|b| u32::from_le_bytes(b.try_into().unwrap()))
+            .collect();
+        //a table which does not follow the recurrence is rejected instead of giving wrong answers
+        let valid = terms[0] == 0 && terms[1] == 1 && terms.windows(3).all(|w| w[0].checked_add(w[1]) == Some(w[2]));
+        valid.then_some(terms)
+    }
+
+    ///Writes the table to a cache file
+    fn save(&self, path: &Path) -> io::Result<()> {
+        let mut bytes = Vec::with_capacity(10 + 4 * self.terms.len());
+        bytes.extend_from_slice(CACHE_MAGIC);
+        bytes.push(CACHE_VERSION);
+        bytes.push(CACHE_WIDTH);
+        bytes.extend_from_slice(&(self.terms.len() as u32).to_le_bytes());
+        for term in &self.terms {
+            bytes.extend_from_slice(&term.to_le_bytes());
+        }
+        fs::write(path, bytes)
+    }
+
+    ///Returns fibo(n), growing the table if needed, or None if an overflow occurs
+    fn get(&mut self, n: u32) -> Option<u32> {
+        let n = n as usize;
+        if n < self.terms.len() {
+            self.hits += 1;
+            return Some(self.terms[n]);
+        }
+        while self.terms.len() <= n {
+            let len = self.terms.len();
+            let next = self.terms[len - 2].checked_add(self.terms[len - 1])?; //the table stops at the first overflow
+            self.terms.push(next);
+        }
+        Some(self.terms[n])
+    }
+
+    ///Returns the number of queries answered from the table
+    fn hits(&self) -> u64 {
+        self.hits
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn ratio_approximates_phi() {
+        let ratio = fibo_ratio(50, 50).unwrap();
+        assert!(ratio.starts_with("1.6180339887")); //first 10 digits of phi
+        assert_eq!(ratio, "1.61803398874989484820740990001204904326284254042472"); //fibo(51)/fibo(50)
+    }
+
+    #[test]
+    fn ratio_without_decimals() {
+        assert_eq!(fibo_ratio(1, 0).unwrap(), "1");
+        assert_eq!(fibo_ratio(4, 3).unwrap(), "1.666"); //5/3 is truncated, not rounded
+    }
+
+    #[test]
+    fn ratio_refuses_n_zero() {
+        assert!(fibo_ratio(0, 10).unwrap_err().contains("division by zero"));
+    }
+
+    #[test]
+    fn ratio_reports_overflow() {
+        assert!(fibo_ratio(u32::MAX, 10).unwrap_err().contains("overflow"));
+    }
+
+    #[test]
+    fn binet_rounds_to_exact_terms() {
+        for n in 0..=APPROX_MAX_N {
+            let approx = fibo_approx(n, false).unwrap();
+            assert_eq!(approx.round() as u128, fibo_wide(n).unwrap(), "n = {}", n);
+        }
+    }
+
+    #[test]
+    fn binet_refuses_beyond_cutoff() {
+        assert!(fibo_approx(APPROX_MAX_N + 1, false).unwrap_err().contains("--force"));
+        assert!(fibo_approx(APPROX_MAX_N + 1, true).is_ok());
+        assert!(fibo_approx(i32::MAX as u32 + 1, true).unwrap_err().contains("i32"));
+    }
+
+    #[test]
+    fn completions_for_every_shell() {
+        Args::command().debug_assert();
+        for shell in [Shell::Bash, Shell::Zsh, Shell::Fish, Shell::Elvish, Shell::PowerShell] {
+            let mut script = Vec::new();
+            clap_complete::generate(shell, &mut Args::command(), "fibo", &mut script);
+            let script = String::from_utf8(script).unwrap();
+            assert!(script.contains("ratio"), "{:?}", shell); //every shell completes the long flags
+        }
+    }
+
+    #[test]
+    fn conflicting_flags_are_rejected() {
+        use clap::ErrorKind;
+        let rejected: [(&[&str], ErrorKind); 6] = [
+            (&["-r", "-a", "10"], ErrorKind::ArgumentConflict),
+            (&["--stdin", "10"], ErrorKind::ArgumentConflict),
+            (&["--stdin", "-r"], ErrorKind::ArgumentConflict),
+            (&["--stdin", "-a"], ErrorKind::ArgumentConflict),
+            (&["--completions", "bash", "10"], ErrorKind::ArgumentConflict),
+            (&["--force", "10"], ErrorKind::MissingRequiredArgument),
+        ];
+        for (flags, kind) in rejected {
+            let args = std::iter::once("fibo").chain(flags.iter().copied());
+            match Args::try_parse_from(args) {
+                Ok(_) => panic!("{:?} accepted", flags),
+                Err(e) => assert_eq!(e.kind(), kind, "{:?}", flags),
+            }
+        }
+        assert!(Args::try_parse_from(["fibo", "-a", "--force", "80"]).is_ok());
+        assert!(Args::try_parse_from(["fibo", "--stdin", "--cache", "fibo.cache"]).is_ok());
+    }
+
+    ///Returns a cache path in the temporary directory, unique to this test process
+    fn temp_cache(name: &str) -> PathBuf {
+        std::env::temp_dir().join(format!("fibo-{}-{}.cache", std::process::id(), name))
+    }
+
+    #[test]
+    fn memo_counts_hits() {
+        let mut memo = Memo::new();
+        for i in [10, 10, 5, 10, 0] {
+            assert_eq!(memo.get(i), fibo(i));
+        }
+        assert_eq!(memo.hits(), 4); //only the first query computed anything
+        assert_eq!(memo.get(48), None); //fibo(48) overflows a u32
+        assert_eq!(memo.get(47), fibo(47)); //the table stopped right before the overflow
+    }
+
+    #[test]
+    fn memo_cache_round_trip() {
+        let path = temp_cache("round-trip");
+        let mut memo = Memo::new();
+        assert_eq!(memo.get(30), Some(832040));
+        memo.save(&path).unwrap();
+
+        let mut loaded = Memo::load(&path);
+        fs::remove_file(&path).unwrap();
+        assert_eq!(loaded.terms, memo.terms);
+        assert_eq!(loaded.get(30), Some(832040));
+        assert_eq!(loaded.hits(), 1);
+    }
+
+    #[test]
+    fn memo_ignores_corrupt_cache() {
+        let path = temp_cache("corrupt");
+        let mut memo = Memo::new();
+        memo.get(20);
+        memo.save(&path).unwrap();
+        let mut bytes = fs::read(&path).unwrap();
+        let last = bytes.len() - 1;
+        bytes[last] ^= 1; //fibo(20) no longer follows the recurrence
+        fs::write(&path, &bytes).unwrap();
+
+        let loaded = Memo::load(&path);
+        fs::write(&path, b"not a cache").unwrap();
+        let garbage = Memo::load(&path);
+        fs::remove_file(&path).unwrap();
+        assert_eq!(loaded.terms, vec![0, 1]);
+        assert_eq!(garbage.terms, vec![0, 1]);
+        assert_eq!(Memo::load(&path).terms, vec![0, 1]); //missing file
+    }
+}
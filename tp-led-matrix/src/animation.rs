@@ -0,0 +1,9 @@
+//! This module defines animations producing one frame at a time
+
+use crate::Image;
+
+/// Source of successive frames
+pub trait Animation {
+    /// Writes the next frame of the animation into `frame`
+    fn next_frame(&mut self, frame: &mut Image);
+}
@@ -0,0 +1,239 @@
+//! This module sends a captured frame over the serial port a few bytes at a time
+//!
+//! The frame is copied into the sender at once, then a low priority task hands the
+//! bytes to the transmitter whenever it is ready, so the display is never delayed.
+//!
+//! XON and XOFF can be sent between any two bytes of a frame, so the frame never
+//! contains them: a pixel byte equal to XON, XOFF or [ESC] is sent as [ESC]
+//! followed by the byte XORed with 0x20. [CaptureReceiver] drops the flow control
+//! bytes and undoes the escapes on the host.
+
+use crate::flow::{XOFF, XON};
+use crate::protocol::FRAME_LEN;
+use crate::Image;
+
+/// Byte announcing that the next byte of a captured frame is escaped
+pub const ESC: u8 = 0x7d;
+/// Value XORed with an escaped byte, so the sent byte is neither XON, XOFF nor ESC
+const ESC_MASK: u8 = 0x20;
+
+/// Largest number of bytes of an encoded frame, when every pixel byte is escaped
+pub const CAPTURE_LEN: usize = 1 + 2 * FRAME_LEN;
+
+/// Returns true if a byte of a captured frame has to be escaped
+fn needs_escape(b: u8) -> bool {
+    b == XON || b == XOFF || b == ESC
+}
+
+/// Transmission state machine of a captured frame
+pub struct CaptureSender {
+    buffer: [u8; CAPTURE_LEN],
+    /// Number of bytes of the frame being sent
+    len: usize,
+    /// Position of the next byte to send, equal to `len` when idle
+    pos: usize,
+}
+
+/// Implements functions for capture sender structure
+impl CaptureSender {
+    /// Creates an idle sender
+    pub const fn new() -> Self {
+        CaptureSender {
+            buffer: [0; CAPTURE_LEN],
+            len: 0,
+            pos: 0,
+        }
+    }
+
+    /// Returns true while a frame is being sent
+    pub fn is_busy(&self) -> bool {
+        self.pos < self.len
+    }
+
+    /// Starts sending an image encoded with [Image::encode_se203], with the flow
+    /// control bytes escaped. A capture in progress is not interrupted, false is
+    /// returned and the image is dropped.
+    pub fn start(&mut self, image: &Image) -> bool {
+        if self.is_busy() {
+            return false;
+        }
+        let mut len = 0;
+        for b in image.encode_se203() {
+            if needs_escape(b) {
+                self.buffer[len] = ESC;
+                self.buffer[len + 1] = b ^ ESC_MASK;
+                len += 2;
+            } else {
+                self.buffer[len] = b;
+                len += 1;
+            }
+        }
+        self.len = len;
+        self.pos = 0;
+        true
+    }
+
+    /// Returns the next byte to send, without consuming it
+    pub fn peek(&self) -> Option<u8> {
+        self.buffer[..self.len].get(self.pos).copied()
+    }
+
+    /// Consumes the byte returned by [peek](CaptureSender::peek) once it was accepted
+    /// by the transmitter
+    pub fn advance(&mut self) {
+        if self.is_busy() {
+            self.pos += 1;
+        }
+    }
+
+    /// Hands bytes to `write` until it refuses one or the frame is complete, returns
+    /// true if bytes remain to be sent
+    pub fn send_with<E>(&mut self, mut write: impl FnMut(u8) -> Result<(), E>) -> bool {
+        while let Some(b) = self.peek() {
+            if write(b).is_err() {
+                break;
+            }
+            self.advance();
+        }
+        self.is_busy()
+    }
+}
+
+/// Implements default function for capture sender type objects
+impl Default for CaptureSender {
+    fn default() -> Self {
+        CaptureSender::new()
+    }
+}
+
+/// Host side of a capture, turning the bytes received from the board back into
+/// the SE203 frame encoded by [CaptureSender]
+pub struct CaptureReceiver {
+    /// True if the previous byte of the frame was [ESC]
+    escaped: bool,
+}
+
+/// Implements functions for capture receiver structure
+impl CaptureReceiver {
+    /// Creates a receiver outside of an escape
+    pub const fn new() -> Self {
+        CaptureReceiver { escaped: false }
+    }
+
+    /// Handles one received byte and returns the frame byte it completes, if any.
+    /// XON and XOFF are dropped, even between [ESC] and the escaped byte.
+    pub fn push(&mut self, b: u8) -> Option<u8> {
+        if b == XON || b == XOFF {
+            None
+        } else if self.escaped {
+            self.escaped = false;
+            Some(b ^ ESC_MASK)
+        } else if b == ESC {
+            self.escaped = true;
+            None
+        } else {
+            Some(b)
+        }
+    }
+}
+
+/// Implements default function for capture receiver type objects
+impl Default for CaptureReceiver {
+    fn default() -> Self {
+        CaptureReceiver::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::protocol::ProtocolDecoder;
+
+    /// Image containing every byte value below 255, the flow control bytes and ESC included
+    fn test_image() -> Image {
+        let mut bytes = [0; FRAME_LEN];
+        for (i, b) in bytes.iter_mut().enumerate() {
+            *b = [XON, XOFF, ESC, 0, 254][i % 5].wrapping_add((i / 5) as u8 % 2 * 64);
+        }
+        Image::from_bytes(&bytes)
+    }
+
+    /// Collects the bytes sent by `sender`, accepting at most `chunk` bytes per call
+    fn send_in_chunks(sender: &mut CaptureSender, chunk: usize) -> heapless::Vec<u8, CAPTURE_LEN> {
+        let mut sent = heapless::Vec::new();
+        let mut calls = 0;
+        loop {
+            let mut accepted = 0;
+            let pending = sender.send_with(|b| {
+                if accepted == chunk {
+                    return Err(());
+                }
+                accepted += 1;
+                sent.push(b).map_err(|_| ())
+            });
+            calls += 1;
+            if !pending {
+                return sent;
+            }
+            assert!(calls <= CAPTURE_LEN, "the sender does not progress");
+        }
+    }
+
+    #[test]
+    fn escaped_frame_has_no_flow_control_byte() {
+        let mut sender = CaptureSender::new();
+        assert!(sender.start(&test_image()));
+        let sent = send_in_chunks(&mut sender, CAPTURE_LEN);
+        assert!(sent.len() > 1 + FRAME_LEN);
+        assert!(!sent.contains(&XON) && !sent.contains(&XOFF));
+    }
+
+    #[test]
+    fn refusing_writer_resumes_where_it_stopped() {
+        let mut whole = CaptureSender::new();
+        whole.start(&test_image());
+        let expected = send_in_chunks(&mut whole, CAPTURE_LEN);
+        for chunk in [1, 7, 64] {
+            let mut sender = CaptureSender::new();
+            assert!(sender.start(&test_image()));
+            assert!(sender.send_with(|_| Err(())));
+            assert_eq!(sender.peek(), Some(expected[0]));
+            assert_eq!(send_in_chunks(&mut sender, chunk), expected);
+            assert!(!sender.is_busy());
+        }
+    }
+
+    #[test]
+    fn capture_in_progress_is_not_interrupted() {
+        let mut sender = CaptureSender::new();
+        assert!(sender.start(&test_image()));
+        sender.send_with(|_| Err::<(), ()>(()));
+        assert!(!sender.start(&Image::default()));
+        let sent = send_in_chunks(&mut sender, 10);
+        assert!(sender.start(&Image::default()));
+        // The second frame only has zeros, nothing to escape
+        assert_eq!(send_in_chunks(&mut sender, 10).len(), 1 + FRAME_LEN);
+        assert!(sent.len() > 1 + FRAME_LEN);
+    }
+
+    #[test]
+    fn captured_frame_decodes_to_the_exact_image() {
+        let image = test_image();
+        let mut sender = CaptureSender::new();
+        sender.start(&image);
+        let sent = send_in_chunks(&mut sender, 5);
+        let mut receiver = CaptureReceiver::new();
+        let mut decoder = ProtocolDecoder::new();
+        let mut decoded = None;
+        for (i, &b) in sent.iter().enumerate() {
+            // Flow control bytes written by the board between any two bytes
+            for control in [XOFF, XON].into_iter().filter(|_| i % 3 == 0) {
+                assert_eq!(receiver.push(control), None);
+            }
+            if let Some(frame) = receiver.push(b).and_then(|b| decoder.push(b)) {
+                decoded = Some(frame);
+            }
+        }
+        assert_eq!(decoded, Some(image));
+    }
+}
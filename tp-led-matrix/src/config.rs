@@ -0,0 +1,95 @@
+//! This module gathers display choices made once at initialization
+
+use crate::{Color, Image, ScanMode};
+
+/// Image shown by the panel before the first frame is received
+#[derive(Clone, Copy, Debug)]
+pub enum StartupImage {
+    Black,
+    Gradient(Color),
+    Solid(Color),
+    /// Image built by a function, such as [splash]
+    Custom(fn() -> Image),
+}
+
+/// Custom startup images are equal when their functions build the same image, since
+/// function addresses are not guaranteed to be unique
+impl PartialEq for StartupImage {
+    fn eq(&self, other: &Self) -> bool {
+        match (self, other) {
+            (StartupImage::Black, StartupImage::Black) => true,
+            (StartupImage::Gradient(a), StartupImage::Gradient(b))
+            | (StartupImage::Solid(a), StartupImage::Solid(b)) => a == b,
+            (StartupImage::Custom(a), StartupImage::Custom(b)) => a() == b(),
+            _ => false,
+        }
+    }
+}
+
+/// Display configuration consumed by `init`
+#[derive(Clone, Copy)]
+pub struct DisplayConfig {
+    pub startup: StartupImage,
+}
+
+/// Implements functions for display configuration structure
+impl DisplayConfig {
+    /// Builds the image to display until the first frame is received
+    pub fn startup_image(&self) -> Image {
+        match self.startup {
+            StartupImage::Black => Image::default(),
+            StartupImage::Gradient(color) => Image::gradient(color),
+            StartupImage::Solid(color) => Image::new_solid(color),
+            StartupImage::Custom(build) => build(),
+        }
+    }
+}
+
+/// Default configuration keeps the panel black until a frame arrives
+impl Default for DisplayConfig {
+    fn default() -> Self {
+        DisplayConfig {
+            startup: StartupImage::Black,
+        }
+    }
+}
+
+/// Wiring of the panel, consumed by the matrix driver
+#[derive(Clone, Copy)]
+pub struct MatrixConfig {
+    /// Image dimension driven by the row pins
+    pub scan: ScanMode,
+    /// Minimum time between switching the previous row off and switching the next
+    /// one on, so the column drivers discharge and bright pixels do not ghost into
+    /// the next row. 0 keeps no dead time.
+    pub interrow_dead_ns: u32,
+}
+
+/// Default configuration matches the panel of the course, multiplexed by rows
+impl Default for MatrixConfig {
+    fn default() -> Self {
+        MatrixConfig {
+            scan: ScanMode::Rows,
+            interrow_dead_ns: 0,
+        }
+    }
+}
+
+/// Converts a duration in nanoseconds to a number of cycles of a `clock_hz` clock,
+/// rounded up so that the duration is never shortened
+pub fn ns_to_cycles(ns: u32, clock_hz: u32) -> u32 {
+    (ns as u64 * clock_hz as u64).div_ceil(1_000_000_000) as u32
+}
+
+/// Builds the splash image: rows lit red, green and blue in turn so that
+/// the row order of the wiring can be checked at a glance
+pub fn splash() -> Image {
+    let colors = [Color::RED, Color::GREEN, Color::BLUE];
+    let mut image = Image::default();
+    for row in 1..=8 {
+        for col in 1..=8 {
+            image[(row, col)] = colors[(row - 1) % 3];
+        }
+    }
+    image
+}
@@ -0,0 +1,156 @@
+//! This module implements XON/XOFF software flow control on the serial link
+//!
+//! The firmware sends XOFF when its backlog of received bytes reaches a high
+//! watermark and XON once it has drained down to a low watermark. The control
+//! byte is only recorded by the receive interrupt and written later by a low
+//! priority task, so the interrupt never waits for the transmitter.
+//!
+//! The board also sends captured frames on the same line and XON or XOFF may be
+//! written in the middle of one. Captured frames escape these two values (see
+//! [CaptureSender](crate::capture::CaptureSender)), so the host drops them from
+//! the stream wherever they appear. Control bytes only travel from the board
+//! to the host, so nothing has to be escaped in the frames the host sends.
+
+/// Byte asking the host to resume sending
+pub const XON: u8 = 0x11;
+/// Byte asking the host to stop sending
+pub const XOFF: u8 = 0x13;
+
+/// Watermark logic deciding when to send XON and XOFF
+pub struct FlowControl {
+    low: usize,
+    high: usize,
+    enabled: bool,
+    paused: bool,
+    /// Control byte waiting for the transmitter
+    pending: Option<u8>,
+}
+
+/// Implements functions for flow control structure
+impl FlowControl {
+    /// Creates an enabled flow control sending XOFF when the backlog reaches
+    /// `high` and XON when it gets down to `low`
+    ///
+    /// # Panics
+    /// This function panics if `low` is not smaller than `high`.
+    pub const fn new(low: usize, high: usize) -> Self {
+        assert!(low < high);
+        FlowControl {
+            low,
+            high,
+            enabled: true,
+            paused: false,
+            pending: None,
+        }
+    }
+
+    /// Creates a flow control which never sends anything
+    pub const fn disabled() -> Self {
+        FlowControl {
+            low: 0,
+            high: usize::MAX,
+            enabled: false,
+            paused: false,
+            pending: None,
+        }
+    }
+
+    /// Returns true if XOFF was sent and not yet followed by XON
+    pub fn is_paused(&self) -> bool {
+        self.paused
+    }
+
+    /// Updates the state with the current backlog, returns true if a control byte
+    /// is now waiting to be sent. A byte not sent yet is replaced by the new one.
+    pub fn update(&mut self, backlog: usize) -> bool {
+        let control = if !self.enabled {
+            None
+        } else if !self.paused && backlog >= self.high {
+            self.paused = true;
+            Some(XOFF)
+        } else if self.paused && backlog <= self.low {
+            self.paused = false;
+            Some(XON)
+        } else {
+            None
+        };
+        if control.is_some() {
+            self.pending = control;
+        }
+        control.is_some()
+    }
+
+    /// Returns the control byte waiting to be sent, if any
+    pub fn pending(&self) -> Option<u8> {
+        self.pending
+    }
+
+    /// Hands the waiting control byte to `write`, returns true if it is still
+    /// waiting because `write` refused it
+    pub fn send_with<E>(&mut self, write: impl FnOnce(u8) -> Result<(), E>) -> bool {
+        if let Some(control) = self.pending {
+            if write(control).is_ok() {
+                self.pending = None;
+            }
+        }
+        self.pending.is_some()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn xoff_at_high_and_xon_at_low() {
+        let mut flow = FlowControl::new(4, 12);
+        assert!(!flow.update(11));
+        assert_eq!(flow.pending(), None);
+        assert!(flow.update(12));
+        assert_eq!(flow.pending(), Some(XOFF));
+        assert!(flow.is_paused());
+        assert!(!flow.update(5));
+        assert!(flow.update(4));
+        assert_eq!(flow.pending(), Some(XON));
+        assert!(!flow.is_paused());
+    }
+
+    #[test]
+    fn repeated_updates_send_once() {
+        let mut flow = FlowControl::new(4, 12);
+        assert!(flow.update(12));
+        assert!(!flow.send_with(|_| Ok::<(), ()>(())));
+        for backlog in [13, 20, 12, 8] {
+            assert!(!flow.update(backlog));
+        }
+        assert_eq!(flow.pending(), None);
+        assert!(flow.update(0));
+        assert!(!flow.update(0));
+        assert_eq!(flow.pending(), Some(XON));
+    }
+
+    #[test]
+    fn refused_byte_stays_pending() {
+        let mut flow = FlowControl::new(4, 12);
+        flow.update(12);
+        assert!(flow.send_with(|_| Err(())));
+        assert_eq!(flow.pending(), Some(XOFF));
+        // The host drained the line before the byte went out: XON replaces XOFF
+        flow.update(2);
+        let mut sent = None;
+        assert!(!flow.send_with(|byte| {
+            sent = Some(byte);
+            Ok::<(), ()>(())
+        }));
+        assert_eq!(sent, Some(XON));
+        assert_eq!(flow.pending(), None);
+    }
+
+    #[test]
+    fn disabled_never_sends() {
+        let mut flow = FlowControl::disabled();
+        assert!(!flow.update(usize::MAX - 1));
+        assert!(!flow.is_paused());
+        assert!(!flow.send_with(|_| Err(())));
+    }
+}
@@ -1,6 +1,14 @@
-//! This module builds gamma correction table and implements associated gamma_correct() function
+//! This module builds gamma correction tables and implements associated gamma_correct()
+//! function, its 12 bit variant gamma_correct_u12() and its approximate inverse
+//! gamma_uncorrect(), with GammaTable and ChannelGamma for other exponents and white points.
+//! make_gamma_table() builds tables for other exponents at compile time.
 
-const GAMMA_TAB: [u8; 256] = [
+#[cfg(not(any(feature = "std", test)))] // f32 methods come from std when it is linked
+use micromath::F32Ext;
+
+/// Gamma correction table of the panel, see [GAMMA] for its exponent. Inputs other
+/// than 0 are corrected to at least 1.
+pub const GAMMA_TABLE: [u8; 256] = [
     0x00, 0x01, 0x01, 0x01, 0x01, 0x01, 0x01, 0x01, 0x01, 0x01, 0x02, 0x02, 0x02, 0x02, 0x02, 0x03,
     0x03, 0x03, 0x03, 0x04, 0x04, 0x04, 0x04, 0x05, 0x05, 0x05, 0x06, 0x06, 0x06, 0x07, 0x07, 0x08,
     0x08, 0x08, 0x09, 0x09, 0x0a, 0x0a, 0x0b, 0x0b, 0x0b, 0x0c, 0x0c, 0x0d, 0x0d, 0x0e, 0x0e, 0x0f,
@@ -19,7 +27,355 @@ const GAMMA_TAB: [u8; 256] = [
     0xe7, 0xe9, 0xea, 0xec, 0xee, 0xef, 0xf1, 0xf3, 0xf4, 0xf6, 0xf8, 0xf9, 0xfb, 0xfd, 0xfe, 0xff,
 ];
 
-/// Returns coefficient from gamma_tab at x position
+/// Same curve as GAMMA_TABLE (exponent 1.7) with 12 bit outputs from 0 to 4095.
+/// Values are rounded up so that every input but 0 stays lit and the dark end is
+/// not banded.
+const GAMMA_TAB_12: [u16; 256] = [
+    0x000, 0x001, 0x002, 0x003, 0x004, 0x006, 0x007, 0x00a, 0x00c, 0x00e, 0x011, 0x014, 0x017,
+    0x01a, 0x01e, 0x022, 0x025, 0x02a, 0x02e, 0x032, 0x037, 0x03b, 0x040, 0x045, 0x04a, 0x050,
+    0x055, 0x05b, 0x060, 0x066, 0x06c, 0x072, 0x079, 0x07f, 0x086, 0x08c, 0x093, 0x09a, 0x0a1,
+    0x0a9, 0x0b0, 0x0b8, 0x0bf, 0x0c7, 0x0cf, 0x0d7, 0x0df, 0x0e8, 0x0f0, 0x0f9, 0x101, 0x10a,
+    0x113, 0x11c, 0x125, 0x12e, 0x138, 0x141, 0x14b, 0x155, 0x15e, 0x168, 0x172, 0x17d, 0x187,
+    0x191, 0x19c, 0x1a7, 0x1b1, 0x1bc, 0x1c7, 0x1d2, 0x1de, 0x1e9, 0x1f4, 0x200, 0x20c, 0x217,
+    0x223, 0x22f, 0x23b, 0x247, 0x254, 0x260, 0x26d, 0x279, 0x286, 0x293, 0x2a0, 0x2ad, 0x2ba,
+    0x2c7, 0x2d4, 0x2e2, 0x2ef, 0x2fd, 0x30b, 0x318, 0x326, 0x334, 0x342, 0x351, 0x35f, 0x36d,
+    0x37c, 0x38b, 0x399, 0x3a8, 0x3b7, 0x3c6, 0x3d5, 0x3e4, 0x3f4, 0x403, 0x413, 0x422, 0x432,
+    0x442, 0x451, 0x461, 0x471, 0x482, 0x492, 0x4a2, 0x4b3, 0x4c3, 0x4d4, 0x4e4, 0x4f5, 0x506,
+    0x517, 0x528, 0x539, 0x54b, 0x55c, 0x56e, 0x57f, 0x591, 0x5a2, 0x5b4, 0x5c6, 0x5d8, 0x5ea,
+    0x5fc, 0x60f, 0x621, 0x633, 0x646, 0x658, 0x66b, 0x67e, 0x691, 0x6a4, 0x6b7, 0x6ca, 0x6dd,
+    0x6f1, 0x704, 0x717, 0x72b, 0x73f, 0x752, 0x766, 0x77a, 0x78e, 0x7a2, 0x7b6, 0x7cb, 0x7df,
+    0x7f3, 0x808, 0x81d, 0x831, 0x846, 0x85b, 0x870, 0x885, 0x89a, 0x8af, 0x8c4, 0x8da, 0x8ef,
+    0x905, 0x91a, 0x930, 0x946, 0x95c, 0x971, 0x987, 0x99e, 0x9b4, 0x9ca, 0x9e0, 0x9f7, 0xa0d,
+    0xa24, 0xa3b, 0xa51, 0xa68, 0xa7f, 0xa96, 0xaad, 0xac4, 0xadb, 0xaf3, 0xb0a, 0xb22, 0xb39,
+    0xb51, 0xb69, 0xb80, 0xb98, 0xbb0, 0xbc8, 0xbe0, 0xbf8, 0xc11, 0xc29, 0xc41, 0xc5a, 0xc73,
+    0xc8b, 0xca4, 0xcbd, 0xcd6, 0xcef, 0xd08, 0xd21, 0xd3a, 0xd53, 0xd6d, 0xd86, 0xda0, 0xdb9,
+    0xdd3, 0xded, 0xe06, 0xe20, 0xe3a, 0xe54, 0xe6e, 0xe89, 0xea3, 0xebd, 0xed8, 0xef2, 0xf0d,
+    0xf28, 0xf42, 0xf5d, 0xf78, 0xf93, 0xfae, 0xfc9, 0xfe4, 0xfff,
+];
+
+/// Number of fractional bits of the fixed point numbers used by make_gamma_table()
+const FRAC_BITS: u32 = 32;
+/// 1.0 in fixed point
+const ONE: u128 = 1 << FRAC_BITS;
+
+/// Builds at compile time the table of `255 * (x / 255) ^ (gamma_num / gamma_den)`,
+/// rounded, with inputs other than 0 corrected to at least 1 like GAMMA_TABLE.
+/// Only integer fixed point operations are used, so it can initialize a const:
+/// `make_gamma_table(17, 10)` gives GAMMA_TABLE within one unit. Panics if
+/// `gamma_den` is 0.
+pub const fn make_gamma_table(gamma_num: u32, gamma_den: u32) -> [u8; 256] {
+    assert!(gamma_den != 0, "gamma denominator must not be 0");
+    let mut table = [0; 256];
+    let log_max = log2_fixed(255);
+    let mut x = 1;
+    while x < 256 {
+        // log2(x / 255) * gamma, which is not positive
+        let exponent = (log2_fixed(x) - log_max) * gamma_num as i128 / gamma_den as i128;
+        let value = (255 * exp2_fixed(exponent) + ONE / 2) >> FRAC_BITS;
+        table[x as usize] = if value == 0 { 1 } else { value as u8 };
+        x += 1;
+    }
+    table
+}
+
+/// Returns log2(x) in fixed point, x being at least 1
+const fn log2_fixed(x: u32) -> i128 {
+    let int_part = 31 - x.leading_zeros();
+    // Mantissa from 1 included to 2 excluded
+    let mut m = ((x as u128) << FRAC_BITS) >> int_part;
+    let mut result = (int_part as i128) << FRAC_BITS;
+    let mut bit = FRAC_BITS;
+    // Squaring the mantissa doubles its logarithm, whose next bit is 1 when the
+    // square reaches 2
+    while bit > 0 {
+        bit -= 1;
+        m = (m * m) >> FRAC_BITS;
+        if m >= 2 * ONE {
+            m >>= 1;
+            result |= 1 << bit;
+        }
+    }
+    result
+}
+
+/// Returns 2^y in fixed point, y being a fixed point number which is not positive
+const fn exp2_fixed(y: i128) -> u128 {
+    // y = int_part + frac with frac from 0 included to 1 excluded
+    let int_part = y >> FRAC_BITS;
+    let frac = (y - (int_part << FRAC_BITS)) as u128;
+    if -int_part >= 64 {
+        return 0;
+    }
+    let mut result = ONE;
+    // 2^(2^-1), then 2^(2^-2)... obtained by successive square roots
+    let mut root = isqrt((2 * ONE) << FRAC_BITS);
+    let mut bit = FRAC_BITS;
+    while bit > 0 {
+        bit -= 1;
+        if frac & (1 << bit) != 0 {
+            result = (result * root) >> FRAC_BITS;
+        }
+        root = isqrt(root << FRAC_BITS);
+    }
+    result >> -int_part
+}
+
+/// Integer square root, rounded down
+const fn isqrt(n: u128) -> u128 {
+    if n < 2 {
+        return n;
+    }
+    // Newton iterations from above converge down to the root
+    let mut x = n;
+    let mut next = x.div_ceil(2);
+    while next < x {
+        x = next;
+        next = (x + n / x) / 2;
+    }
+    x
+}
+
+/// Exponent of the curve encoded by GAMMA_TABLE, which matches `255 * (x / 255) ^ GAMMA`
+/// within one unit on every entry
+pub const GAMMA: f32 = 1.7;
+
+/// Returns coefficient from GAMMA_TABLE at x position
 pub fn gamma_correct(x: u8) -> u8 {
-    return GAMMA_TAB[x as usize];
+    GAMMA_TABLE[x as usize]
+}
+
+/// Returns the 12 bit gamma corrected value of `x`, for modulations deeper than 8 bits
+pub fn gamma_correct_u12(x: u8) -> u16 {
+    GAMMA_TAB_12[x as usize]
+}
+
+/// Applies the curve of gamma_correct() to a normalized value from 0.0 to 1.0
+/// (clamped), for host tools building reference images
+pub fn gamma_correct_f32(v: f32) -> f32 {
+    v.clamp(0.0, 1.0).powf(GAMMA)
+}
+
+/// Approximate inverse of GAMMA_TABLE: for every corrected value, the middle of the
+/// input values which give it, or the closest input if none does
+const INVERSE_GAMMA_TAB: [u8; 256] = [
+    0x00, 0x05, 0x0c, 0x11, 0x15, 0x18, 0x1b, 0x1e, 0x20, 0x23, 0x25, 0x27, 0x2a, 0x2c, 0x2e, 0x30,
+    0x31, 0x33, 0x35, 0x37, 0x38, 0x3a, 0x3c, 0x3d, 0x3f, 0x40, 0x42, 0x43, 0x45, 0x46, 0x48, 0x49,
+    0x4b, 0x4c, 0x4d, 0x4f, 0x50, 0x51, 0x53, 0x54, 0x55, 0x56, 0x58, 0x59, 0x5a, 0x5b, 0x5d, 0x5e,
+    0x5f, 0x60, 0x61, 0x62, 0x63, 0x65, 0x66, 0x67, 0x68, 0x69, 0x6a, 0x6b, 0x6c, 0x6d, 0x6e, 0x6f,
+    0x70, 0x71, 0x73, 0x74, 0x75, 0x76, 0x77, 0x78, 0x79, 0x7a, 0x7b, 0x7b, 0x7c, 0x7d, 0x7e, 0x7f,
+    0x80, 0x81, 0x82, 0x83, 0x84, 0x85, 0x86, 0x87, 0x88, 0x89, 0x8a, 0x8a, 0x8b, 0x8c, 0x8d, 0x8e,
+    0x8f, 0x90, 0x91, 0x91, 0x92, 0x93, 0x94, 0x95, 0x96, 0x97, 0x97, 0x98, 0x99, 0x9a, 0x9b, 0x9c,
+    0x9c, 0x9d, 0x9e, 0x9f, 0xa0, 0xa1, 0xa1, 0xa2, 0xa3, 0xa4, 0xa5, 0xa5, 0xa6, 0xa7, 0xa8, 0xa9,
+    0xa9, 0xaa, 0xab, 0xac, 0xac, 0xad, 0xae, 0xaf, 0xaf, 0xb0, 0xb1, 0xb2, 0xb3, 0xb3, 0xb4, 0xb5,
+    0xb5, 0xb6, 0xb7, 0xb8, 0xb8, 0xb9, 0xba, 0xbb, 0xbb, 0xbc, 0xbd, 0xbe, 0xbe, 0xbf, 0xc0, 0xc0,
+    0xc1, 0xc2, 0xc3, 0xc3, 0xc4, 0xc5, 0xc5, 0xc6, 0xc7, 0xc7, 0xc8, 0xc9, 0xca, 0xca, 0xcb, 0xcc,
+    0xcc, 0xcd, 0xce, 0xce, 0xcf, 0xd0, 0xd0, 0xd1, 0xd2, 0xd2, 0xd3, 0xd4, 0xd4, 0xd5, 0xd6, 0xd6,
+    0xd7, 0xd8, 0xd8, 0xd9, 0xda, 0xda, 0xdb, 0xdc, 0xdc, 0xdd, 0xde, 0xde, 0xdf, 0xe0, 0xe0, 0xe1,
+    0xe1, 0xe2, 0xe3, 0xe3, 0xe4, 0xe5, 0xe5, 0xe6, 0xe7, 0xe7, 0xe8, 0xe8, 0xe9, 0xea, 0xea, 0xeb,
+    0xec, 0xec, 0xed, 0xed, 0xee, 0xef, 0xef, 0xf0, 0xf0, 0xf1, 0xf2, 0xf2, 0xf3, 0xf3, 0xf4, 0xf5,
+    0xf5, 0xf6, 0xf6, 0xf7, 0xf8, 0xf8, 0xf9, 0xf9, 0xfa, 0xfb, 0xfb, 0xfc, 0xfc, 0xfd, 0xfe, 0xff,
+];
+
+/// Returns the linear value which gamma_correct() turns into `v`. Several inputs
+/// share a corrected value in the dark end of the table, so
+/// `gamma_uncorrect(gamma_correct(x))` may differ from `x` by a few units.
+pub fn gamma_uncorrect(v: u8) -> u8 {
+    INVERSE_GAMMA_TAB[v as usize]
+}
+
+/// Gamma correction table computed at runtime for a given exponent
+#[derive(Clone)]
+pub struct GammaTable(pub [u8; 256]);
+
+/// Implements functions for gamma table structure
+impl GammaTable {
+    /// Computes the table of `255 * (x / 255) ^ gamma`, rounded. 1.0 gives the
+    /// identity and larger exponents darken midtones. This calls powf 256 times, so
+    /// build the table once, in init for example.
+    pub fn new(gamma: f32) -> Self {
+        GammaTable::with_max(gamma, 255)
+    }
+
+    /// Computes the table of `max * (x / 255) ^ gamma`, rounded, so that 255 is
+    /// corrected to `max`
+    pub fn with_max(gamma: f32, max: u8) -> Self {
+        let mut table = [0; 256];
+        for (x, out) in table.iter_mut().enumerate() {
+            *out = (max as f32 * (x as f32 / 255.0).powf(gamma))
+                .round()
+                .clamp(0.0, 255.0) as u8;
+        }
+        GammaTable(table)
+    }
+
+    /// Returns the corrected value of `v`
+    pub fn apply(&self, v: u8) -> u8 {
+        self.0[v as usize]
+    }
+}
+
+/// The default table is the one used by gamma_correct()
+impl Default for GammaTable {
+    fn default() -> Self {
+        GammaTable(GAMMA_TABLE)
+    }
+}
+
+/// Folds a brightness scale (out of 255, like `Color * u8`) and a gamma table into
+/// one table, so that both cost a single lookup per channel. It only needs to be
+/// rebuilt when the brightness changes.
+pub fn build_dimming_table(brightness: u8, gamma: &GammaTable) -> [u8; 256] {
+    let mut table = [0; 256];
+    for (x, out) in table.iter_mut().enumerate() {
+        *out = gamma.apply(((x as u16 * brightness as u16 + 127) / 255) as u8);
+    }
+    table
+}
+
+/// Independent gamma tables for the red, green and blue channels, to balance LEDs
+/// of unequal strength
+#[derive(Clone, Default)]
+pub struct ChannelGamma {
+    /// Table of the red channel
+    pub r: GammaTable,
+    /// Table of the green channel
+    pub g: GammaTable,
+    /// Table of the blue channel
+    pub b: GammaTable,
+}
+
+/// Implements functions for channel gamma structure
+impl ChannelGamma {
+    /// Builds tables sharing the same exponent which correct white (255, 255, 255)
+    /// to (r_max, g_max, b_max)
+    pub fn from_white_point(r_max: u8, g_max: u8, b_max: u8, gamma: f32) -> Self {
+        ChannelGamma {
+            r: GammaTable::with_max(gamma, r_max),
+            g: GammaTable::with_max(gamma, g_max),
+            b: GammaTable::with_max(gamma, b_max),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Color;
+
+    #[test]
+    fn uncorrect_inverts_correct_within_tolerance() {
+        // Dark inputs share corrected values, which costs a few units
+        for x in 0..=255u8 {
+            let back = gamma_uncorrect(gamma_correct(x));
+            assert!(back.abs_diff(x) <= 4, "{} came back as {}", x, back);
+        }
+        for v in 0..=255u8 {
+            assert!(gamma_correct(gamma_uncorrect(v)).abs_diff(v) <= 1);
+        }
+        assert_eq!(gamma_uncorrect(0), 0);
+        assert_eq!(gamma_uncorrect(255), 255);
+    }
+
+    #[test]
+    fn gamma_one_is_the_identity() {
+        let table = GammaTable::new(1.0);
+        for x in 0..=255u8 {
+            assert_eq!(table.apply(x), x);
+        }
+    }
+
+    #[test]
+    fn larger_gamma_darkens_midtones() {
+        let (soft, steep) = (GammaTable::new(1.8), GammaTable::new(2.5));
+        for x in 1..255u8 {
+            assert!(soft.apply(x) < x);
+            assert!(steep.apply(x) <= soft.apply(x));
+        }
+        assert!(steep.apply(128) < soft.apply(128));
+        assert_eq!((steep.apply(0), steep.apply(255)), (0, 255));
+        assert_eq!(GammaTable::default().0, GAMMA_TABLE);
+    }
+
+    #[test]
+    fn white_point_maps_white_to_the_maxima() {
+        let tables = ChannelGamma::from_white_point(255, 200, 160, 2.2);
+        assert_eq!(
+            Color::WHITE.corrected(&tables),
+            Color {
+                r: 255,
+                g: 200,
+                b: 160
+            }
+        );
+        assert_eq!(Color::BLACK.corrected(&tables), Color::BLACK);
+        // Every channel keeps the curve of the shared exponent
+        let shared = GammaTable::new(2.2);
+        for x in 0..=255u8 {
+            assert_eq!(tables.r.apply(x), shared.apply(x));
+            assert!(tables.b.apply(x) <= tables.g.apply(x));
+        }
+    }
+
+    #[test]
+    fn twelve_bit_table_is_strictly_increasing() {
+        assert_eq!(gamma_correct_u12(0), 0);
+        assert_eq!(gamma_correct_u12(255), 4095);
+        // 12 bits leave room for a distinct output per input, even in the dark end
+        for x in 1..=255u8 {
+            assert!(gamma_correct_u12(x) > gamma_correct_u12(x - 1), "{}", x);
+        }
+        for x in 0..=255u8 {
+            let exact = 4095.0 * gamma_correct_f32(x as f32 / 255.0);
+            assert!((gamma_correct_u12(x) as f32 - exact).abs() <= 1.0, "{}", x);
+        }
+    }
+
+    #[test]
+    fn dimming_table_folds_brightness_into_gamma() {
+        let gamma = GammaTable::default();
+        assert_eq!(build_dimming_table(255, &gamma), GAMMA_TABLE);
+        assert_eq!(build_dimming_table(0, &gamma), [0; 256]);
+
+        let half = build_dimming_table(128, &gamma);
+        let color = Color {
+            r: 10,
+            g: 128,
+            b: 255,
+        };
+        assert_eq!(color.mapped(&half), (color * 128).gamma_correct());
+    }
+
+    #[test]
+    fn table_matches_the_f32_curve() {
+        for x in 0..=255u8 {
+            let quantized = (255.0 * gamma_correct_f32(x as f32 / 255.0)).round() as u8;
+            assert!(gamma_correct(x).abs_diff(quantized) <= 1, "{}", x);
+        }
+        assert_eq!(gamma_correct_f32(-0.5), 0.0);
+        assert_eq!(gamma_correct_f32(2.0), 1.0);
+    }
+
+    #[test]
+    fn const_tables() {
+        const SHIPPED: [u8; 256] = make_gamma_table(17, 10);
+        const STEEP: [u8; 256] = make_gamma_table(22, 10);
+        for (x, (&built, &shipped)) in SHIPPED.iter().zip(GAMMA_TABLE.iter()).enumerate() {
+            assert!(built.abs_diff(shipped) <= 1, "{}", x);
+        }
+        for (x, pair) in STEEP.windows(2).enumerate() {
+            assert!(pair[1] >= pair[0], "{}", x + 1);
+        }
+        assert!(STEEP
+            .iter()
+            .zip(SHIPPED.iter())
+            .all(|(steep, shipped)| steep <= shipped));
+        assert_eq!((STEEP[0], STEEP[1], STEEP[255]), (0, 1, 255));
+        // Same curve as the runtime tables within one unit, inputs but 0 staying lit
+        let runtime = GammaTable::new(2.2);
+        for (x, (&built, &computed)) in STEEP.iter().zip(runtime.0.iter()).enumerate().skip(1) {
+            assert!(built.abs_diff(computed.max(1)) <= 1, "{}", x);
+        }
+        let identity = make_gamma_table(1, 1);
+        assert!((0..=255).eq(identity));
+    }
 }
@@ -1,15 +1,30 @@
 //! Module builds image and color structures with associated functions
 
-use core::ops::{Div, IndexMut};
-use core::{
-    ops::{Index, Mul},
-    panic,
-};
-
-use crate::gamma;
+use crate::animation;
+use crate::rng::XorShift32;
+use crate::{font, gamma, protocol};
+#[cfg(not(any(feature = "std", test)))] // f32 methods come from std when it is linked
 use micromath::F32Ext;
 
-#[derive(Clone, Copy, Default)]
+/// Errors returned when parsing a color
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ColorParseError {
+    /// The text is not 6 hexadecimal digits, with or without a leading `#`
+    InvalidLength,
+    /// The text contains a character which is not a hexadecimal digit
+    InvalidDigit,
+}
+
+/// Errors returned when building an image from bytes
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum ImageBytesError {
+    /// The buffer does not hold exactly 3 bytes per pixel, the given length
+    InvalidLength(usize),
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
 #[repr(C)]
 pub struct Color {
     pub r: u8,
@@ -22,6 +37,76 @@ impl Color {
     pub const RED: Color = Color { r: 255, g: 0, b: 0 };
     pub const BLUE: Color = Color { r: 0, g: 0, b: 255 };
     pub const GREEN: Color = Color { r: 0, g: 255, b: 0 };
+    pub const WHITE: Color = Color {
+        r: 255,
+        g: 255,
+        b: 255,
+    };
+    pub const BLACK: Color = Color { r: 0, g: 0, b: 0 };
+    pub const YELLOW: Color = Color {
+        r: 255,
+        g: 255,
+        b: 0,
+    };
+    pub const CYAN: Color = Color {
+        r: 0,
+        g: 255,
+        b: 255,
+    };
+    pub const MAGENTA: Color = Color {
+        r: 255,
+        g: 0,
+        b: 255,
+    };
+    pub const ORANGE: Color = Color {
+        r: 255,
+        g: 128,
+        b: 0,
+    };
+
+    /// Named colors, for animations cycling through them by index
+    pub const PALETTE: [Color; 9] = [
+        Color::BLACK,
+        Color::WHITE,
+        Color::RED,
+        Color::GREEN,
+        Color::BLUE,
+        Color::YELLOW,
+        Color::CYAN,
+        Color::MAGENTA,
+        Color::ORANGE,
+    ];
+
+    /// Creates a color from a `0xRRGGBB` value, the upper byte is ignored
+    pub const fn from_hex(rgb: u32) -> Color {
+        Color {
+            r: (rgb >> 16) as u8,
+            g: (rgb >> 8) as u8,
+            b: rgb as u8,
+        }
+    }
+
+    /// Parses a color written `#rrggbb` or `rrggbb`, in lower or upper case
+    pub fn try_from_str(text: &str) -> Result<Color, ColorParseError> {
+        let digits = text.strip_prefix('#').unwrap_or(text);
+        if digits.len() != 6 {
+            return Err(ColorParseError::InvalidLength);
+        }
+        let mut rgb = 0;
+        for c in digits.chars() {
+            rgb = rgb << 4 | c.to_digit(16).ok_or(ColorParseError::InvalidDigit)?;
+        }
+        Ok(Color::from_hex(rgb))
+    }
+
+    /// Subtracts another color channel by channel, stopping at 0
+    pub fn saturating_sub(self, other: Color) -> Color {
+        Color {
+            r: self.r.saturating_sub(other.r),
+            g: self.g.saturating_sub(other.g),
+            b: self.b.saturating_sub(other.b),
+        }
+    }
 
     /// Applies gamma correction to each r g b bytes
     pub fn gamma_correct(&self) -> Self {
@@ -31,6 +116,201 @@ impl Color {
             b: gamma::gamma_correct(self.b),
         }
     }
+
+    /// Applies gamma correction to each r g b bytes with 12 bit outputs
+    pub fn gamma_correct_12(&self) -> Color12 {
+        Color12 {
+            r: gamma::gamma_correct_u12(self.r),
+            g: gamma::gamma_correct_u12(self.g),
+            b: gamma::gamma_correct_u12(self.b),
+        }
+    }
+
+    /// Applies gamma correction to each r g b bytes with a custom table
+    pub fn gamma_correct_with(&self, table: &gamma::GammaTable) -> Self {
+        Color {
+            r: table.apply(self.r),
+            g: table.apply(self.g),
+            b: table.apply(self.b),
+        }
+    }
+
+    /// Replaces each r g b byte by its entry in `lut`, for example a table from
+    /// [gamma::build_dimming_table]
+    pub fn mapped(&self, lut: &[u8; 256]) -> Self {
+        Color {
+            r: lut[self.r as usize],
+            g: lut[self.g as usize],
+            b: lut[self.b as usize],
+        }
+    }
+
+    /// Applies the gamma table of each channel to its r g b byte
+    pub fn corrected(&self, tables: &gamma::ChannelGamma) -> Self {
+        Color {
+            r: tables.r.apply(self.r),
+            g: tables.g.apply(self.g),
+            b: tables.b.apply(self.b),
+        }
+    }
+
+    /// Reverts gamma correction on each r g b bytes, see [gamma::gamma_uncorrect]
+    pub fn gamma_uncorrect(&self) -> Self {
+        Color {
+            r: gamma::gamma_uncorrect(self.r),
+            g: gamma::gamma_uncorrect(self.g),
+            b: gamma::gamma_uncorrect(self.b),
+        }
+    }
+
+    /// Perceived brightness of the color from 0 to 255, weighting the channels
+    /// like ITU-R BT.601 (0.299 R + 0.587 G + 0.114 B)
+    pub fn luminance(&self) -> u8 {
+        ((299 * self.r as u32 + 587 * self.g as u32 + 114 * self.b as u32 + 500) / 1000) as u8
+    }
+
+    /// Returns the gray of the same luminance
+    pub fn to_grayscale(&self) -> Color {
+        let l = self.luminance();
+        Color { r: l, g: l, b: l }
+    }
+
+    /// Scales every channel by `num / den` with rounding, saturating at 255.
+    /// Panics if `den` is 0.
+    pub fn scaled(self, num: u8, den: u8) -> Color {
+        let scale = |c: u8| ((c as u32 * num as u32 + den as u32 / 2) / den as u32).min(255) as u8;
+        Color {
+            r: scale(self.r),
+            g: scale(self.g),
+            b: scale(self.b),
+        }
+    }
+
+    /// Returns the negative of the color
+    pub fn invert(&self) -> Color {
+        Color {
+            r: 255 - self.r,
+            g: 255 - self.g,
+            b: 255 - self.b,
+        }
+    }
+
+    /// Returns true if the luminance of the color is below `threshold`, for
+    /// instance to draw white over dark pixels and black over light ones
+    pub fn is_dark(&self, threshold: u8) -> bool {
+        self.luminance() < threshold
+    }
+
+    /// Interpolates linearly between two colors, `t` going from 0 (self) to 1 (other)
+    /// and being clamped to this range
+    pub fn lerp(self, other: Color, t: f32) -> Color {
+        let t = t.clamp(0.0, 1.0);
+        let lerp = |a: u8, b: u8| (a as f32 + (b as f32 - a as f32) * t).round() as u8;
+        Color {
+            r: lerp(self.r, other.r),
+            g: lerp(self.g, other.g),
+            b: lerp(self.b, other.b),
+        }
+    }
+
+    /// Same as [lerp](Color::lerp) with integer math only, `t` going from 0 (self)
+    /// to 255 (other)
+    pub fn lerp_u8(self, other: Color, t: u8) -> Color {
+        let lerp =
+            |a: u8, b: u8| ((a as u16 * (255 - t as u16) + b as u16 * t as u16 + 127) / 255) as u8;
+        Color {
+            r: lerp(self.r, other.r),
+            g: lerp(self.g, other.g),
+            b: lerp(self.b, other.b),
+        }
+    }
+
+    /// Draws `over` on top of the color with an opacity of `alpha` out of 255
+    pub fn blend(self, over: Color, alpha: u8) -> Color {
+        self.lerp_u8(over, alpha)
+    }
+
+    /// Interpolates between two colors in HSV space, `t` going from 0 (self) to 1
+    /// (other). The hue turns along the shorter arc, so red to blue goes through
+    /// magenta instead of gray. If one color is a gray, whose hue is undefined,
+    /// the interpolation is done on R, G and B instead.
+    pub fn lerp_hsv(self, other: Color, t: f32) -> Color {
+        if t <= 0.0 {
+            return self;
+        }
+        if t >= 1.0 {
+            return other;
+        }
+        let (h1, s1, v1) = self.to_hsv();
+        let (h2, s2, v2) = other.to_hsv();
+        if s1 == 0.0 || s2 == 0.0 {
+            return self.lerp(other, t);
+        }
+        let mut dh = h2 - h1;
+        if dh > 180.0 {
+            dh -= 360.0;
+        } else if dh < -180.0 {
+            dh += 360.0;
+        }
+        // from_hsv wraps the hue back into 0 to 360
+        Color::from_hsv(h1 + dh * t, s1 + (s2 - s1) * t, v1 + (v2 - v1) * t)
+    }
+
+    /// Converts the color to hue (degrees from 0 to 360), saturation and value
+    /// (0 to 1). The hue of a gray is 0.
+    pub fn to_hsv(&self) -> (f32, f32, f32) {
+        let (r, g, b) = (
+            self.r as f32 / 255.0,
+            self.g as f32 / 255.0,
+            self.b as f32 / 255.0,
+        );
+        let max = r.max(g).max(b);
+        let delta = max - r.min(g).min(b);
+        if delta == 0.0 {
+            return (0.0, 0.0, max);
+        }
+        let h = if max == r {
+            60.0 * ((g - b) / delta)
+        } else if max == g {
+            60.0 * ((b - r) / delta + 2.0)
+        } else {
+            60.0 * ((r - g) / delta + 4.0)
+        };
+        (if h < 0.0 { h + 360.0 } else { h }, delta / max, max)
+    }
+
+    /// Creates a color from hue (degrees, wrapped into 0 to 360), saturation and
+    /// value (0 to 1, clamped)
+    pub fn from_hsv(h: f32, s: f32, v: f32) -> Color {
+        let h = h - 360.0 * (h / 360.0).floor();
+        let (s, v) = (s.clamp(0.0, 1.0), v.clamp(0.0, 1.0));
+        let c = v * s;
+        let sector = h / 60.0;
+        let x = c * (1.0 - ((sector - 2.0 * (sector / 2.0).floor()) - 1.0).abs());
+        let (r, g, b) = match sector as u8 {
+            0 => (c, x, 0.0),
+            1 => (x, c, 0.0),
+            2 => (0.0, c, x),
+            3 => (0.0, x, c),
+            4 => (x, 0.0, c),
+            _ => (c, 0.0, x),
+        };
+        let channel = |value: f32| ((value + v - c) * 255.0).clamp(0.0, 255.0).round() as u8;
+        Color {
+            r: channel(r),
+            g: channel(g),
+            b: channel(b),
+        }
+    }
+}
+
+/// Color with 12 bit channels, from 0 to 4095
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct Color12 {
+    pub r: u16,
+    pub g: u16,
+    pub b: u16,
 }
 
 /// Implements multiplication for color type objects
@@ -38,7 +318,7 @@ impl core::ops::Mul<f32> for Color {
     type Output = Self;
 
     fn mul(self, rhs: f32) -> Self::Output {
-        let check_overflow = |pixel: u8| (pixel as f32 * rhs).max(0.0).min(255.0).round() as u8;
+        let check_overflow = |pixel: u8| (pixel as f32 * rhs).clamp(0.0, 255.0).round() as u8;
         Color {
             r: check_overflow(self.r),
             g: check_overflow(self.g),
@@ -47,80 +327,2090 @@ impl core::ops::Mul<f32> for Color {
     }
 }
 
+/// Implements integer multiplication for color type objects, the factor being
+/// a fraction of 255 so that 255 keeps the color and 0 turns it black
+impl core::ops::Mul<u8> for Color {
+    type Output = Self;
+
+    fn mul(self, rhs: u8) -> Self::Output {
+        let scale = |c: u8| ((c as u16 * rhs as u16 + 127) / 255) as u8;
+        Color {
+            r: scale(self.r),
+            g: scale(self.g),
+            b: scale(self.b),
+        }
+    }
+}
+
 /// Implements division for color type objects using mul implementation
 impl core::ops::Div<f32> for Color {
     type Output = Self;
 
     fn div(self, rhs: f32) -> Self::Output {
-        self * 1.0 / rhs
+        self * (1.0 / rhs)
+    }
+}
+
+/// Implements channel-wise saturating addition for color type objects
+impl core::ops::Add for Color {
+    type Output = Self;
+
+    fn add(self, rhs: Color) -> Self::Output {
+        Color {
+            r: self.r.saturating_add(rhs.r),
+            g: self.g.saturating_add(rhs.g),
+            b: self.b.saturating_add(rhs.b),
+        }
+    }
+}
+
+/// Implements in place saturating addition for color type objects using add implementation
+impl core::ops::AddAssign for Color {
+    fn add_assign(&mut self, rhs: Color) {
+        *self = *self + rhs;
+    }
+}
+
+/// Order in which the channels of a pixel are exported
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum ChannelOrder {
+    Rgb,
+    Bgr,
+    Grb,
+}
+
+/// Implements functions for channel order enumeration
+impl ChannelOrder {
+    /// Returns the channels of a color in this order
+    pub fn channels(self, color: Color) -> [u8; 3] {
+        match self {
+            ChannelOrder::Rgb => [color.r, color.g, color.b],
+            ChannelOrder::Bgr => [color.b, color.g, color.r],
+            ChannelOrder::Grb => [color.g, color.r, color.b],
+        }
+    }
+}
+
+/// Dimension of the panel which is multiplexed: the lines driven one at a time
+/// by the row pins are either the rows or the columns of the image
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum ScanMode {
+    Rows,
+    Columns,
+}
+
+/// Direction along which [Image::gradient_dir] fades, from the brightest pixels to
+/// the darkest ones
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum GradientDirection {
+    /// From column 1 to column 8
+    Horizontal,
+    /// From row 1 to row 8
+    Vertical,
+    /// From the top left corner to the bottom right one
+    DiagonalDown,
+    /// From the bottom left corner to the top right one
+    DiagonalUp,
+    /// Away from the top left corner, faster along the rows than along the columns
+    Radial,
+}
+
+/// One of the 8 ways to lay an image on the panel, combining quarter turns and
+/// mirroring
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Orientation {
+    /// Image unchanged
+    Identity,
+    /// Turned a quarter clockwise
+    Rotate90,
+    /// Turned upside down
+    Rotate180,
+    /// Turned a quarter counterclockwise
+    Rotate270,
+    /// Mirrored left to right
+    FlipHorizontal,
+    /// Mirrored top to bottom
+    FlipVertical,
+    /// Mirrored along the diagonal from (1, 1) to (8, 8)
+    Transpose,
+    /// Mirrored along the diagonal from (1, 8) to (8, 1)
+    AntiTranspose,
+}
+
+/// Implements functions for orientation enumeration
+impl Orientation {
+    /// Returns the pixel of an 8x8 image which is displayed at (row, col) with this
+    /// orientation, so that `image.orient(o)[(row, col)] == image[o.source(row, col)]`
+    pub fn source(self, row: usize, col: usize) -> (usize, usize) {
+        match self {
+            Orientation::Identity => (row, col),
+            Orientation::Rotate90 => (9 - col, row),
+            Orientation::Rotate180 => (9 - row, 9 - col),
+            Orientation::Rotate270 => (col, 9 - row),
+            Orientation::FlipHorizontal => (row, 9 - col),
+            Orientation::FlipVertical => (9 - row, col),
+            Orientation::Transpose => (col, row),
+            Orientation::AntiTranspose => (9 - col, 9 - row),
+        }
+    }
+}
+
+/// Returns the position, from 0 to 63, of pixel (row, col) in the output stream of
+/// the given scan mode. Row and column start at 1 like [Image] indexing.
+pub fn scan_position(mode: ScanMode, row: usize, col: usize) -> usize {
+    match mode {
+        ScanMode::Rows => (row - 1) * 8 + col - 1,
+        ScanMode::Columns => (col - 1) * 8 + row - 1,
     }
 }
 
+/// Image of `W` columns by `H` rows, rows and columns starting at 1. The firmware
+/// uses the 8x8 [Image], larger sizes are for panels made of several chained
+/// drivers.
+#[derive(Clone, PartialEq, Eq)]
 #[repr(transparent)]
-pub struct Image([Color; 64]);
+pub struct GenericImage<const W: usize, const H: usize>([[Color; W]; H]);
 
-/// Implements functions for Image structure
-impl Image {
+/// Image of the 8x8 panel
+pub type Image = GenericImage<8, 8>;
+
+// Color is repr(C) with three u8 fields, so it has no padding, and GenericImage is
+// repr(transparent) over nested arrays of them: the pixels are W * H * 3 contiguous
+// bytes, R, G and B row after row. The byte views of images rely on it.
+const _: () = assert!(core::mem::size_of::<Color>() == 3);
+const _: () = assert!(core::mem::align_of::<Color>() == 1);
+const _: () = assert!(core::mem::size_of::<Image>() == 192);
+
+/// Luminance above which a pixel is a living cell for [Image::life_step]
+pub const LIFE_THRESHOLD: u8 = 127;
+
+/// Luminance below which [Image::draw_text_auto] draws white rather than black
+pub const AUTO_CONTRAST_THRESHOLD: u8 = 128;
+
+/// One period of a sine wave in 64 steps, scaled from 0 to 255, for effects which
+/// cannot afford calling sin for every pixel
+const SINE: [u8; 64] = [
+    128, 140, 152, 165, 176, 188, 198, 208, 218, 226, 234, 240, 245, 250, 253, 254, 255, 254, 253,
+    250, 245, 240, 234, 226, 218, 208, 198, 188, 176, 165, 152, 140, 128, 115, 103, 90, 79, 67, 57,
+    47, 37, 29, 21, 15, 10, 5, 2, 1, 0, 1, 2, 5, 10, 15, 21, 29, 37, 47, 57, 67, 79, 90, 103, 115,
+];
+
+/// Looks up the sine table, `phase` wrapping every 64 steps
+fn sine(phase: u32) -> u32 {
+    SINE[(phase % 64) as usize] as u32
+}
+
+/// Implements functions for images of any size
+impl<const W: usize, const H: usize> GenericImage<W, H> {
     /// Creates new image with one given color
     pub fn new_solid(color: Color) -> Self {
-        Image([color; 64])
+        GenericImage([[color; W]; H])
+    }
+
+    /// Sets every pixel of the image to `color`
+    pub fn fill(&mut self, color: Color) {
+        self.pixels_slice_mut().fill(color);
+    }
+
+    /// Turns every pixel of the image black
+    pub fn clear(&mut self) {
+        self.fill(Color::BLACK);
+    }
+
+    /// Sets every pixel of a row, from 1 to H, to `color`
+    pub fn fill_row(&mut self, row: usize, color: Color) {
+        self.row_mut(row).fill(color);
+    }
+
+    /// Returns the position, from 0 to W * H - 1, of pixel (row, col) in the pixel
+    /// array, row going from 1 to H and column from 1 to W
+    pub const fn pixel_index(row: usize, col: usize) -> usize {
+        (row - 1) * W + col - 1
     }
 
-    /// Returns a line color array for a given line indice
+    /// Iterates over the rows in order, from row 1 to row H
+    pub fn rows(&self) -> impl ExactSizeIterator<Item = &[Color]> {
+        self.0.iter().map(|row| row.as_slice())
+    }
+
+    /// Iterates mutably over the rows in order, from row 1 to row H
+    pub fn rows_mut(&mut self) -> impl ExactSizeIterator<Item = &mut [Color]> {
+        self.0.iter_mut().map(|row| row.as_mut_slice())
+    }
+
+    /// Iterates over the pixels row after row
+    pub fn iter(&self) -> impl Iterator<Item = &Color> {
+        self.pixels_slice().iter()
+    }
+
+    /// Iterates mutably over the pixels row after row
+    pub fn iter_mut(&mut self) -> impl Iterator<Item = &mut Color> {
+        self.pixels_slice_mut().iter_mut()
+    }
+
+    /// Iterates mutably over the pixels row after row along with their row and
+    /// column, both starting at 1
+    pub fn pixels_mut(&mut self) -> impl Iterator<Item = (usize, usize, &mut Color)> {
+        self.pixels_slice_mut()
+            .iter_mut()
+            .enumerate()
+            .map(|(i, pixel)| (i / W + 1, i % W + 1, pixel))
+    }
+
+    /// Returns the pixel at (row, col), or None if either is outside of the image
+    pub fn get(&self, row: usize, col: usize) -> Option<&Color> {
+        if (1..=H).contains(&row) && (1..=W).contains(&col) {
+            Some(&self[Self::pixel_index(row, col)])
+        } else {
+            None
+        }
+    }
+
+    /// Returns the pixel at (row, col) for modification, or None if either is
+    /// outside of the image
+    pub fn get_mut(&mut self, row: usize, col: usize) -> Option<&mut Color> {
+        if (1..=H).contains(&row) && (1..=W).contains(&col) {
+            Some(&mut self[Self::pixel_index(row, col)])
+        } else {
+            None
+        }
+    }
+
+    /// Returns the W * H * 3 bytes of the image, R, G and B row after row
+    pub fn as_bytes(&self) -> &[u8] {
+        let pixels = self.pixels_slice();
+        // SAFETY: the pixels are W * H * 3 bytes without padding (see the
+        // assertions next to Image), and u8 has no alignment requirement
+        unsafe { core::slice::from_raw_parts(pixels.as_ptr() as *const u8, pixels.len() * 3) }
+    }
+
+    /// Returns the W * H * 3 bytes of the image for modification, R, G and B row
+    /// after row
+    pub fn as_bytes_mut(&mut self) -> &mut [u8] {
+        let pixels = self.pixels_slice_mut();
+        // SAFETY: same layout as in as_bytes, and every byte value is a valid
+        // channel value
+        unsafe { core::slice::from_raw_parts_mut(pixels.as_mut_ptr() as *mut u8, pixels.len() * 3) }
+    }
+
+    /// Returns a line color array for a given line indice, from 1 to H
     pub fn row(&self, row: usize) -> &[Color] {
-        &self.0[(row - 1) * 8..(row - 1) * 8 + 8]
+        debug_assert!((1..=H).contains(&row), "row out of the image");
+        &self.0[row - 1]
+    }
+
+    /// Returns a mutable line color array for a given line indice, from 1 to H
+    pub fn row_mut(&mut self, row: usize) -> &mut [Color] {
+        debug_assert!((1..=H).contains(&row), "row out of the image");
+        &mut self.0[row - 1]
+    }
+
+    /// Replaces the pixels of a row, from 1 to H
+    pub fn set_row(&mut self, row: usize, pixels: &[Color; W]) {
+        self.row_mut(row).copy_from_slice(pixels);
+    }
+
+    /// Returns the colors of a row, or None if `row` is not between 1 and H
+    pub fn row_checked(&self, row: usize) -> Option<&[Color]> {
+        if (1..=H).contains(&row) {
+            Some(self.row(row))
+        } else {
+            None
+        }
+    }
+
+    /// Returns the colors of a column, from 1 to W like rows, going from row 1 to row H
+    pub fn column(&self, col: usize) -> [Color; H] {
+        let mut column = [Color::default(); H];
+        for (row, pixel) in column.iter_mut().enumerate() {
+            *pixel = self[(row + 1, col)];
+        }
+        column
+    }
+
+    /// Replaces the pixels of a column, from 1 to W, going from row 1 to row H
+    pub fn set_column(&mut self, col: usize, pixels: &[Color; H]) {
+        for (row, &pixel) in pixels.iter().enumerate() {
+            self[(row + 1, col)] = pixel;
+        }
+    }
+
+    /// Returns the image with `f` applied to every pixel, row after row
+    pub fn map(&self, mut f: impl FnMut(Color) -> Color) -> Self {
+        let mut image = self.clone();
+        image.map_in_place(|pixel| *pixel = f(*pixel));
+        image
+    }
+
+    /// Applies `f` to every pixel row after row, without needing another image
+    pub fn map_in_place(&mut self, f: impl FnMut(&mut Color)) {
+        self.pixels_slice_mut().iter_mut().for_each(f);
+    }
+
+    /// Returns the mean of every channel over the image, rounded
+    pub fn average_color(&self) -> Color {
+        let (mut r, mut g, mut b) = (0u32, 0u32, 0u32);
+        for pixel in self.pixels_slice() {
+            r += pixel.r as u32;
+            g += pixel.g as u32;
+            b += pixel.b as u32;
+        }
+        let n = (W * H) as u32;
+        let mean = |sum: u32| ((sum + n / 2) / n) as u8;
+        Color {
+            r: mean(r),
+            g: mean(g),
+            b: mean(b),
+        }
+    }
+
+    /// Returns the largest channel value of the image, 0 for a black image
+    pub fn max_channel(&self) -> u8 {
+        self.as_bytes().iter().copied().max().unwrap_or(0)
+    }
+
+    /// Counts the pixels by luminance in 8 bins of 32 levels, from the darkest
+    pub fn histogram(&self) -> [u16; 8] {
+        let mut bins = [0; 8];
+        for pixel in self.pixels_slice() {
+            bins[(pixel.luminance() / 32) as usize] += 1;
+        }
+        bins
+    }
+
+    /// Builds an image of random noise. The same seed always gives the same image.
+    pub fn random(seed: u32) -> Self {
+        let mut image = Self::default();
+        image.randomize(&mut XorShift32::new(seed));
+        image
+    }
+
+    /// Replaces every pixel by a random color drawn from `rng`
+    pub fn randomize(&mut self, rng: &mut XorShift32) {
+        rng.fill_bytes(self.as_bytes_mut());
     }
 
-    /// Builds a gradient image from a given color
+    /// Builds a gradient image from a given color, fading radially
     pub fn gradient(color: Color) -> Self {
-        let mut image_grad = Image::default();
-        for line in 1..=8 {
-            for col in 1..=8 {
-                image_grad.index_mut((line, col)).r =
-                    (color.r as f32).div(1.0 + (line * line + col) as f32) as u8;
-                image_grad.index_mut((line, col)).g =
-                    (color.g as f32).div(1.0 + (line * line + col) as f32) as u8;
-                image_grad.index_mut((line, col)).b =
-                    (color.b as f32).div(1.0 + (line * line + col) as f32) as u8;
-            }
+        Self::gradient_dir(color, GradientDirection::Radial)
+    }
+
+    /// Builds a radial gradient image whose falloff is `steepness` times the one of
+    /// [gradient](GenericImage::gradient): 0 gives a solid image, 1 the same image
+    /// as `gradient` and larger values a darker one
+    pub fn gradient_steep(color: Color, steepness: u8) -> Self {
+        let mut image_grad = Self::default();
+        for (line, col, pixel) in image_grad.pixels_mut() {
+            *pixel = Self::faded(color, 1, 1 + steepness as usize * (line * line + col));
         }
         image_grad
     }
-}
 
-/// Implements default function for image type objects
-impl Default for Image {
-    fn default() -> Self {
-        Image([Color::default(); 64])
+    /// Builds a gradient image from a given color fading in the given direction.
+    /// Channels are rounded, and a channel which is not 0 in `color` stays at least
+    /// 1 so that dim colors do not vanish.
+    pub fn gradient_dir(color: Color, dir: GradientDirection) -> Self {
+        let mut image_grad = Self::default();
+        // The factor depends on the position, so this is map_in_place with
+        // the row and column of each pixel
+        for (line, col, pixel) in image_grad.pixels_mut() {
+            // Pixel scale as num / den, 1 for the brightest pixels of linear directions
+            let (num, den) = match dir {
+                GradientDirection::Horizontal => (W + 1 - col, W),
+                GradientDirection::Vertical => (H + 1 - line, H),
+                GradientDirection::DiagonalDown => (W + H + 1 - line - col, W + H - 1),
+                GradientDirection::DiagonalUp => (W + line - col, W + H - 1),
+                GradientDirection::Radial => (1, 1 + line * line + col),
+            };
+            *pixel = Self::faded(color, num, den);
+        }
+        image_grad
     }
-}
 
-/// Implements index function for image type objects
-impl core::ops::Index<(usize, usize)> for Image {
-    type Output = Color;
+    /// Scales `color` by `num / den` with rounding, keeping the channels which
+    /// are not 0 at least at 1
+    fn faded(color: Color, num: usize, den: usize) -> Color {
+        let fade = |c: u8| ((c as usize * num + den / 2) / den) as u8;
+        let keep_lit = |c: u8| fade(c).max(c.min(1));
+        Color {
+            r: keep_lit(color.r),
+            g: keep_lit(color.g),
+            b: keep_lit(color.b),
+        }
+    }
 
-    fn index(&self, index: (usize, usize)) -> &Self::Output {
-        &self.0[(index.0 - 1) * 8 + index.1 - 1]
+    /// Returns the pixels as one slice, row after row
+    fn pixels_slice(&self) -> &[Color] {
+        self.0.as_flattened()
     }
-}
 
-/// Implements mutable index function for image type objects
-impl core::ops::IndexMut<(usize, usize)> for Image {
-    fn index_mut(&mut self, index: (usize, usize)) -> &mut Self::Output {
-        &mut self.0[(index.0 - 1) * 8 + index.1 - 1]
+    /// Returns the pixels as one mutable slice, row after row
+    fn pixels_slice_mut(&mut self) -> &mut [Color] {
+        self.0.as_flattened_mut()
     }
 }
 
-/// Implements as_ref() function for image type objects
-impl AsRef<[u8; 192]> for Image {
-    fn as_ref(&self) -> &[u8; 192] {
-        unsafe { core::mem::transmute(self) }
+/// Implements functions specific to images of the 8x8 panel
+impl Image {
+    /// Builds an image from 192 bytes, pixels going row after row in R, G, B order
+    /// like [as_bytes](GenericImage::as_bytes) returns them
+    pub fn from_bytes(bytes: &[u8; 192]) -> Image {
+        let mut image = Image::default();
+        image.as_bytes_mut().copy_from_slice(bytes);
+        image
+    }
+
+    /// Returns the pixels of the multiplexed line `line` in the given scan mode
+    pub fn line(&self, mode: ScanMode, line: usize) -> [Color; 8] {
+        match mode {
+            ScanMode::Rows => {
+                let mut row = [Color::default(); 8];
+                row.copy_from_slice(self.row(line));
+                row
+            }
+            ScanMode::Columns => self.column(line),
+        }
+    }
+
+    /// Exports the image column by column, for panels whose shift registers are
+    /// wired along the columns
+    pub fn write_column_major(&self, out: &mut [u8; 192], order: ChannelOrder) {
+        for row in 1..=8 {
+            for col in 1..=8 {
+                let pos = scan_position(ScanMode::Columns, row, col) * 3;
+                out[pos..pos + 3].copy_from_slice(&order.channels(self[(row, col)]));
+            }
+        }
+    }
+
+    /// Encodes the image as an SE203 frame: the synchronization byte followed by the
+    /// pixels. 255 is sent as 254 since it would restart the frame.
+    pub fn encode_se203(&self) -> [u8; 1 + protocol::FRAME_LEN] {
+        let mut encoded = [protocol::SYNC; 1 + protocol::FRAME_LEN];
+        for (out, &b) in encoded[1..].iter_mut().zip(self.as_bytes().iter()) {
+            *out = b.min(protocol::SYNC - 1);
+        }
+        encoded
+    }
+
+    /// Streams the image as an SE203 frame, byte by byte, like [Image::encode_se203]
+    /// but without building the whole frame
+    pub fn to_protocol_bytes(&self) -> impl Iterator<Item = u8> + '_ {
+        core::iter::once(protocol::SYNC)
+            .chain(self.as_bytes().iter().map(|&b| b.min(protocol::SYNC - 1)))
+    }
+
+    /// Returns the image with every pixel converted to the gray of the same luminance
+    pub fn to_grayscale(&self) -> Image {
+        self.map(|pixel| pixel.to_grayscale())
+    }
+
+    /// Returns the negative of the image
+    pub fn invert(&self) -> Image {
+        self.map(|pixel| pixel.invert())
+    }
+
+    /// Turns the image into its negative without needing another image
+    pub fn invert_in_place(&mut self) {
+        self.map_in_place(|pixel| *pixel = pixel.invert());
+    }
+
+    /// Returns the image with `other` drawn on top of it with an opacity of
+    /// `alpha` out of 255, for instance to fade between two images
+    pub fn blend(&self, other: &Image, alpha: u8) -> Image {
+        let mut image = self.clone();
+        for (pixel, &over) in image.iter_mut().zip(other.iter()) {
+            *pixel = pixel.blend(over, alpha);
+        }
+        image
     }
-}
 
-/// Implements as_mut() function for image type objects
-impl AsMut<[u8; 192]> for Image {
-    fn as_mut(&mut self) -> &mut [u8; 192] {
-        unsafe { core::mem::transmute(self) }
+    /// Returns the image dimmed by `factor / 255`
+    pub fn dimmed(&self, factor: u8) -> Image {
+        let mut image = self.clone();
+        image.dim_in_place(factor);
+        image
+    }
+
+    /// Dims the image by `factor / 255` without needing another image, 255 leaving
+    /// it unchanged and 0 turning it black
+    pub fn dim_in_place(&mut self, factor: u8) {
+        self.map_in_place(|pixel| *pixel = *pixel * factor);
+    }
+
+    /// Returns the image turned a quarter clockwise
+    pub fn rotated_90(&self) -> Image {
+        let mut image = self.transposed();
+        image.flip_horizontal();
+        image
+    }
+
+    /// Returns the image turned upside down
+    pub fn rotated_180(&self) -> Image {
+        let mut image = self.clone();
+        image.rotate_180();
+        image
+    }
+
+    /// Returns the image turned a quarter counterclockwise
+    pub fn rotated_270(&self) -> Image {
+        let mut image = self.transposed();
+        image.flip_vertical();
+        image
+    }
+
+    /// Turns the image upside down without needing another image, pixel (row, col)
+    /// going to (9 - row, 9 - col) which is the reversed pixel order
+    pub fn rotate_180(&mut self) {
+        self.pixels_slice_mut().reverse();
+    }
+
+    /// Swaps rows and columns in place, pixel (row, col) going to (col, row)
+    pub fn transpose(&mut self) {
+        for row in 1..=8 {
+            for col in row + 1..=8 {
+                self.pixels_slice_mut()
+                    .swap(Image::pixel_index(row, col), Image::pixel_index(col, row));
+            }
+        }
+    }
+
+    /// Returns the image with rows and columns swapped
+    pub fn transposed(&self) -> Image {
+        let mut image = self.clone();
+        image.transpose();
+        image
+    }
+
+    /// Returns the image laid out with the given orientation
+    pub fn orient(&self, o: Orientation) -> Image {
+        let mut image = self.clone();
+        match o {
+            Orientation::Identity => {}
+            Orientation::Rotate90 => {
+                image.transpose();
+                image.flip_horizontal();
+            }
+            Orientation::Rotate180 => image.rotate_180(),
+            Orientation::Rotate270 => {
+                image.transpose();
+                image.flip_vertical();
+            }
+            Orientation::FlipHorizontal => image.flip_horizontal(),
+            Orientation::FlipVertical => image.flip_vertical(),
+            Orientation::Transpose => image.transpose(),
+            Orientation::AntiTranspose => {
+                image.transpose();
+                image.rotate_180();
+            }
+        }
+        image
+    }
+
+    /// Mirrors the image left to right in place
+    pub fn flip_horizontal(&mut self) {
+        for row in self.rows_mut() {
+            row.reverse();
+        }
+    }
+
+    /// Mirrors the image top to bottom in place
+    pub fn flip_vertical(&mut self) {
+        self.0.reverse();
+    }
+
+    /// Returns the image mirrored left to right
+    pub fn flipped_horizontal(&self) -> Image {
+        let mut image = self.clone();
+        image.flip_horizontal();
+        image
+    }
+
+    /// Returns the image mirrored top to bottom
+    pub fn flipped_vertical(&self) -> Image {
+        let mut image = self.clone();
+        image.flip_vertical();
+        image
+    }
+
+    /// Moves every pixel by `dx` columns to the right and `dy` rows down, pixels
+    /// going out on one edge coming back on the opposite one. Negative offsets move
+    /// left and up, and offsets are taken modulo 8.
+    pub fn scroll(&mut self, dx: i8, dy: i8) {
+        let dx = dx.rem_euclid(8) as usize;
+        for row in self.rows_mut() {
+            row.rotate_right(dx);
+        }
+        self.0.rotate_right(dy.rem_euclid(8) as usize);
+    }
+
+    /// Moves every pixel by `dx` columns to the right and `dy` rows down like
+    /// [scroll](Image::scroll), except that pixels going out are lost and the
+    /// uncovered ones are set to `fill`
+    pub fn shift(&mut self, dx: i8, dy: i8, fill: Color) {
+        let source = self.clone();
+        for (row, col, pixel) in self.pixels_mut() {
+            let from_row = row as isize - dy as isize;
+            let from_col = col as isize - dx as isize;
+            *pixel = if (1..=8).contains(&from_row) && (1..=8).contains(&from_col) {
+                source[(from_row as usize, from_col as usize)]
+            } else {
+                fill
+            };
+        }
+    }
+
+    /// Copies `src` over the image, skipping its pixels equal to `transparent`
+    pub fn overlay(&mut self, src: &Image, transparent: Option<Color>) {
+        self.overlay_at(src, 0, 0, transparent);
+    }
+
+    /// Copies `src` over the image moved by `dx` columns to the right and `dy` rows
+    /// down, skipping its pixels equal to `transparent`. Pixels of `src` falling
+    /// outside of the image are clipped.
+    pub fn overlay_at(&mut self, src: &Image, dx: i8, dy: i8, transparent: Option<Color>) {
+        for (row, col, pixel) in self.pixels_mut() {
+            let from_row = row as isize - dy as isize;
+            let from_col = col as isize - dx as isize;
+            if !(1..=8).contains(&from_row) || !(1..=8).contains(&from_col) {
+                continue;
+            }
+            let over = src[(from_row as usize, from_col as usize)];
+            if Some(over) != transparent {
+                *pixel = over;
+            }
+        }
+    }
+
+    /// Copies the 8x8 window whose top left pixel is at column `x` and row `y`, both
+    /// starting at 0, of a row-major buffer of `src_width` by `src_height` pixels.
+    /// Pixels of the window outside of the buffer are black.
+    pub fn crop_from(
+        src: &[Color],
+        src_width: usize,
+        src_height: usize,
+        x: usize,
+        y: usize,
+    ) -> Image {
+        let mut image = Image::default();
+        for (row, col, pixel) in image.pixels_mut() {
+            let (src_x, src_y) = (x + col - 1, y + row - 1);
+            if src_x < src_width && src_y < src_height {
+                if let Some(&color) = src.get(src_y * src_width + src_x) {
+                    *pixel = color;
+                }
+            }
+        }
+        image
+    }
+
+    /// Copies the image into a row-major buffer `dst_width` pixels wide, its top
+    /// left pixel going to column `x` and row `y`, both starting at 0. Pixels
+    /// falling outside of the buffer are clipped.
+    pub fn paste_into(&self, dst: &mut [Color], dst_width: usize, x: usize, y: usize) {
+        for (i, row) in self.rows().enumerate() {
+            for (j, &pixel) in row.iter().enumerate() {
+                if x + j >= dst_width {
+                    break;
+                }
+                if let Some(target) = dst.get_mut((y + i) * dst_width + x + j) {
+                    *target = pixel;
+                }
+            }
+        }
+    }
+
+    /// Moves every channel of every pixel by at most `step` towards `target`, so that
+    /// calling it once per frame fades into `target` in at most 255 / `step` frames
+    /// (rounded up)
+    pub fn fade_towards(&mut self, target: &Image, step: u8) {
+        for (c, &t) in self.as_bytes_mut().iter_mut().zip(target.as_bytes().iter()) {
+            *c = if *c < t {
+                c.saturating_add(step).min(t)
+            } else {
+                c.saturating_sub(step).max(t)
+            };
+        }
+    }
+
+    /// Returns true if every pixel is equal to the one of `other`, for instance
+    /// to detect the end of a [fade](Image::fade_towards)
+    pub fn is_equal(&self, other: &Image) -> bool {
+        self == other
+    }
+
+    /// Iterates over the row and column, both from 1 to 8, of the pixels which
+    /// differ between the image and `other`, row after row
+    pub fn diff<'a>(&'a self, other: &'a Image) -> impl Iterator<Item = (usize, usize)> + 'a {
+        self.iter()
+            .zip(other.iter())
+            .enumerate()
+            .filter(|(_, (a, b))| a != b)
+            .map(|(i, _)| (i / 8 + 1, i % 8 + 1))
+    }
+
+    /// Returns the number of pixels which differ between the image and `other`
+    pub fn count_diff(&self, other: &Image) -> usize {
+        self.diff(other).count()
+    }
+
+    /// Draws the lit pixels of the 5x7 glyph of `c` in `color`, its top left pixel
+    /// moved by `x` columns and `y` rows from pixel (1, 1). Pixels falling outside
+    /// of the image are clipped, and characters missing from the font are drawn as
+    /// a filled box.
+    pub fn draw_char(&mut self, c: char, x: i8, y: i8, color: Color) {
+        let glyph = font::glyph(c);
+        for glyph_col in 0..font::WIDTH {
+            for glyph_row in 0..font::HEIGHT {
+                if font::is_lit(glyph, glyph_col, glyph_row) {
+                    self.plot(
+                        x as isize + glyph_col as isize,
+                        y as isize + glyph_row as isize,
+                        color,
+                    );
+                }
+            }
+        }
+    }
+
+    /// Draws `text` with the 5x7 font, one blank column between characters, the top
+    /// left pixel of the first one moved by `x` columns and `y` rows from pixel
+    /// (1, 1). Every lit pixel is white over a dark background pixel and black over
+    /// a light one (see [AUTO_CONTRAST_THRESHOLD]), so the text stays readable over
+    /// any frame. Pixels outside of the image are clipped.
+    pub fn draw_text_auto(&mut self, text: &str, x: i32, y: i32) {
+        let Ok(y) = i8::try_from(y) else {
+            return;
+        };
+        // Lit pixels of the text, drawn with draw_char on a blank mask
+        let mut mask = Image::default();
+        for (i, c) in text.chars().enumerate() {
+            let char_x = x as i64 + i as i64 * (font::WIDTH as i64 + 1);
+            if char_x >= 8 {
+                break;
+            }
+            if let Ok(char_x) = i8::try_from(char_x) {
+                mask.draw_char(c, char_x, y, Color::WHITE);
+            }
+        }
+        for row in 1..=8 {
+            for col in 1..=8 {
+                if mask[(row, col)] == Color::WHITE {
+                    let background = self[(row, col)];
+                    self[(row, col)] = if background.is_dark(AUTO_CONTRAST_THRESHOLD) {
+                        Color::WHITE
+                    } else {
+                        Color::BLACK
+                    };
+                }
+            }
+        }
+    }
+
+    /// Draws the outline of the `w` by `h` rectangle whose top left pixel is moved
+    /// by `x` columns and `y` rows from pixel (1, 1). The rectangle covers columns
+    /// `x` to `x + w - 1` and rows `y` to `y + h - 1`, the far edges `x + w` and
+    /// `y + h` being excluded. Pixels outside of the image are clipped and an empty
+    /// rectangle draws nothing.
+    pub fn draw_rect(&mut self, x: i8, y: i8, w: u8, h: u8, color: Color) {
+        if w == 0 || h == 0 {
+            return;
+        }
+        let (left, top) = (x as isize, y as isize);
+        let (right, bottom) = (left + w as isize - 1, top + h as isize - 1);
+        for col in left..=right {
+            self.plot(col, top, color);
+            self.plot(col, bottom, color);
+        }
+        for row in top..=bottom {
+            self.plot(left, row, color);
+            self.plot(right, row, color);
+        }
+    }
+
+    /// Fills the rectangle described in [draw_rect](Image::draw_rect)
+    pub fn fill_rect(&mut self, x: i8, y: i8, w: u8, h: u8, color: Color) {
+        // Only walk the part of the rectangle which is inside the image
+        let clip =
+            |start: i8, len: u8| (start as isize).max(0)..(start as isize + len as isize).min(8);
+        for row in clip(y, h) {
+            for col in clip(x, w) {
+                self.plot(col, row, color);
+            }
+        }
+    }
+
+    /// Draws the circle of radius `r` centered on the pixel moved by `cx` columns and
+    /// `cy` rows from pixel (1, 1), with the integer midpoint algorithm. Radius 0
+    /// draws the center only, and the visible arc of a circle centered outside of
+    /// the image is still drawn.
+    pub fn draw_circle(&mut self, cx: i8, cy: i8, r: u8, color: Color) {
+        let (cx, cy) = (cx as isize, cy as isize);
+        let (mut x, mut y, mut d) = (r as isize, 0, 1 - r as isize);
+        while x >= y {
+            for (dx, dy) in [
+                (x, y),
+                (y, x),
+                (-y, x),
+                (-x, y),
+                (-x, -y),
+                (-y, -x),
+                (y, -x),
+                (x, -y),
+            ] {
+                self.plot(cx + dx, cy + dy, color);
+            }
+            y += 1;
+            if d < 0 {
+                d += 2 * y + 1;
+            } else {
+                x -= 1;
+                d += 2 * (y - x) + 1;
+            }
+        }
+    }
+
+    /// Fills the circle described in [draw_circle](Image::draw_circle)
+    pub fn fill_circle(&mut self, cx: i8, cy: i8, r: u8, color: Color) {
+        let (cx, cy) = (cx as isize, cy as isize);
+        let (mut x, mut y, mut d) = (r as isize, 0, 1 - r as isize);
+        while x >= y {
+            self.plot_span(cx - x, cx + x, cy + y, color);
+            self.plot_span(cx - x, cx + x, cy - y, color);
+            self.plot_span(cx - y, cx + y, cy + x, color);
+            self.plot_span(cx - y, cx + y, cy - x, color);
+            y += 1;
+            if d < 0 {
+                d += 2 * y + 1;
+            } else {
+                x -= 1;
+                d += 2 * (y - x) + 1;
+            }
+        }
+    }
+
+    /// Sets the pixels from `x0` to `x1` included on row `y`, with the offsets of
+    /// [plot](Image::plot), clipped to the image
+    fn plot_span(&mut self, x0: isize, x1: isize, y: isize, color: Color) {
+        for x in x0.max(0)..=x1.min(7) {
+            self.plot(x, y, color);
+        }
+    }
+
+    /// Sets the pixel moved by `x` columns and `y` rows from pixel (1, 1), if it is
+    /// inside the image
+    fn plot(&mut self, x: isize, y: isize, color: Color) {
+        if (0..8).contains(&x) && (0..8).contains(&y) {
+            self[(y as usize + 1, x as usize + 1)] = color;
+        }
+    }
+
+    /// Snaps every channel to the nearest of `levels` evenly spaced values from 0 to
+    /// 255, from 2 levels (a channel becomes 255 from 128 upwards, 0 below) to 256
+    /// levels (image unchanged).
+    ///
+    /// # Panics
+    /// This function panics if `levels` is not between 2 and 256.
+    pub fn posterize(&mut self, levels: u16) {
+        assert!(
+            (2..=256).contains(&levels),
+            "posterize needs 2 to 256 levels"
+        );
+        let steps = (levels - 1) as u32;
+        let snap = |c: u8| {
+            let level = (c as u32 * steps + 127) / 255;
+            ((level * 255 + steps / 2) / steps) as u8
+        };
+        for pixel in self.iter_mut() {
+            *pixel = Color {
+                r: snap(pixel.r),
+                g: snap(pixel.g),
+                b: snap(pixel.b),
+            };
+        }
+    }
+
+    /// Replaces every pixel with the nearest palette color, using the squared
+    /// distance between channels. On a tie the first of the nearest colors in the
+    /// palette wins. An empty palette leaves the image unchanged.
+    pub fn quantize_palette(&mut self, palette: &[Color]) {
+        let distance = |a: &Color, b: &Color| {
+            let d = |x: u8, y: u8| (x as i32 - y as i32).pow(2) as u32;
+            d(a.r, b.r) + d(a.g, b.g) + d(a.b, b.b)
+        };
+        for pixel in self.iter_mut() {
+            // min_by_key returns the first of several equal minimums
+            if let Some(nearest) = palette.iter().min_by_key(|color| distance(pixel, color)) {
+                *pixel = *nearest;
+            }
+        }
+    }
+
+    /// Builds a checkerboard test pattern, pixel (1, 1) being `a`
+    pub fn checkerboard(a: Color, b: Color) -> Self {
+        let mut image = Image::default();
+        for (row, col, pixel) in image.pixels_mut() {
+            *pixel = if (row + col) % 2 == 0 { a } else { b };
+        }
+        image
+    }
+
+    /// Builds a test pattern with only the outer ring of pixels lit
+    pub fn border(color: Color) -> Self {
+        let mut image = Image::default();
+        for (row, col, pixel) in image.pixels_mut() {
+            if row == 1 || row == 8 || col == 1 || col == 8 {
+                *pixel = color;
+            }
+        }
+        image
+    }
+
+    /// Builds a test pattern with only the four corner pixels lit
+    pub fn corners(color: Color) -> Self {
+        let mut image = Image::default();
+        for (row, col) in [(1, 1), (1, 8), (8, 1), (8, 8)] {
+            image[(row, col)] = color;
+        }
+        image
+    }
+
+    /// Runs one generation of Conway's game of life (B3/S23) on the panel, whose
+    /// edges wrap around. Pixels whose luminance is above [LIFE_THRESHOLD] are alive;
+    /// the result is painted with `alive` and `dead`.
+    pub fn life_step(&self, alive: Color, dead: Color) -> Image {
+        let mut next = self.clone();
+        next.life_step_in_place(alive, dead);
+        next
+    }
+
+    /// Same as [life_step](Image::life_step), keeping the current generation in a
+    /// 64 bit mask rather than in a second image
+    pub fn life_step_in_place(&mut self, alive: Color, dead: Color) {
+        let mut cells = 0u64;
+        for (pos, pixel) in self.pixels_slice().iter().enumerate() {
+            if pixel.luminance() > LIFE_THRESHOLD {
+                cells |= 1 << pos;
+            }
+        }
+        let is_alive = |row: usize, col: usize| cells & (1 << ((row % 8) * 8 + col % 8)) != 0;
+        for (pos, pixel) in self.pixels_slice_mut().iter_mut().enumerate() {
+            let (row, col) = (pos / 8, pos % 8);
+            // Adding 7 instead of subtracting 1 wraps around the edges
+            let neighbours = [7, 0, 1]
+                .iter()
+                .flat_map(|&dr| [7, 0, 1].iter().map(move |&dc| (dr, dc)))
+                .filter(|&(dr, dc)| (dr, dc) != (0, 0) && is_alive(row + dr, col + dc))
+                .count();
+            let lives = matches!((is_alive(row, col), neighbours), (true, 2) | (_, 3));
+            *pixel = if lives { alive } else { dead };
+        }
+    }
+
+    /// Builds frame `t` of a plasma effect: the sum of four sine waves running
+    /// horizontally, vertically, diagonally and around the center, used as a hue.
+    /// Only table lookups and one HSV conversion per pixel are needed.
+    pub fn plasma(t: u32) -> Image {
+        let mut image = Image::default();
+        for (row, col, pixel) in image.pixels_mut() {
+            let (x, y) = (col as u32, row as u32);
+            // Squared distance to the center of the panel, counted in half pixels
+            let (dx, dy) = ((2 * x).abs_diff(9), (2 * y).abs_diff(9));
+            let sum = sine(4 * x + t)
+                + sine(4 * y + 2 * t)
+                + sine(3 * (x + y) + 3 * t)
+                + sine((dx * dx + dy * dy) / 2 + t);
+            *pixel = Color::from_hsv((sum as f32) * (360.0 / 1020.0), 1.0, 1.0);
+        }
+        image
+    }
+
+    /// Moves the image to the left by a fraction `phase / 255` of a pixel, blending
+    /// every pixel with its right neighbour. The column entering on the right is
+    /// `next_column` (from row 1 to row 8). Phase 0 leaves the image unchanged and
+    /// phase 255 is a full one pixel shift, so smooth scrolling calls this on a copy
+    /// of the frame with increasing phases, then shifts the frame by one pixel.
+    pub fn scroll_left_smooth(&mut self, next_column: &[Color; 8], phase: u8) {
+        for line in 1..=8 {
+            // Columns are updated from left to right so the right neighbour is still unchanged
+            for col in 1..=8 {
+                let next = if col < 8 {
+                    self[(line, col + 1)]
+                } else {
+                    next_column[line - 1]
+                };
+                self[(line, col)] = self[(line, col)].lerp_u8(next, phase);
+            }
+        }
+    }
+}
+
+/// Position of the next line to display, which can only be a valid line: it goes
+/// from 1 to 8 and wraps around to 1
+#[derive(Clone, Copy)]
+pub struct RowCursor {
+    next: usize,
+}
+
+/// Implements functions for row cursor structure
+impl RowCursor {
+    /// Creates a cursor on the first line of a frame
+    pub const fn new() -> Self {
+        RowCursor { next: 1 }
+    }
+
+    /// Returns true if the next line is the first one of a frame
+    pub fn at_frame_start(&self) -> bool {
+        self.next == 1
+    }
+
+    /// Returns the next line index and moves to the following one
+    pub fn next_line(&mut self) -> usize {
+        let line = self.next;
+        self.next = line % 8 + 1;
+        line
+    }
+
+    /// Returns the next row of `image` with its index and moves to the following one
+    pub fn advance<'a>(&mut self, image: &'a Image) -> (usize, &'a [Color]) {
+        let row = self.next_line();
+        (row, image.row(row))
+    }
+}
+
+/// Implements default function for row cursor type objects
+impl Default for RowCursor {
+    fn default() -> Self {
+        RowCursor::new()
+    }
+}
+
+/// Maximum number of characters of a [TextScroller], longer texts are truncated
+pub const SCROLL_TEXT_LEN: usize = 64;
+
+/// Number of frames a smooth [TextScroller] takes to move by one column
+pub const SMOOTH_SCROLL_STEPS: u8 = 8;
+
+/// Produces the frames of a text scrolling from right to left, looping at its end.
+/// Characters are drawn with the 5x7 font on rows 1 to 7, followed by one blank
+/// column each.
+pub struct TextScroller {
+    text: heapless::Vec<char, SCROLL_TEXT_LEN>,
+    color: Color,
+    offset: usize,
+    smooth: bool,
+    /// Step of a smooth scroll between two columns, below [SMOOTH_SCROLL_STEPS]
+    step: u8,
+}
+
+/// Implements functions for text scroller structure
+impl TextScroller {
+    /// Creates a scroller whose first frame starts with the first character of
+    /// `text`, truncated to [SCROLL_TEXT_LEN] characters
+    pub fn new(text: &str, color: Color) -> Self {
+        TextScroller {
+            text: text.chars().take(SCROLL_TEXT_LEN).collect(),
+            color,
+            offset: 0,
+            smooth: false,
+            step: 0,
+        }
+    }
+
+    /// Chooses between moving by one column per frame and gliding by one column
+    /// every [SMOOTH_SCROLL_STEPS] frames with [Image::scroll_left_smooth]
+    pub fn set_smooth(&mut self, smooth: bool) {
+        self.smooth = smooth;
+        self.step = 0;
+    }
+
+    /// Width in columns of the rasterized text, after which frames repeat
+    pub fn width(&self) -> usize {
+        self.text.len() * (font::WIDTH + 1)
+    }
+
+    /// Returns the next frame, one column (or one smooth step) further into the text
+    pub fn next_frame(&mut self) -> Image {
+        let mut frame = Image::default();
+        animation::Animation::next_frame(self, &mut frame);
+        frame
+    }
+
+    /// Returns a column of the rasterized text, from row 1 to row 8
+    fn column(&self, text_col: usize) -> [Color; 8] {
+        let mut pixels = [Color::BLACK; 8];
+        let glyph_col = text_col % (font::WIDTH + 1);
+        if glyph_col < font::WIDTH {
+            let glyph = font::glyph(self.text[text_col / (font::WIDTH + 1)]);
+            for row in 1..=font::HEIGHT {
+                if font::is_lit(glyph, glyph_col, row - 1) {
+                    pixels[row - 1] = self.color;
+                }
+            }
+        }
+        pixels
+    }
+}
+
+/// Each frame is the window of 8 columns starting at the current column of the
+/// rasterized text, wrapping around at its end. In smooth mode the window is moved
+/// by a fraction of a column towards the next one.
+impl animation::Animation for TextScroller {
+    fn next_frame(&mut self, frame: &mut Image) {
+        frame.clear();
+        let width = self.width();
+        if width == 0 {
+            return;
+        }
+        for col in 1..=8 {
+            frame.set_column(col, &self.column((self.offset + col - 1) % width));
+        }
+        if self.smooth {
+            let phase = (self.step as u16 * 255 / SMOOTH_SCROLL_STEPS as u16) as u8;
+            frame.scroll_left_smooth(&self.column((self.offset + 8) % width), phase);
+            self.step += 1;
+            if self.step < SMOOTH_SCROLL_STEPS {
+                return;
+            }
+            self.step = 0;
+        }
+        self.offset = (self.offset + 1) % width;
+    }
+}
+
+/// Order in which a [FrameSequence] plays its frames
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum PlaybackMode {
+    /// From the first frame to the last one, then from the first one again
+    Loop,
+    /// From the first frame to the last one and back, without repeating the ends
+    PingPong,
+}
+
+/// Sequence of `N` frames, each one shown for its own number of ticks. Unlike
+/// the [animation::Animation] trait, which generates frames, it stores them, so
+/// that a fixed animation can be kept in a `static` and played when idle.
+pub struct FrameSequence<const N: usize> {
+    frames: [Image; N],
+    durations: [u32; N],
+    mode: PlaybackMode,
+    index: usize,
+    forward: bool,
+}
+
+/// Implements functions for frame sequence structure
+impl<const N: usize> FrameSequence<N> {
+    /// Creates a sequence starting at its first frame, frame `i` lasting
+    /// `durations[i]` ticks. Panics if there is no frame.
+    pub const fn new(frames: [Image; N], durations: [u32; N], mode: PlaybackMode) -> Self {
+        assert!(N > 0, "a frame sequence needs at least one frame");
+        FrameSequence {
+            frames,
+            durations,
+            mode,
+            index: 0,
+            forward: true,
+        }
+    }
+
+    /// Returns the frame to display
+    pub fn current(&self) -> &Image {
+        &self.frames[self.index]
+    }
+
+    /// Returns the number of ticks during which the current frame is displayed
+    pub fn duration(&self) -> u32 {
+        self.durations[self.index]
+    }
+
+    /// Moves to the next frame according to the playback mode
+    pub fn advance(&mut self) {
+        if N == 1 {
+            return;
+        }
+        match self.mode {
+            PlaybackMode::Loop => self.index = (self.index + 1) % N,
+            PlaybackMode::PingPong => {
+                if self.forward && self.index == N - 1 {
+                    self.forward = false;
+                } else if !self.forward && self.index == 0 {
+                    self.forward = true;
+                }
+                if self.forward {
+                    self.index += 1;
+                } else {
+                    self.index -= 1;
+                }
+            }
+        }
+    }
+
+    /// Goes back to the first frame
+    pub fn reset(&mut self) {
+        self.index = 0;
+        self.forward = true;
+    }
+}
+
+/// Each frame is the current stored frame, the sequence then advances
+impl<const N: usize> animation::Animation for FrameSequence<N> {
+    fn next_frame(&mut self, frame: &mut Image) {
+        frame.clone_from(self.current());
+        self.advance();
+    }
+}
+
+/// Implements default function for image type objects
+impl<const W: usize, const H: usize> Default for GenericImage<W, H> {
+    fn default() -> Self {
+        GenericImage([[Color::default(); W]; H])
+    }
+}
+
+/// Implements Debug for image type objects, printing rows of `#rrggbb` pixels
+impl<const W: usize, const H: usize> core::fmt::Debug for GenericImage<W, H> {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        writeln!(f, "Image [")?;
+        for row in self.rows() {
+            write!(f, "   ")?;
+            for pixel in row {
+                write!(f, " #{:02x}{:02x}{:02x}", pixel.r, pixel.g, pixel.b)?;
+            }
+            writeln!(f)?;
+        }
+        write!(f, "]")
+    }
+}
+
+/// Implements defmt Format for image type objects, with the same layout as Debug
+#[cfg(feature = "defmt")]
+impl<const W: usize, const H: usize> defmt::Format for GenericImage<W, H> {
+    fn format(&self, f: defmt::Formatter) {
+        defmt::write!(f, "Image [\n");
+        for row in self.rows() {
+            defmt::write!(f, "   ");
+            for pixel in row {
+                defmt::write!(
+                    f,
+                    " #{=u8:02x}{=u8:02x}{=u8:02x}",
+                    pixel.r,
+                    pixel.g,
+                    pixel.b
+                );
+            }
+            defmt::write!(f, "\n");
+        }
+        defmt::write!(f, "]");
+    }
+}
+
+/// Implements index function for image type objects
+impl<const W: usize, const H: usize> core::ops::Index<(usize, usize)> for GenericImage<W, H> {
+    type Output = Color;
+
+    fn index(&self, index: (usize, usize)) -> &Self::Output {
+        &self.0[index.0 - 1][index.1 - 1]
+    }
+}
+
+/// Implements mutable index function for image type objects
+impl<const W: usize, const H: usize> core::ops::IndexMut<(usize, usize)> for GenericImage<W, H> {
+    fn index_mut(&mut self, index: (usize, usize)) -> &mut Self::Output {
+        &mut self.0[index.0 - 1][index.1 - 1]
+    }
+}
+
+/// Implements linear index function for image type objects, pixels going from
+/// 0 to W * H - 1 row after row
+impl<const W: usize, const H: usize> core::ops::Index<usize> for GenericImage<W, H> {
+    type Output = Color;
+
+    fn index(&self, index: usize) -> &Self::Output {
+        &self.pixels_slice()[index]
+    }
+}
+
+/// Implements mutable linear index function for image type objects
+impl<const W: usize, const H: usize> core::ops::IndexMut<usize> for GenericImage<W, H> {
+    fn index_mut(&mut self, index: usize) -> &mut Self::Output {
+        &mut self.pixels_slice_mut()[index]
+    }
+}
+
+/// Implements conversion from a byte slice for image type objects, which must hold
+/// exactly W * H * 3 bytes
+impl<const W: usize, const H: usize> TryFrom<&[u8]> for GenericImage<W, H> {
+    type Error = ImageBytesError;
+
+    fn try_from(bytes: &[u8]) -> Result<Self, Self::Error> {
+        let mut image = Self::default();
+        if bytes.len() != W * H * 3 {
+            return Err(ImageBytesError::InvalidLength(bytes.len()));
+        }
+        image.as_bytes_mut().copy_from_slice(bytes);
+        Ok(image)
+    }
+}
+
+/// Implements as_ref() function for image type objects, viewing them as bytes
+impl<const W: usize, const H: usize> AsRef<[u8]> for GenericImage<W, H> {
+    fn as_ref(&self) -> &[u8] {
+        self.as_bytes()
+    }
+}
+
+/// Implements as_mut() function for image type objects, viewing them as bytes
+impl<const W: usize, const H: usize> AsMut<[u8]> for GenericImage<W, H> {
+    fn as_mut(&mut self) -> &mut [u8] {
+        self.as_bytes_mut()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Image whose pixels all differ, red giving the row and green the column
+    fn test_image() -> Image {
+        let mut image = Image::default();
+        for (row, col, pixel) in image.pixels_mut() {
+            *pixel = Color {
+                r: 30 * row as u8,
+                g: 30 * col as u8,
+                b: 100,
+            };
+        }
+        image
+    }
+
+    const NEXT_COLUMN: [Color; 8] = [Color::WHITE; 8];
+
+    #[test]
+    fn smooth_scroll_phase_0_is_no_shift() {
+        let mut image = test_image();
+        image.scroll_left_smooth(&NEXT_COLUMN, 0);
+        assert_eq!(image, test_image());
+    }
+
+    #[test]
+    fn smooth_scroll_phase_255_is_a_full_shift() {
+        let mut image = test_image();
+        image.scroll_left_smooth(&NEXT_COLUMN, 255);
+        let original = test_image();
+        for row in 1..=8 {
+            for col in 1..8 {
+                assert_eq!(image[(row, col)], original[(row, col + 1)]);
+            }
+            assert_eq!(image[(row, 8)], Color::WHITE);
+        }
+    }
+
+    #[test]
+    fn smooth_scroll_is_monotonic_per_channel() {
+        let mut previous = test_image();
+        for phase in 1..=255 {
+            let mut image = test_image();
+            image.scroll_left_smooth(&NEXT_COLUMN, phase);
+            // Every channel of test_image grows to the right and towards NEXT_COLUMN
+            for (a, b) in previous.as_ref().iter().zip(image.as_ref()) {
+                assert!(a <= b, "phase {}", phase);
+            }
+            previous = image;
+        }
+    }
+
+    /// Colors of the lit pixels of "I" drawn with draw_text_auto at (1, 0) over `background`
+    fn auto_text_colors(background: Image) -> heapless::Vec<Color, 64> {
+        let mut image = background.clone();
+        image.draw_text_auto("I", 1, 0);
+        let mut mask = Image::default();
+        mask.draw_char('I', 1, 0, Color::WHITE);
+        mask.iter()
+            .zip(image.iter())
+            .filter(|(&lit, _)| lit == Color::WHITE)
+            .map(|(_, &color)| color)
+            .collect()
+    }
+
+    #[test]
+    fn auto_text_is_white_over_black() {
+        let colors = auto_text_colors(Image::default());
+        assert!(!colors.is_empty());
+        assert!(colors.iter().all(|&c| c == Color::WHITE));
+    }
+
+    #[test]
+    fn auto_text_is_black_over_white() {
+        let colors = auto_text_colors(Image::new_solid(Color::WHITE));
+        assert!(!colors.is_empty());
+        assert!(colors.iter().all(|&c| c == Color::BLACK));
+    }
+
+    #[test]
+    fn auto_text_over_half_and_half_uses_both() {
+        // Top half white, bottom half black: "I" spans rows 1 to 7
+        let mut background = Image::default();
+        for (row, _, pixel) in background.pixels_mut() {
+            if row <= 4 {
+                *pixel = Color::WHITE;
+            }
+        }
+        let mut image = background.clone();
+        image.draw_text_auto("I", 1, 0);
+        let colors = auto_text_colors(background.clone());
+        assert!(colors.contains(&Color::WHITE) && colors.contains(&Color::BLACK));
+        for row in 1..=8 {
+            for col in 1..=8 {
+                // Every lit pixel differs from its background
+                let pixel = image[(row, col)];
+                if pixel != background[(row, col)] {
+                    assert_eq!(pixel, if row <= 4 { Color::BLACK } else { Color::WHITE });
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn auto_text_is_clipped() {
+        let mut image = Image::default();
+        image.draw_text_auto("III", -200, 0);
+        image.draw_text_auto("I", 0, 100);
+        assert_eq!(image, Image::default());
+        image.draw_text_auto("HELLO", 3, 2);
+        assert_ne!(image, Image::default());
+    }
+
+    #[test]
+    fn smooth_scroller_glides_between_columns() {
+        let mut steps = TextScroller::new("AB", Color::WHITE);
+        let mut smooth = TextScroller::new("AB", Color::WHITE);
+        smooth.set_smooth(true);
+        let first = steps.next_frame();
+        let second = steps.next_frame();
+        assert_eq!(smooth.next_frame(), first);
+        for _ in 1..SMOOTH_SCROLL_STEPS {
+            let frame = smooth.next_frame();
+            assert_ne!(frame, first);
+            assert_ne!(frame, second);
+        }
+        assert_eq!(smooth.next_frame(), second);
+    }
+
+    #[test]
+    fn hex_colors_round_trip() {
+        for (color, hex, text) in [
+            (Color::RED, 0xff0000, "#ff0000"),
+            (Color::GREEN, 0x00ff00, "00FF00"),
+            (Color::BLUE, 0x0000ff, "#0000Ff"),
+            (Color::ORANGE, 0xff8000, "ff8000"),
+        ] {
+            assert_eq!(Color::from_hex(hex), color);
+            assert_eq!(Color::try_from_str(text), Ok(color));
+        }
+        // The upper byte is ignored
+        assert_eq!(Color::from_hex(0x12ff0000), Color::RED);
+    }
+
+    #[test]
+    fn hex_parse_errors() {
+        for text in ["", "#", "#fff", "ff00000", "#ff00000"] {
+            assert_eq!(
+                Color::try_from_str(text),
+                Err(ColorParseError::InvalidLength)
+            );
+        }
+        for text in ["#gg0000", "ff00 0", "+f0000", "#00000z"] {
+            assert_eq!(
+                Color::try_from_str(text),
+                Err(ColorParseError::InvalidDigit)
+            );
+        }
+    }
+
+    #[test]
+    fn inverting_twice_gives_the_original() {
+        let color = Color {
+            r: 0,
+            g: 100,
+            b: 255,
+        };
+        assert_eq!(
+            color.invert(),
+            Color {
+                r: 255,
+                g: 155,
+                b: 0
+            }
+        );
+        assert_eq!(color.invert().invert(), color);
+
+        let image = test_image();
+        assert_eq!(image.invert().invert(), image);
+        let mut inverted = image.clone();
+        inverted.invert_in_place();
+        assert_eq!(inverted, image.invert());
+        inverted.invert_in_place();
+        assert_eq!(inverted, image);
+    }
+
+    #[test]
+    fn columns_read_back_through_index() {
+        let mut image = test_image();
+        let column = image.column(3);
+        for (row, &pixel) in (1..=8).zip(column.iter()) {
+            assert_eq!(pixel, image[(row, 3)]);
+        }
+
+        let pixels = [Color::BLUE; 8];
+        image.set_column(8, &pixels);
+        assert_eq!(image.column(8), pixels);
+        for row in 1..=8 {
+            assert_eq!(image[(row, 8)], Color::BLUE);
+            assert_eq!(image[(row, 7)], test_image()[(row, 7)]);
+        }
+    }
+
+    #[test]
+    fn get_checks_the_bounds() {
+        let mut image = test_image();
+        assert_eq!(image.get(0, 1), None);
+        assert_eq!(image.get(1, 0), None);
+        assert_eq!(image.get(9, 1), None);
+        assert_eq!(image.get(1, 9), None);
+        assert_eq!(image.get(8, 8), Some(&test_image()[(8, 8)]));
+        assert_eq!(image.get(1, 1), Some(&test_image()[(1, 1)]));
+
+        assert!(image.get_mut(9, 1).is_none());
+        *image.get_mut(8, 8).unwrap() = Color::RED;
+        assert_eq!(image[(8, 8)], Color::RED);
+    }
+
+    #[test]
+    fn iteration_follows_the_rows() {
+        let mut image = test_image();
+        let mut pixels = image.iter();
+        for row in 1..=8 {
+            for pixel in image.row(row) {
+                assert_eq!(pixels.next(), Some(pixel));
+            }
+        }
+        assert_eq!(pixels.next(), None);
+        drop(pixels);
+
+        for (i, pixel) in image.iter_mut().enumerate() {
+            pixel.b = i as u8;
+        }
+        assert_eq!(image[(1, 1)].b, 0);
+        assert_eq!(image[(1, 8)].b, 7);
+        assert_eq!(image[(2, 1)].b, 8);
+        assert_eq!(image[(8, 8)].b, 63);
+    }
+
+    #[test]
+    fn identity_map_keeps_the_image() {
+        let image = test_image();
+        assert_eq!(image.map(|pixel| pixel), image);
+        let red = image.map(|pixel| Color {
+            g: 0,
+            b: 0,
+            ..pixel
+        });
+        assert_eq!(red[(4, 6)], Color { r: 120, g: 0, b: 0 });
+    }
+
+    #[test]
+    fn four_rotations_give_the_original() {
+        let image = test_image();
+        let turned = image.rotated_90().rotated_90().rotated_90().rotated_90();
+        assert_eq!(turned, image);
+        assert_eq!(image.rotated_90().rotated_90(), image.rotated_180());
+        assert_eq!(image.rotated_90().rotated_270(), image);
+
+        // A quarter clockwise moves the top left pixel to the top right
+        let mut corner = Image::default();
+        corner[(1, 1)] = Color::RED;
+        assert_eq!(corner.rotated_90()[(1, 8)], Color::RED);
+        assert_eq!(corner.rotated_180()[(8, 8)], Color::RED);
+        assert_eq!(corner.rotated_270()[(8, 1)], Color::RED);
+    }
+
+    #[test]
+    fn flips_mirror_pixels() {
+        let image = test_image();
+        assert_eq!(image.flipped_horizontal().flipped_horizontal(), image);
+        assert_eq!(image.flipped_vertical().flipped_vertical(), image);
+
+        let mut pixel = Image::default();
+        pixel[(2, 3)] = Color::GREEN;
+        let horizontal = pixel.flipped_horizontal();
+        assert_eq!(horizontal[(2, 6)], Color::GREEN);
+        assert_eq!(horizontal.count_diff(&Image::default()), 1);
+        let vertical = pixel.flipped_vertical();
+        assert_eq!(vertical[(7, 3)], Color::GREEN);
+        assert_eq!(vertical.count_diff(&Image::default()), 1);
+    }
+
+    #[test]
+    fn scroll_wraps_around() {
+        let gradient = Image::gradient(Color::WHITE);
+        let mut image = gradient.clone();
+        image.scroll(8, 8);
+        assert_eq!(image, gradient);
+
+        let mut image = test_image();
+        image.scroll(1, 2);
+        assert_eq!(image[(3, 2)], test_image()[(1, 1)]);
+        assert_eq!(image[(1, 1)], test_image()[(7, 8)]);
+        // Negative and large offsets are taken modulo 8
+        image.scroll(-1, -2);
+        assert_eq!(image, test_image());
+        image.scroll(9, -6);
+        let mut expected = test_image();
+        expected.scroll(1, 2);
+        assert_eq!(image, expected);
+    }
+
+    #[test]
+    fn shift_fills_the_uncovered_pixels() {
+        let mut image = test_image();
+        image.shift(2, -1, Color::BLUE);
+        assert_eq!(image[(1, 3)], test_image()[(2, 1)]);
+        assert_eq!(image[(7, 8)], test_image()[(8, 6)]);
+        for i in 1..=8 {
+            assert_eq!(image[(i, 1)], Color::BLUE);
+            assert_eq!(image[(i, 2)], Color::BLUE);
+            assert_eq!(image[(8, i)], Color::BLUE);
+        }
+    }
+
+    #[test]
+    fn overlay_clips_at_every_edge() {
+        let src = Image::new_solid(Color::RED);
+        for (dx, dy, inside) in [
+            (-7, 0, (1, 1)),
+            (7, 0, (1, 8)),
+            (0, -7, (1, 1)),
+            (0, 7, (8, 1)),
+        ] {
+            let mut image = Image::default();
+            image.overlay_at(&src, dx, dy, None);
+            assert_eq!(image[inside], Color::RED);
+            assert_eq!(image.count_diff(&Image::default()), 8);
+        }
+        let mut image = test_image();
+        image.overlay_at(&src, 8, 0, None);
+        assert_eq!(image, test_image());
+    }
+
+    #[test]
+    fn overlay_skips_the_transparent_color() {
+        let mut src = Image::default();
+        src[(2, 2)] = Color::GREEN;
+        let mut image = test_image();
+        image.overlay(&src, Some(Color::BLACK));
+        assert_eq!(image[(2, 2)], Color::GREEN);
+        assert_eq!(image.count_diff(&test_image()), 1);
+        // Without transparency the whole source is copied
+        image.overlay(&src, None);
+        assert_eq!(image, src);
+    }
+
+    #[test]
+    fn bytes_round_trip() {
+        let image = test_image();
+        let bytes: &[u8] = image.as_ref();
+        assert_eq!(Image::try_from(bytes), Ok(image.clone()));
+        let array: [u8; 192] = bytes.try_into().unwrap();
+        assert_eq!(Image::from_bytes(&array), image);
+    }
+
+    #[test]
+    fn bytes_of_the_wrong_length_are_rejected() {
+        let bytes = [0u8; 193];
+        assert_eq!(
+            Image::try_from(&bytes[..191]),
+            Err(ImageBytesError::InvalidLength(191))
+        );
+        assert_eq!(
+            Image::try_from(&bytes[..]),
+            Err(ImageBytesError::InvalidLength(193))
+        );
+        assert_eq!(
+            Image::try_from(&[][..]),
+            Err(ImageBytesError::InvalidLength(0))
+        );
+    }
+
+    #[test]
+    fn bytes_are_rgb_row_after_row() {
+        let mut image = Image::default();
+        image[(1, 2)] = Color { r: 1, g: 2, b: 3 };
+        image[(2, 1)] = Color { r: 4, g: 5, b: 6 };
+        let bytes = image.as_bytes();
+        assert_eq!(bytes.len(), 192);
+        assert_eq!(&bytes[3..6], &[1, 2, 3]);
+        assert_eq!(&bytes[24..27], &[4, 5, 6]);
+        assert_eq!(bytes.iter().filter(|&&b| b != 0).count(), 6);
+
+        image.as_mut()[191] = 9;
+        assert_eq!(image[(8, 8)].b, 9);
+    }
+
+    #[test]
+    fn diff_lists_the_changed_pixels() {
+        let image = test_image();
+        assert_eq!(image.diff(&image).next(), None);
+        assert_eq!(image.count_diff(&image), 0);
+
+        let mut changed = image.clone();
+        changed[(3, 7)] = Color::BLACK;
+        assert!(image.diff(&changed).eq([(3, 7)]));
+        changed[(8, 1)] = Color::BLACK;
+        assert!(image.diff(&changed).eq([(3, 7), (8, 1)]));
+    }
+
+    #[test]
+    fn fade_reaches_the_target_in_time() {
+        for step in [1, 7, 100, 255] {
+            let target = Image::new_solid(Color::WHITE);
+            let mut image = Image::default();
+            let frames = 255usize.div_ceil(step as usize);
+            for _ in 1..frames {
+                image.fade_towards(&target, step);
+                assert!(!image.is_equal(&target));
+            }
+            image.fade_towards(&target, step);
+            assert!(image.is_equal(&target));
+            // Fading back down takes as long
+            for _ in 0..frames {
+                image.fade_towards(&Image::default(), step);
+            }
+            assert_eq!(image, Image::default());
+        }
+        let mut image = test_image();
+        image.fade_towards(&test_image(), 10);
+        assert_eq!(image, test_image());
+    }
+
+    #[test]
+    fn test_patterns() {
+        let board = Image::checkerboard(Color::RED, Color::BLUE);
+        assert_eq!(board[(1, 1)], Color::RED);
+        assert_eq!(board[(1, 2)], Color::BLUE);
+        assert_eq!(board[(2, 1)], Color::BLUE);
+        assert_eq!(board[(8, 8)], Color::RED);
+        assert_eq!(
+            board.iter().filter(|&&pixel| pixel == Color::RED).count(),
+            32
+        );
+
+        let border = Image::border(Color::WHITE);
+        assert_eq!(border.count_diff(&Image::default()), 28);
+        assert_eq!(border[(1, 5)], Color::WHITE);
+        assert_eq!(border[(5, 8)], Color::WHITE);
+        assert_eq!(border[(2, 2)], Color::BLACK);
+
+        let corners = Image::corners(Color::GREEN);
+        assert!(corners
+            .diff(&Image::default())
+            .eq([(1, 1), (1, 8), (8, 1), (8, 8)]));
+    }
+
+    /// Returns the lit pixels of an image as text, '#' for lit and '.' for black,
+    /// one row per line
+    fn lit_rows(image: &Image) -> [[u8; 8]; 8] {
+        let mut rows = [[b'.'; 8]; 8];
+        for (text, pixels) in rows.iter_mut().zip(image.rows()) {
+            for (c, &pixel) in text.iter_mut().zip(pixels) {
+                if pixel != Color::BLACK {
+                    *c = b'#';
+                }
+            }
+        }
+        rows
+    }
+
+    #[test]
+    fn char_a_is_rasterized() {
+        let mut image = Image::default();
+        image.draw_char('A', 1, 0, Color::WHITE);
+        assert_eq!(
+            lit_rows(&image),
+            [
+                *b"..###...",
+                *b".#...#..",
+                *b".#...#..",
+                *b".#...#..",
+                *b".#####..",
+                *b".#...#..",
+                *b".#...#..",
+                *b"........",
+            ]
+        );
+
+        // Clipped on the left edge and at the bottom
+        let mut clipped = Image::default();
+        clipped.draw_char('A', -2, 3, Color::WHITE);
+        assert_eq!(lit_rows(&clipped)[3], *b"##......");
+        assert_eq!(lit_rows(&clipped)[7], *b"###.....");
+    }
+
+    #[test]
+    fn scroller_loops_over_the_text() {
+        let mut scroller = TextScroller::new("AB", Color::WHITE);
+        assert_eq!(scroller.width(), 12);
+
+        let first = scroller.next_frame();
+        let mut expected = Image::default();
+        expected.draw_char('A', 0, 0, Color::WHITE);
+        expected.draw_char('B', 6, 0, Color::WHITE);
+        assert_eq!(first, expected);
+
+        let second = scroller.next_frame();
+        let mut shifted = first.clone();
+        shifted.shift(-1, 0, Color::BLACK);
+        for col in 1..=7 {
+            assert_eq!(second.column(col), shifted.column(col));
+        }
+        for _ in 2..12 {
+            scroller.next_frame();
+        }
+        assert_eq!(scroller.next_frame(), first);
+        assert_eq!(scroller.next_frame(), second);
+    }
+
+    #[test]
+    fn rects_are_clipped() {
+        let mut full = Image::default();
+        full.fill_rect(0, 0, 8, 8, Color::RED);
+        assert_eq!(full, Image::new_solid(Color::RED));
+        let mut outline = Image::default();
+        outline.draw_rect(0, 0, 8, 8, Color::WHITE);
+        assert_eq!(outline, Image::border(Color::WHITE));
+
+        let mut dot = Image::default();
+        dot.draw_rect(3, 4, 1, 1, Color::GREEN);
+        assert!(dot.diff(&Image::default()).eq([(5, 4)]));
+        let mut filled_dot = Image::default();
+        filled_dot.fill_rect(3, 4, 1, 1, Color::GREEN);
+        assert_eq!(filled_dot, dot);
+
+        let mut off_right = Image::default();
+        off_right.fill_rect(8, 0, 4, 4, Color::BLUE);
+        off_right.draw_rect(8, 0, 4, 4, Color::BLUE);
+        assert_eq!(off_right, Image::default());
+        let mut empty = Image::default();
+        empty.draw_rect(2, 2, 0, 3, Color::BLUE);
+        assert_eq!(empty, Image::default());
+    }
+
+    #[test]
+    fn circle_of_radius_3() {
+        let mut image = Image::default();
+        image.draw_circle(3, 3, 3, Color::WHITE);
+        assert_eq!(
+            lit_rows(&image),
+            [
+                *b"..###...",
+                *b".#...#..",
+                *b"#.....#.",
+                *b"#.....#.",
+                *b"#.....#.",
+                *b".#...#..",
+                *b"..###...",
+                *b"........",
+            ]
+        );
+
+        let mut disk = Image::default();
+        disk.fill_circle(3, 3, 3, Color::WHITE);
+        assert_eq!(
+            lit_rows(&disk),
+            [
+                *b"..###...",
+                *b".#####..",
+                *b"#######.",
+                *b"#######.",
+                *b"#######.",
+                *b".#####..",
+                *b"..###...",
+                *b"........",
+            ]
+        );
+    }
+
+    /// Returns the index of the first 7 frames played by a sequence of solid frames
+    /// whose red channel is their index
+    fn played<const N: usize>(sequence: &mut FrameSequence<N>) -> [u8; 7] {
+        let mut order = [0; 7];
+        for index in order.iter_mut() {
+            *index = sequence.current()[(1, 1)].r;
+            sequence.advance();
+        }
+        order
+    }
+
+    #[test]
+    fn frame_sequence_playback_order() {
+        let frames = [0, 1, 2].map(|r| Image::new_solid(Color { r, g: 0, b: 0 }));
+        let mut ping_pong = FrameSequence::new(frames.clone(), [1, 2, 3], PlaybackMode::PingPong);
+        assert_eq!(played(&mut ping_pong), [0, 1, 2, 1, 0, 1, 2]);
+        let mut looping = FrameSequence::new(frames, [1, 2, 3], PlaybackMode::Loop);
+        assert_eq!(played(&mut looping), [0, 1, 2, 0, 1, 2, 0]);
+        assert_eq!(looping.duration(), 2);
+
+        ping_pong.reset();
+        assert_eq!(ping_pong.current()[(1, 1)].r, 0);
+        assert_eq!(played(&mut ping_pong), [0, 1, 2, 1, 0, 1, 2]);
+    }
+
+    /// Every orientation, in the order of their protocol payload
+    const ORIENTATIONS: [Orientation; 8] = [
+        Orientation::Identity,
+        Orientation::Rotate90,
+        Orientation::Rotate180,
+        Orientation::Rotate270,
+        Orientation::FlipHorizontal,
+        Orientation::FlipVertical,
+        Orientation::Transpose,
+        Orientation::AntiTranspose,
+    ];
+
+    #[test]
+    fn transposing_twice_gives_the_original() {
+        let image = test_image();
+        assert_eq!(image.transposed()[(2, 7)], image[(7, 2)]);
+        assert_eq!(image.transposed().transposed(), image);
+        let mut in_place = image.clone();
+        in_place.transpose();
+        assert_eq!(in_place, image.transposed());
+    }
+
+    #[test]
+    fn orient_matches_source() {
+        let image = test_image();
+        assert_eq!(image.orient(Orientation::Identity), image);
+        assert_eq!(image.orient(Orientation::Rotate90), image.rotated_90());
+        assert_eq!(image.orient(Orientation::Rotate270), image.rotated_270());
+        assert_eq!(image.orient(Orientation::Transpose), image.transposed());
+        for o in ORIENTATIONS {
+            let oriented = image.orient(o);
+            for row in 1..=8 {
+                for col in 1..=8 {
+                    assert_eq!(oriented[(row, col)], image[o.source(row, col)], "{:?}", o);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn wide_images_have_their_own_size() {
+        let mut wide = GenericImage::<16, 8>::new_solid(Color::RED);
+        assert_eq!(wide.row(1).len(), 16);
+        assert_eq!(wide.rows().len(), 8);
+        assert_eq!(wide.column(16).len(), 8);
+        assert_eq!(wide.as_bytes().len(), 16 * 8 * 3);
+        assert_eq!(GenericImage::<16, 8>::pixel_index(2, 1), 16);
+
+        wide[(2, 16)] = Color::GREEN;
+        assert_eq!(wide.get(2, 16), Some(&Color::GREEN));
+        assert_eq!(wide.get(2, 17), None);
+        assert_eq!(wide.get(9, 1), None);
+        assert_eq!(&wide.as_bytes()[3 * 31..3 * 32], &[0, 255, 0]);
+    }
+
+    #[test]
+    fn blue_gradient_values() {
+        // Blue of (row, col) is 255 / (1 + row * row + col), rounded
+        let gradient = Image::gradient(Color::BLUE);
+        assert!(gradient
+            .row(1)
+            .iter()
+            .map(|pixel| pixel.b)
+            .eq([85, 64, 51, 43, 36, 32, 28, 26]));
+        assert_eq!(gradient[(2, 1)].b, 43);
+        assert_eq!(gradient[(8, 1)].b, 4);
+        assert_eq!(gradient[(8, 8)].b, 3);
+        assert!(gradient.iter().all(|pixel| pixel.r == 0 && pixel.g == 0));
+
+        // Dim channels stay lit
+        let dim = Image::gradient(Color { r: 0, g: 0, b: 1 });
+        assert_eq!(dim, Image::new_solid(Color { r: 0, g: 0, b: 1 }));
+    }
+
+    #[test]
+    fn random_images_depend_only_on_the_seed() {
+        assert_eq!(Image::random(7), Image::random(7));
+        assert_ne!(Image::random(7), Image::random(8));
+        assert!(Image::random(7).count_diff(&Image::default()) > 60);
+    }
+
+    #[test]
+    fn plasma_moves_with_time() {
+        assert_eq!(Image::plasma(3), Image::plasma(3));
+        for t in 0..8 {
+            assert!(Image::plasma(t).count_diff(&Image::plasma(t + 1)) > 0);
+        }
+        // Every wave goes through the 64 entries of the sine table
+        assert_eq!(Image::plasma(5), Image::plasma(5 + 64));
+    }
+
+    /// Builds a board with the given cells alive
+    fn board(cells: &[(usize, usize)]) -> Image {
+        let mut image = Image::default();
+        for &cell in cells {
+            image[cell] = Color::WHITE;
+        }
+        image
+    }
+
+    #[test]
+    fn blinker_oscillates() {
+        let horizontal = board(&[(4, 3), (4, 4), (4, 5)]);
+        let vertical = board(&[(3, 4), (4, 4), (5, 4)]);
+        let next = horizontal.life_step(Color::WHITE, Color::BLACK);
+        assert_eq!(next, vertical);
+        assert_eq!(next.life_step(Color::WHITE, Color::BLACK), horizontal);
+        let mut in_place = horizontal.clone();
+        in_place.life_step_in_place(Color::WHITE, Color::BLACK);
+        assert_eq!(in_place, vertical);
+    }
+
+    #[test]
+    fn glider_moves_diagonally_and_wraps() {
+        let mut glider = board(&[(1, 2), (2, 3), (3, 1), (3, 2), (3, 3)]);
+        let start = glider.clone();
+        for generation in 1..=32 {
+            glider.life_step_in_place(Color::WHITE, Color::BLACK);
+            if generation % 4 == 0 {
+                let mut expected = start.clone();
+                let moves = (generation / 4) as i8;
+                expected.scroll(moves, moves);
+                assert_eq!(glider, expected, "generation {}", generation);
+            }
+        }
+        // After 32 generations it went around the panel back to its start
+        assert_eq!(glider, start);
+    }
+
+    #[test]
+    fn image_statistics() {
+        let color = Color {
+            r: 10,
+            g: 200,
+            b: 33,
+        };
+        let solid = Image::new_solid(color);
+        assert_eq!(solid.average_color(), color);
+        assert_eq!(solid.max_channel(), 200);
+
+        let board = Image::checkerboard(Color::BLACK, Color::WHITE);
+        let average = board.average_color();
+        for channel in [average.r, average.g, average.b] {
+            assert!((127..=128).contains(&channel));
+        }
+        assert_eq!(board.histogram(), [32, 0, 0, 0, 0, 0, 0, 32]);
+        assert_eq!(Image::default().max_channel(), 0);
+        assert_eq!(Image::default().histogram(), [64, 0, 0, 0, 0, 0, 0, 0]);
     }
 }
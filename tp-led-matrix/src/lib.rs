@@ -1,9 +1,30 @@
 //! Library module which makes available modules for whole project
 
-#![no_std] //do not use standard library in an embedded context
+#![cfg_attr(not(feature = "std"), no_std)] //do not use standard library in an embedded context, host tools enable the std feature
 
+#[macro_use]
+mod log; //declared first so that its macros are visible in the other modules
 
+pub mod animation;
+pub mod capture;
+pub mod config;
+pub mod flow;
+pub mod font;
 pub mod gamma;
-pub use image::{Color,Image};
+pub use image::{
+    ChannelOrder, Color, Color12, GenericImage, GradientDirection, Image, Orientation, RowCursor,
+    ScanMode,
+};
 pub mod image;
 pub mod matrix;
+pub mod palette;
+pub mod pins;
+pub mod postprocess;
+pub mod protocol;
+pub mod rng;
+pub mod scan;
+pub mod settings;
+#[cfg(feature = "sim")]
+pub mod simulator;
+pub mod slots;
+pub mod sprite;
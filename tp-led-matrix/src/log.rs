@@ -0,0 +1,32 @@
+//! This module maps library logging onto defmt when the `defmt` feature is enabled
+//!
+//! Without the feature the macros expand to code which is never executed, so
+//! their arguments are still type checked but nothing is left in the binary.
+
+#[cfg(feature = "defmt")]
+macro_rules! trace {
+    ($($arg:tt)*) => { defmt::trace!($($arg)*) };
+}
+
+#[cfg(feature = "defmt")]
+macro_rules! warn {
+    ($($arg:tt)*) => { defmt::warn!($($arg)*) };
+}
+
+#[cfg(not(feature = "defmt"))]
+macro_rules! trace {
+    ($fmt:literal $(, $arg:expr)* $(,)?) => {
+        if false {
+            $( let _ = &$arg; )*
+        }
+    };
+}
+
+#[cfg(not(feature = "defmt"))]
+macro_rules! warn {
+    ($fmt:literal $(, $arg:expr)* $(,)?) => {
+        if false {
+            $( let _ = &$arg; )*
+        }
+    };
+}
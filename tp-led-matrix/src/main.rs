@@ -9,11 +9,30 @@ use dwt_systick_monotonic::DwtSystick;
 use dwt_systick_monotonic::ExtU32;
 use panic_probe as _;
 use stm32l4xx_hal::pac::USART1;
-use stm32l4xx_hal::serial::{Config, Event, Rx, Serial};
+use stm32l4xx_hal::serial::{Config, Event, Rx, Serial, Tx};
 use stm32l4xx_hal::{pac, prelude::*};
-use tp_led_matrix::{matrix::Matrix, Color, Image};
+use tp_led_matrix::capture::CaptureSender;
+use tp_led_matrix::config::{DisplayConfig, MatrixConfig, StartupImage};
+use tp_led_matrix::flow::FlowControl;
+use tp_led_matrix::matrix::{Matrix, MatrixUnreset};
+use tp_led_matrix::postprocess::{PostProcessors, PowerCap, PowerStatus, ZoneGains};
+use tp_led_matrix::protocol::{Decoded, FrameDecoder, ProtocolCommand};
+use tp_led_matrix::scan::{output_line, scan_line, BCM_PLANES};
+use tp_led_matrix::settings::DisplaySettings;
+use tp_led_matrix::slots::ImageSlots;
+use tp_led_matrix::{Color, Image, RowCursor};
 
 use heapless::pool::{Box, Node, Pool};
+use heapless::spsc::{Consumer, Producer, Queue};
+
+/// Power cap of the panel: half of the current drawn by a full white frame
+const MAX_TOTAL_BRIGHTNESS: u32 = 64 * 3 * 255 / 2;
+
+/// Frames scaled down by the power cap, reported by the status command
+static POWER_STATUS: PowerStatus = PowerStatus::new();
+
+/// Capacity of the queue of received bytes waiting to be decoded
+const RX_QUEUE_LEN: usize = 256;
 
 #[rtic::app(device = stm32l4xx_hal::pac, dispatchers = [USART2,USART3])]
 mod app {
@@ -24,17 +43,34 @@ mod app {
     struct Shared {
         next_image: Option<Box<Image>>,
         pool: Pool<Image>,
+        usart1_tx: Tx<USART1>,
+        flow: FlowControl,
+        post_processors: PostProcessors<4>,
+        settings: DisplaySettings,
+        capture: CaptureSender,
+        capture_request: Option<bool>,
+        // Only used by tasks of priority 2, so no lock is needed
+        #[lock_free]
+        matrix: Option<Matrix>,
     }
 
     #[local]
     struct Local {
-        matrix: Matrix,
+        matrix_unreset: Option<MatrixUnreset>,
         usart1_rx: Rx<USART1>,
+        rx_producer: Producer<'static, u8, RX_QUEUE_LEN>,
+        rx_consumer: Consumer<'static, u8, RX_QUEUE_LEN>,
         current_image: Box<Image>,
         rx_image: Box<Image>,
+        config: DisplayConfig,
+        slots: ImageSlots,
     }
 
-    #[init]
+    #[init(local = [
+        rx_queue: Queue<u8, RX_QUEUE_LEN> = Queue::new(),
+        zone_gains: ZoneGains = ZoneGains,
+        power_cap: PowerCap = PowerCap::new(&POWER_STATUS),
+    ])]
     /// Init ports and clocks and local shared structures
     fn init(cx: init::Context) -> (Shared, Local, init::Monotonics) {
         defmt::info!("defmt correctly initialized");
@@ -80,10 +116,15 @@ mod app {
 
         port_serie.listen(Event::Rxne); //triggers an interrpution when a character is received
 
-        let (_, usart1_rx) = port_serie.split(); //get received character
+        let (usart1_tx, usart1_rx) = port_serie.split(); //get received character and transmission half for flow control
+
+        // Received bytes are queued by the interrupt and decoded by a lower priority task,
+        // XOFF is sent when the queue is 3/4 full and XON once it is down to 1/4
+        let (rx_producer, rx_consumer) = cx.local.rx_queue.split();
+        let flow = FlowControl::new(RX_QUEUE_LEN / 4, 3 * RX_QUEUE_LEN / 4);
 
-        // Init matrix object
-        let matrix = Matrix::new(
+        // Init matrix object, its reset is released 100ms later by finish_matrix
+        let matrix_unreset = Matrix::new_unreset(
             gpioa.pa2,
             gpioa.pa3,
             gpioa.pa4,
@@ -106,11 +147,11 @@ mod app {
             clocks,
         );
 
-        let mut mono = DwtSystick::new(&mut cp.DCB, cp.DWT, cp.SYST, 80_000_000);
+        let mono = DwtSystick::new(&mut cp.DCB, cp.DWT, cp.SYST, 80_000_000);
         //let image = Image::default();
         //let image2 = Image::default();
 
-        display::spawn(mono.now()).unwrap();
+        finish_matrix::spawn_after(100.millis()).unwrap();
 
         //rotate_image::spawn(0).unwrap();
 
@@ -120,33 +161,101 @@ mod app {
             static mut MEMORY: MaybeUninit<[Node<Image>; 3]> = MaybeUninit::uninit();
             pool.grow_exact(&mut MEMORY); // static mut access is unsafe
         }
-        let current_image = pool.alloc().unwrap().init(Image::default());
+        // Choose what the panel shows before the first frame is received: a
+        // checkerboard makes swapped or mirrored rows and columns obvious
+        let config = DisplayConfig {
+            startup: StartupImage::Custom(|| Image::checkerboard(Color::WHITE, Color::BLACK)),
+        };
+        let mut current_image = pool.alloc().unwrap().init(config.startup_image());
         let rx_image = pool.alloc().unwrap().init(Image::default());
         let next_image = None;
 
+        // Settings can be changed at runtime by the host through setting commands
+        // Setting bcm to true displays rows with binary code modulation instead of the
+        // DM163 PWM
+        let settings = DisplaySettings {
+            max_total_brightness: Some(MAX_TOTAL_BRIGHTNESS),
+            bcm: false,
+            ..DisplaySettings::default()
+        };
+
+        // Register the built-in frame post processors, applied in this order
+        let mut post_processors = PostProcessors::new();
+        assert!(
+            post_processors.register(cx.local.zone_gains).is_ok(),
+            "too many post processors"
+        );
+        assert!(
+            post_processors.register(cx.local.power_cap).is_ok(),
+            "too many post processors"
+        );
+        post_processors.process(&mut current_image, &settings);
+
         (
-            Shared { next_image, pool },
+            Shared {
+                next_image,
+                pool,
+                usart1_tx,
+                flow,
+                post_processors,
+                settings,
+                capture: CaptureSender::new(),
+                capture_request: None,
+                matrix: None,
+            },
             Local {
-                matrix,
+                matrix_unreset: Some(matrix_unreset),
                 usart1_rx,
+                rx_producer,
+                rx_consumer,
                 current_image,
                 rx_image,
+                config,
+                slots: ImageSlots::new(),
             },
             init::Monotonics(mono),
         )
     }
 
-    #[task(local = [matrix, current_image, next_line: usize = 1],shared = [next_image,pool], priority = 2)] //start to 1 because row() is implemented for strict positive numbers in image.rs
-    /// Displays image with matrix row by row
-    fn display(mut cx: display::Context, at: Instant) {
-        // Display line next_line (cx.local.next_line) of
-        // the image (cx.local.image) on the matrix (cx.local.matrix).
-        // All those are mutable references.
-        /*cx.shared.image.lock(|image| {
-            cx.local.matrix.send_row(*cx.local.next_line, image.row(*cx.local.next_line)); //test with first line of gradient image
-        });*/
-
-        if *cx.local.next_line == 1 {
+    #[task(local = [matrix_unreset], shared = [matrix], priority = 2)]
+    /// Releases the reset of the matrix once it has been held for 100ms, then starts
+    /// displaying
+    fn finish_matrix(cx: finish_matrix::Context) {
+        if let Some(unreset) = cx.local.matrix_unreset.take() {
+            let mut matrix = unreset.finish();
+            // The panel of the course is multiplexed by rows, use ScanMode::Columns for
+            // panels whose shift registers are wired along the columns
+            matrix.set_config(MatrixConfig::default());
+            *cx.shared.matrix = Some(matrix);
+            display::spawn(monotonics::now(), false).unwrap();
+        }
+    }
+
+    #[task(local = [current_image, cursor: RowCursor = RowCursor::new(), frame_settings: DisplaySettings = DisplaySettings::DEFAULT, bcm_plane: u8 = 0, bcm_line: (usize, [Color; 8]) = (1, [Color::BLACK; 8])],shared = [matrix,next_image,pool,post_processors,settings,capture,capture_request], priority = 2, capacity = 2)]
+    /// Displays image with matrix row by row. When `off` is true, only switches off
+    /// the current row, before the end of its period if the duty is below 255. With
+    /// binary code modulation, the task runs once per bit plane of every row.
+    fn display(mut cx: display::Context, at: Instant, off: bool) {
+        // Display the next line of the image (cx.local.current_image) on the
+        // matrix (cx.shared.matrix), the cursor wraps around after line 8.
+        // finish_matrix only spawns this task once the matrix is ready
+        let Some(matrix) = cx.shared.matrix.as_mut() else {
+            return;
+        };
+        if off {
+            matrix.deactivate_current_row();
+            return;
+        }
+
+        // With binary code modulation, a frame only ends after the last plane of its last line
+        let bcm_plane = *cx.local.bcm_plane;
+        if bcm_plane == 0 && cx.local.cursor.at_frame_start() {
+            // Settings only change between two frames so a frame is never displayed
+            // with a mix of old and new settings
+            *cx.local.frame_settings = cx.shared.settings.lock(|settings| *settings);
+            let settings = *cx.local.frame_settings;
+            matrix.set_orientation(settings.orientation);
+            matrix.set_duty(settings.duty);
             cx.shared.next_image.lock(|next_image| {
                 if next_image.is_some() {
                     cx.shared.pool.lock(|pool| {
@@ -155,26 +264,72 @@ mod app {
                             pool.free(image);
                         }
                     });
+                    // Post processors, zone gains and power cap included, run once on every new frame
+                    cx.shared.post_processors.lock(|post_processors| {
+                        post_processors.process(cx.local.current_image, &settings);
+                    });
                 }
             });
+            // A capture is served at a frame boundary so the captured image is the one
+            // displayed during the whole frame
+            if let Some(processed) = cx.shared.capture_request.lock(|request| request.take()) {
+                let mut image = cx.local.current_image.clone();
+                if processed {
+                    for row in 1..=8 {
+                        for col in 1..=8 {
+                            image[(row, col)] = settings.output_color(image[(row, col)]);
+                        }
+                    }
+                }
+                if cx.shared.capture.lock(|capture| capture.start(&image)) {
+                    send_capture::spawn().ok();
+                }
+            }
         }
 
-        //Sends current_row to matrix to be displayed
-        cx.local.matrix.send_row(
-            *cx.local.next_line,
-            cx.local.current_image.row(*cx.local.next_line),
-        );
-
-        // Increment next_line up to 8 and wraparound to 1
-        if *cx.local.next_line < 8 {
-            *cx.local.next_line = *cx.local.next_line + 1;
-        } else {
-            *cx.local.next_line = 1;
+        //Sends current line (a row or a column depending on the wiring) to matrix to be
+        //displayed, with the current brightness
+        let settings = *cx.local.frame_settings;
+        let scan = matrix.config().scan;
+        //Displays rows evry period, faster when the duty dims the rows so they do not flicker
+        let period = 1.secs() / (8 * settings.effective_refresh_rate() as u32);
+
+        if settings.bcm {
+            // Binary code modulation: the 8 bit planes of a line are displayed one after
+            // the other, plane n during 2^n units of period / 255, so every channel is on
+            // for a time proportional to its value and the 8 planes fill the period
+            if bcm_plane == 0 {
+                let line = cx.local.cursor.next_line();
+                let pixels = output_line(
+                    cx.local.current_image,
+                    matrix.orientation(),
+                    scan,
+                    line,
+                    &settings,
+                );
+                *cx.local.bcm_line = (line, pixels);
+            }
+            let (line, pixels) = *cx.local.bcm_line;
+            if let Err(e) = matrix.send_row_bcm(line, &pixels, bcm_plane) {
+                defmt::warn!("display: {}", e);
+            }
+            *cx.local.bcm_plane = (bcm_plane + 1) % BCM_PLANES;
+            let time_to_disp = at + period * (1u32 << bcm_plane) / 255;
+            display::spawn_at(time_to_disp, time_to_disp, false).unwrap();
+            return;
         }
 
-        //Displays rows evry period
-        let time_to_disp = at + 1.secs() / (8 * 60);
-        display::spawn_at(time_to_disp, time_to_disp).unwrap();
+        let line = cx.local.cursor.next_line();
+        scan_line(matrix, cx.local.current_image, scan, line, &settings);
+
+        let duty = matrix.duty();
+        if duty < 255 {
+            // The row stays on for duty / 255 of its period
+            let time_to_off = at + period * duty as u32 / 255;
+            display::spawn_at(time_to_off, time_to_off, true).unwrap();
+        }
+        let time_to_disp = at + period;
+        display::spawn_at(time_to_disp, time_to_disp, false).unwrap();
     }
 
     #[idle()]
@@ -183,53 +338,151 @@ mod app {
         loop {}
     }
 
-    #[task(binds = USART1, local = [usart1_rx, rx_image, next_pos: usize = 0], shared = [next_image,pool])]
-    /// Manages the byte received and light up a R G B led depending on received byte value
-    fn receive_byte(cx: receive_byte::Context) {
-        let next_pos: &mut usize = cx.local.next_pos;
+    #[task(binds = USART1, local = [usart1_rx, rx_producer], shared = [flow], priority = 3)]
+    /// Queues the received byte for decoding, it runs above the display task so no byte is lost
+    fn receive_byte(mut cx: receive_byte::Context) {
         if let Ok(b) = cx.local.usart1_rx.read() {
-            // Handle the incoming byte according to the SE203 protocol
-            // and update next_image
-            // Do not forget that next_image.as_mut() might be handy here!
-
-            if b == 0xff {
-                // Return to position 0 case
-                *next_pos = 0;
-            } else if *next_pos < 3 * 64 {
-                let colonne = (*next_pos % 24) / 3;
-                let ligne = *next_pos / 24;
-
-                // Assigns R G B led for one pixel
-                match *next_pos % 3 {
-                    0 => cx.local.rx_image[(ligne + 1, colonne + 1)].r = b,
-                    1 => cx.local.rx_image[(ligne + 1, colonne + 1)].g = b,
-                    2 => cx.local.rx_image[(ligne + 1, colonne + 1)].b = b,
-                    _ => panic!("Indice RGB hors de 0 1 2"),
-                }
-
-                *next_pos += 1; //update next position
+            // The byte is dropped if the queue is full, which only happens if the host ignores XOFF
+            cx.local.rx_producer.enqueue(b).ok();
+            let backlog = cx.local.rx_producer.len();
+            // XON or XOFF is only recorded here and written by send_flow, so this
+            // interrupt never waits for the transmitter
+            if cx.shared.flow.lock(|flow| flow.update(backlog)) {
+                send_flow::spawn().ok();
+            }
+            decode_bytes::spawn().ok(); // a pending decode_bytes will also handle this byte
+        }
+    }
 
-                // If the received image is complete, make it available to
-                // the display task.
-                if *next_pos == 3 * 64 {
-                    // max position
-                    (cx.shared.next_image, cx.shared.pool).lock(|next_image, pool| {
-                        if let Some(image_nt_displayed) = next_image.take() {
-                            pool.free(image_nt_displayed);
-                        }
-                        let mut future_image =
-                            pool.alloc().unwrap().init(Image::gradient(Color::BLUE));
+    #[task(local = [rx_consumer, rx_image, config, slots, decoder: FrameDecoder = FrameDecoder::new(), store_next: Option<u8> = None], shared = [next_image, pool, flow, settings, capture_request], priority = 1)]
+    /// Decodes the queued bytes according to the SE203 protocol and publishes complete frames
+    fn decode_bytes(mut cx: decode_bytes::Context) {
+        while let Some(b) = cx.local.rx_consumer.dequeue() {
+            match cx.local.decoder.push(b, cx.local.rx_image) {
+                Some(Decoded::Frame) => match cx.local.store_next.take() {
+                    // A frame requested by a store command goes to its slot and is not displayed
+                    Some(slot) => {
+                        cx.local.slots.store(slot, cx.local.rx_image).ok();
+                    }
+                    // Otherwise the received image is complete, make it available to
+                    // the display task.
+                    None => {
+                        (&mut cx.shared.next_image, &mut cx.shared.pool).lock(
+                            |next_image, pool| {
+                                // Reuse the node of a frame which was never displayed as the
+                                // next reception buffer, and only allocate otherwise. Its content
+                                // does not matter since it will be entirely overwritten.
+                                let mut future_image = match next_image.take() {
+                                    Some(image_nt_displayed) => image_nt_displayed,
+                                    None => pool.alloc().unwrap().init(Image::default()),
+                                };
+
+                                core::mem::swap(&mut future_image, cx.local.rx_image);
+
+                                *next_image = Some(future_image);
+                            },
+                        );
+                    }
+                },
+                Some(Decoded::Command(ProtocolCommand::SetStartup(startup))) => {
+                    cx.local.config.startup = startup;
+                    let image = cx.local.config.startup_image();
+                    publish(&mut cx.shared.next_image, &mut cx.shared.pool, image);
+                }
+                Some(Decoded::Command(ProtocolCommand::StoreNextFrame(slot))) => {
+                    *cx.local.store_next = Some(slot);
+                }
+                Some(Decoded::Command(ProtocolCommand::ShowSlot(slot))) => {
+                    // Displaying an invalid or empty slot only bumps the slots error counter
+                    if let Ok(image) = cx.local.slots.get(slot) {
+                        publish(
+                            &mut cx.shared.next_image,
+                            &mut cx.shared.pool,
+                            image.clone(),
+                        );
+                    }
+                }
+                Some(Decoded::Command(ProtocolCommand::Solid(color))) => {
+                    publish(
+                        &mut cx.shared.next_image,
+                        &mut cx.shared.pool,
+                        Image::new_solid(color),
+                    );
+                }
+                Some(Decoded::Command(ProtocolCommand::Gradient(color))) => {
+                    publish(
+                        &mut cx.shared.next_image,
+                        &mut cx.shared.pool,
+                        Image::gradient(color),
+                    );
+                }
+                Some(Decoded::Command(ProtocolCommand::Capture(processed))) => {
+                    cx.shared
+                        .capture_request
+                        .lock(|request| *request = Some(processed));
+                }
+                Some(Decoded::Command(ProtocolCommand::Status)) => {
+                    defmt::info!(
+                        "status: {} frames limited by the power cap (last frame: {}), {} decoding errors",
+                        POWER_STATUS.limited_frames(),
+                        POWER_STATUS.last_frame_limited(),
+                        cx.local.decoder.errors()
+                    );
+                }
+                // Remaining commands change settings, invalid values are ignored
+                Some(Decoded::Command(cmd)) => {
+                    cx.shared
+                        .settings
+                        .lock(|settings| settings.apply_command(cmd))
+                        .ok();
+                }
+                Some(Decoded::Error(_)) | None => {}
+            }
+            let backlog = cx.local.rx_consumer.len();
+            if cx.shared.flow.lock(|flow| flow.update(backlog)) {
+                send_flow::spawn().ok();
+            }
+        }
+    }
 
-                        core::mem::swap(&mut future_image, cx.local.rx_image);
+    #[task(shared = [usart1_tx, flow], priority = 2)]
+    /// Sends the pending XON or XOFF, it runs above decode_bytes so XOFF is not delayed
+    /// while the backlog is drained, and tries again later if the transmitter is busy
+    fn send_flow(cx: send_flow::Context) {
+        let pending =
+            (cx.shared.usart1_tx, cx.shared.flow).lock(|tx, flow| flow.send_with(|b| tx.write(b)));
+        if pending {
+            send_flow::spawn_after(1.millis()).ok();
+        }
+    }
 
-                        *next_image = Some(future_image);
-                    });
+    #[task(shared = [usart1_tx, capture], priority = 1)]
+    /// Sends the captured frame as long as the transmitter accepts bytes, then tries
+    /// again later so the transmission never waits for the serial port
+    fn send_capture(cx: send_capture::Context) {
+        let pending = (cx.shared.usart1_tx, cx.shared.capture)
+            .lock(|tx, capture| capture.send_with(|b| tx.write(b)));
+        if pending {
+            send_capture::spawn_after(1.millis()).ok();
+        }
+    }
 
-                    // Next position reset
-                    *next_pos = 0;
+    /// Makes an image generated on the MCU available to the display task
+    fn publish(
+        next_image: &mut impl rtic::Mutex<T = Option<Box<Image>>>,
+        pool: &mut impl rtic::Mutex<T = Pool<Image>>,
+        image: Image,
+    ) {
+        (next_image, pool).lock(|next_image, pool| {
+            let node = match next_image.take() {
+                Some(mut image_nt_displayed) => {
+                    *image_nt_displayed = image;
+                    image_nt_displayed
                 }
-            }
-        }
+                None => pool.alloc().unwrap().init(image),
+            };
+            *next_image = Some(node);
+        });
     }
 
     /*
@@ -1,11 +1,47 @@
 //! This module builds matrix object and implements associated functions
 
-use crate::{Color, Image};
+use crate::config::{ns_to_cycles, MatrixConfig};
+pub use crate::pins::MAX_BRIGHTNESS;
+use crate::pins::{self, PanelPins};
+pub use crate::scan::MatrixError;
+use crate::scan::{bcm_plane, oriented_line, RowSink, BCM_PLANES};
+use crate::{Color, Image, Orientation, RowCursor, ScanMode};
+use cortex_m::peripheral::DWT;
 use stm32l4xx_hal::gpio::Speed::VeryHigh;
 use stm32l4xx_hal::gpio::*;
 use stm32l4xx_hal::prelude::_embedded_hal_blocking_delay_DelayMs;
 use stm32l4xx_hal::rcc::Clocks;
 
+/// Pins driving the panel, configured as push-pull outputs
+pub struct MatrixPins {
+    /// Bank selection of the DM163
+    pub sb: PC5<Output<PushPull>>,
+    /// Latch of the DM163, active low
+    pub lat: PC4<Output<PushPull>>,
+    /// Reset of the DM163, active low
+    pub rst: PC3<Output<PushPull>>,
+    /// Serial clock of the DM163
+    pub sck: PB1<Output<PushPull>>,
+    /// Serial data of the DM163
+    pub sda: PA4<Output<PushPull>>,
+    /// Output of row 1
+    pub c0: PB2<Output<PushPull>>,
+    /// Output of row 2
+    pub c1: PA15<Output<PushPull>>,
+    /// Output of row 3
+    pub c2: PA2<Output<PushPull>>,
+    /// Output of row 4
+    pub c3: PA7<Output<PushPull>>,
+    /// Output of row 5
+    pub c4: PA6<Output<PushPull>>,
+    /// Output of row 6
+    pub c5: PA5<Output<PushPull>>,
+    /// Output of row 7
+    pub c6: PB0<Output<PushPull>>,
+    /// Output of row 8
+    pub c7: PA3<Output<PushPull>>,
+}
+
 pub struct Matrix {
     sb: PC5<Output<PushPull>>,
     lat: PC4<Output<PushPull>>,
@@ -20,6 +56,32 @@ pub struct Matrix {
     c5: PA5<Output<PushPull>>,
     c6: PB0<Output<PushPull>>,
     c7: PA3<Output<PushPull>>,
+    config: MatrixConfig,
+    sysclk_hz: u32,
+    dead_cycles: u32, //interrow_dead_ns converted to cycles of sysclk
+    brightness: u8,   //current level applied to the dot correction in bank 0
+    dot_correction: [u8; 24],
+    duty: u8,        //fraction of the row period during which a row is on, out of 255
+    blanked_at: u32, //cycle count when the previous row was switched off
+    active_row: Option<usize>,
+    orientation: Orientation,
+}
+
+/// Matrix whose pins are configured but whose DM163 is still held in reset
+pub struct MatrixUnreset {
+    matrix: Matrix,
+}
+
+/// Implements functions for matrix unreset structure
+impl MatrixUnreset {
+    /// Releases RST and initializes bank 0. RST must have been low for 100ms since
+    /// [Matrix::new_unreset], which the application waits for as it likes, for
+    /// example by spawning a task with its monotonic timer.
+    pub fn finish(self) -> Matrix {
+        let mut matrix = self.matrix;
+        matrix.finish_wake();
+        matrix
+    }
 }
 
 /// Implements functions for matrix structure
@@ -30,6 +92,7 @@ impl Matrix {
     /// the bank 0 will be initialized by calling `init_bank0()` on the
     /// newly constructed structure.
     /// The pins will be set to very high speed mode.
+    /// This blocks for 100ms, see [new_unreset](Matrix::new_unreset) to avoid it.
     #[allow(clippy::too_many_arguments)] // Necessary to avoid a clippy warning
     /// Creates a new matrix
     pub fn new(
@@ -54,8 +117,64 @@ impl Matrix {
         gpioc_otyper: &mut OTYPER<'C'>,
         clocks: Clocks,
     ) -> Self {
+        let unreset = Matrix::new_unreset(
+            pa2,
+            pa3,
+            pa4,
+            pa5,
+            pa6,
+            pa7,
+            pa15,
+            pb0,
+            pb1,
+            pb2,
+            pc3,
+            pc4,
+            pc5,
+            gpioa_moder,
+            gpioa_otyper,
+            gpiob_moder,
+            gpiob_otyper,
+            gpioc_moder,
+            gpioc_otyper,
+            clocks,
+        );
+
+        let mut x = stm32l4xx_hal::delay::DelayCM::new(clocks);
+        x.delay_ms(100u8);
+
+        unreset.finish()
+    }
+
+    /// Configures the pins like [new](Matrix::new) does, SB and LAT high and the
+    /// other pins low, without waiting: the DM163 stays in reset until
+    /// [MatrixUnreset::finish] is called, at least 100ms later. `clocks` gives the
+    /// sysclk frequency used to time the dead time between rows.
+    #[allow(clippy::too_many_arguments)] // Necessary to avoid a clippy warning
+    pub fn new_unreset(
+        pa2: PA2<Analog>,
+        pa3: PA3<Analog>,
+        pa4: PA4<Analog>,
+        pa5: PA5<Analog>,
+        pa6: PA6<Analog>,
+        pa7: PA7<Analog>,
+        pa15: PA15<Alternate<PushPull, 0>>,
+        pb0: PB0<Analog>,
+        pb1: PB1<Analog>,
+        pb2: PB2<Analog>,
+        pc3: PC3<Analog>,
+        pc4: PC4<Analog>,
+        pc5: PC5<Analog>,
+        gpioa_moder: &mut MODER<'A'>,
+        gpioa_otyper: &mut OTYPER<'A'>,
+        gpiob_moder: &mut MODER<'B'>,
+        gpiob_otyper: &mut OTYPER<'B'>,
+        gpioc_moder: &mut MODER<'C'>,
+        gpioc_otyper: &mut OTYPER<'C'>,
+        clocks: Clocks,
+    ) -> MatrixUnreset {
         // Use .into_push_pull_output_in_state(…) to set an initial state on pins
-        let mut init_matrix = Matrix {
+        let pins = MatrixPins {
             sb: pc5
                 .into_push_pull_output_in_state(gpioc_moder, gpioc_otyper, PinState::High)
                 .set_speed(VeryHigh),
@@ -96,31 +215,124 @@ impl Matrix {
                 .into_push_pull_output_in_state(gpioa_moder, gpioa_otyper, PinState::Low)
                 .set_speed(VeryHigh),
         };
+        MatrixUnreset {
+            matrix: Matrix::from_pins(pins, clocks),
+        }
+    }
 
-        let mut x = stm32l4xx_hal::delay::DelayCM::new(clocks);
-        x.delay_ms(100u8);
+    /// Rebuilds a matrix from pins returned by [release](Matrix::release), without
+    /// waiting: the DM163 has been held in reset since the release, so RST is
+    /// released and bank 0 initialized right away. SB and LAT are set high and the
+    /// other pins low first, like [new](Matrix::new) does, in case they were used
+    /// for something else meanwhile.
+    pub fn from_configured_pins(pins: MatrixPins, clocks: Clocks) -> Self {
+        let mut matrix = Matrix::from_pins(pins, clocks);
+        matrix.begin_wake();
+        matrix.finish_wake();
+        matrix
+    }
+
+    /// Blanks the panel, holds the DM163 in reset and gives the pins back, for
+    /// example to use them for something else in a low power mode
+    pub fn release(mut self) -> MatrixPins {
+        self.power_down();
+        MatrixPins {
+            sb: self.sb,
+            lat: self.lat,
+            rst: self.rst,
+            sck: self.sck,
+            sda: self.sda,
+            c0: self.c0,
+            c1: self.c1,
+            c2: self.c2,
+            c3: self.c3,
+            c4: self.c4,
+            c5: self.c5,
+            c6: self.c6,
+            c7: self.c7,
+        }
+    }
 
-        init_matrix.rst.set_high();
+    /// Builds the structure around configured pins, with default settings
+    fn from_pins(pins: MatrixPins, clocks: Clocks) -> Self {
+        Matrix {
+            sb: pins.sb,
+            lat: pins.lat,
+            rst: pins.rst,
+            sck: pins.sck,
+            sda: pins.sda,
+            c0: pins.c0,
+            c1: pins.c1,
+            c2: pins.c2,
+            c3: pins.c3,
+            c4: pins.c4,
+            c5: pins.c5,
+            c6: pins.c6,
+            c7: pins.c7,
+            config: MatrixConfig::default(),
+            sysclk_hz: clocks.sysclk().raw(),
+            dead_cycles: 0,
+            brightness: MAX_BRIGHTNESS,
+            dot_correction: [MAX_BRIGHTNESS; 24],
+            duty: 255,
+            blanked_at: 0,
+            active_row: None,
+            orientation: Orientation::Identity,
+        }
+    }
 
-        init_matrix.init_bank0();
+    /// Returns the wiring configuration of the panel
+    pub fn config(&self) -> MatrixConfig {
+        self.config
+    }
+
+    /// Changes the wiring configuration of the panel, the row pins drive the
+    /// image rows or columns depending on its scan mode
+    pub fn set_config(&mut self, config: MatrixConfig) {
+        self.config = config;
+        self.dead_cycles = ns_to_cycles(config.interrow_dead_ns, self.sysclk_hz);
+    }
 
-        init_matrix
+    /// Returns the orientation in which images are laid out on the panel
+    pub fn orientation(&self) -> Orientation {
+        self.orientation
     }
 
-    /// Make a brief high pulse of the SCK pin
-    fn pulse_sck(&mut self) {
-        self.sck.set_high();
-        self.sck.set_low();
+    /// Changes the orientation in which [display_image](Matrix::display_image) and
+    /// the scanned lines lay images out on the panel, instead of rotating the images
+    pub fn set_orientation(&mut self, o: Orientation) {
+        self.orientation = o;
     }
 
-    /// Make a brief low pulse of the LAT pin
-    fn pulse_lat(&mut self) {
-        self.lat.set_low();
-        self.lat.set_high();
+    /// Returns the fraction of the row period during which a row is on, out of 255
+    pub fn duty(&self) -> u8 {
+        self.duty
     }
 
-    /// Set the given row output in the chosen state
-    fn row(&mut self, row: usize, state: PinState) {
+    /// Sets the fraction of the row period during which a row is on, out of 255
+    /// (255, the default, keeps rows on until the next one is sent).
+    ///
+    /// The matrix does not switch rows off by itself: the display task calls
+    /// [deactivate_current_row](Matrix::deactivate_current_row) `period * duty / 255`
+    /// after sending a row, `period` being `1 / (8 * refresh_rate)`. Every LED still
+    /// flashes once per frame, only the flash gets shorter, so duty dimming adds no
+    /// flicker frequency below the refresh rate; the refresh rate itself has to be
+    /// high enough for dim frames not to flicker, see
+    /// [effective_refresh_rate](crate::settings::DisplaySettings::effective_refresh_rate).
+    pub fn set_duty(&mut self, on_fraction: u8) {
+        self.duty = on_fraction;
+    }
+
+    /// Switches off the row which was last switched on, until the next row is sent
+    pub fn deactivate_current_row(&mut self) {
+        if let Some(row) = self.active_row.take() {
+            // The row was checked when it was sent
+            let _ = self.row(row, PinState::Low);
+        }
+    }
+
+    /// Set the given row output (from 1 to 8) in the chosen state
+    fn row(&mut self, row: usize, state: PinState) -> Result<(), MatrixError> {
         match row {
             1 => self.c0.set_state(state),
             2 => self.c1.set_state(state),
@@ -130,53 +342,232 @@ impl Matrix {
             6 => self.c5.set_state(state),
             7 => self.c6.set_state(state),
             8 => self.c7.set_state(state),
-            _ => self.c7.set_state(state),
+            _ => return Err(MatrixError::InvalidRow(row)),
         }
+        Ok(())
+    }
+
+    /// Send a full row of bytes in BGR order and pulse LAT low. Gamma correction
+    /// must be applied to every pixel before sending them. The previous row must
+    /// be deactivated and the new one activated. Rows go from 1 to 8, nothing is
+    /// sent for other rows.
+    pub fn send_row(&mut self, row: usize, pixels: &[Color]) -> Result<(), MatrixError> {
+        self.send_row_with(row, pixels, Color::gamma_correct)
     }
 
-    /// Send a byte on SDA starting with the MSB and pulse SCK high after each bit
-    fn send_byte(&mut self, pixel: u8) {
-        for i in (0..8).rev() {
-            self.sda.set_state((pixel & (1 << i) != 0).into());
-            self.pulse_sck();
+    /// Same as [send_row](Matrix::send_row) without gamma correction, for pixels
+    /// which are already corrected or when gamma correction is disabled.
+    pub fn send_row_raw(&mut self, row: usize, pixels: &[Color]) -> Result<(), MatrixError> {
+        self.send_row_with(row, pixels, |pixel| *pixel)
+    }
+
+    /// Sends bit plane `bit_plane` (0 for the LSB to 7) of a row of pixels as they
+    /// are sent to the panel (already corrected, like for
+    /// [send_row_raw](Matrix::send_row_raw)) for binary code modulation: every
+    /// channel whose bit is set is sent fully on, the others off (see [bcm_plane]).
+    ///
+    /// The display task sends the 8 planes of a row one after the other, keeping
+    /// plane n on for `2^n` time units, so that a row period is 255 units. Each
+    /// channel is then on for a time proportional to its value, with timing done by
+    /// the task instead of the DM163 PWM. At 60 frames per second a unit is about
+    /// 8 µs, which is less than bit-banging a row: planes 0 and 1 end up displayed
+    /// too long unless they are sent at a lower current (see
+    /// [set_brightness](Matrix::set_brightness)) with longer durations.
+    pub fn send_row_bcm(
+        &mut self,
+        row: usize,
+        pixels: &[Color],
+        bit_plane: u8,
+    ) -> Result<(), MatrixError> {
+        if bit_plane >= BCM_PLANES {
+            return Err(MatrixError::InvalidBitPlane(bit_plane));
         }
+        self.send_row_with(row, pixels, |pixel| bcm_plane(*pixel, bit_plane))
     }
 
-    /// Send a full row of bytes in BGR order and pulse LAT low. Gamma correction
-    /// must be applied to every pixel before sending them. The previous row must
-    /// be deactivated and the new one activated.
-    pub fn send_row(&mut self, row: usize, pixels: &[Color]) {
-        for (i, pixel) in pixels.iter().map(Color::gamma_correct).rev().enumerate() {
-            self.send_byte(pixel.b);
-            self.send_byte(pixel.g);
-            if i == 4 {
-                self.row((row + 7) % 8, PinState::Low); //turn off row at 5e beetween bg and r send
+    /// Send a full row, applying `correct` to every pixel before sending it
+    fn send_row_with(
+        &mut self,
+        row: usize,
+        pixels: &[Color],
+        correct: impl Fn(&Color) -> Color,
+    ) -> Result<(), MatrixError> {
+        #[cfg(feature = "defmt")]
+        let start = DWT::cycle_count();
+        pins::send_row(self, row, pixels.iter().map(correct))?;
+        self.active_row = Some(row);
+        #[cfg(feature = "defmt")]
+        trace!(
+            "matrix: row {} sent in {} cycles ({} dead cycles)",
+            row,
+            DWT::cycle_count().wrapping_sub(start),
+            self.dead_cycles
+        );
+        Ok(())
+    }
+
+    /// Switches every row off and latches black pixels, so that the panel stays dark
+    /// until the next row is sent
+    pub fn blank(&mut self) {
+        pins::blank(self);
+        self.active_row = None;
+    }
+
+    /// Blanks the panel and holds the DM163 in reset. [wake](Matrix::wake) or
+    /// [begin_wake](Matrix::begin_wake) must be called before displaying anything
+    /// again.
+    pub fn power_down(&mut self) {
+        pins::power_down(self);
+        self.active_row = None;
+    }
+
+    /// Runs the reset sequence of [new](Matrix::new) again after
+    /// [power_down](Matrix::power_down): SB and LAT high, RST low for 100ms, then RST
+    /// high and bank 0 programmed with the current dot correction and brightness.
+    /// This blocks for 100ms, see [begin_wake](Matrix::begin_wake) to avoid it.
+    pub fn wake(&mut self) {
+        self.begin_wake();
+        cortex_m::asm::delay(self.sysclk_hz / 10);
+        self.finish_wake();
+    }
+
+    /// Starts the reset sequence of [wake](Matrix::wake) without waiting: SB and LAT
+    /// high, SCK, SDA, RST and the rows low. The application calls
+    /// [finish_wake](Matrix::finish_wake) at least 100ms later, for example from a
+    /// task spawned with its monotonic timer, like for [MatrixUnreset::finish].
+    pub fn begin_wake(&mut self) {
+        pins::begin_wake(self);
+        self.active_row = None;
+    }
+
+    /// Ends the reset sequence started by [begin_wake](Matrix::begin_wake): releases
+    /// RST and programs bank 0 with the current dot correction and brightness
+    pub fn finish_wake(&mut self) {
+        let bank0 = self.bank0_values();
+        pins::finish_wake(self, &bank0);
+    }
+
+    /// Returns the brightness level, from 0 to [MAX_BRIGHTNESS]
+    pub fn brightness(&self) -> u8 {
+        self.brightness
+    }
+
+    /// Sets the current of every DM163 output to its dot correction value scaled by
+    /// `level` out of [MAX_BRIGHTNESS] (larger levels are clamped), which dims the
+    /// whole panel without changing the pixels.
+    pub fn set_brightness(&mut self, level: u8) {
+        self.brightness = level.min(MAX_BRIGHTNESS);
+        self.init_bank0();
+    }
+
+    /// Returns the dot correction values last programmed by
+    /// [init_bank0_with](Matrix::init_bank0_with)
+    pub fn dot_correction(&self) -> &[u8; 24] {
+        &self.dot_correction
+    }
+
+    /// Programs a 6 bit dot correction value (larger values are clamped) for each
+    /// output, to compensate for LEDs of unequal strength. Values go in the order
+    /// of the pixels given to [send_row](Matrix::send_row): red, green and blue of
+    /// the first pixel, then of the second one, and so on. They are kept, scaled by
+    /// the brightness, and programmed again by [wake](Matrix::wake).
+    pub fn init_bank0_with(&mut self, values: &[u8; 24]) {
+        self.dot_correction = values.map(|value| value.min(MAX_BRIGHTNESS));
+        self.init_bank0();
+    }
+
+    /// Initialize bank0 with the dot correction values scaled by the brightness, by
+    /// default every output at full current, that is 144 one bits.
+    ///
+    /// The DM163 has two banks of registers, selected by SB: with SB low, bits go
+    /// to bank 0, the 6 bit current (dot correction) register of each of the 24
+    /// outputs; with SB high, they go to bank 1, the 8 bit PWM value of each output,
+    /// which [send_row](Matrix::send_row) fills. Bank 0 takes 24 x 6 = 144 bits, MSB
+    /// first, the last output first like the bytes of a row, and is latched by a LAT
+    /// pulse. SB is left high so that rows keep going to bank 1.
+    fn init_bank0(&mut self) {
+        let bank0 = self.bank0_values();
+        pins::write_bank0(self, &bank0);
+    }
+
+    /// Returns the dot correction values scaled by the brightness
+    fn bank0_values(&self) -> [u8; 24] {
+        pins::scale_bank0(&self.dot_correction, self.brightness)
+    }
+
+    /// Display a full image, line by line according to the scan mode and in the
+    /// orientation of the matrix, as fast as possible.
+    pub fn display_image(&mut self, image: &Image) -> Result<(), MatrixError> {
+        let scan = self.config.scan;
+        match (scan, self.orientation) {
+            (ScanMode::Rows, Orientation::Identity) => {
+                for (i, row) in image.rows().enumerate() {
+                    self.send_row(i + 1, row)?;
+                }
+            }
+            // oriented_line(image, o, mode, n) gives the content of line n as displayed,
+            // in the format self.send_row() uses.
+            _ => {
+                let mut cursor = RowCursor::new();
+                for _ in 0..8 {
+                    let line = cursor.next_line();
+                    self.send_row(line, &oriented_line(image, self.orientation, scan, line))?;
+                }
             }
-            self.send_byte(pixel.r);
         }
-        self.pulse_lat();
-        self.row(row, PinState::High);
+        Ok(())
     }
+}
 
-    /// Initialize bank0 by temporarily setting SB to low and sending 144 one bits,
-    /// pulsing SCK high after each bit and pulsing LAT low at the end. SB is then
-    /// restored to high.
-    fn init_bank0(&mut self) {
-        self.sb.set_low();
-        for _ in 1..=144 {
-            self.sda.set_state(PinState::High);
-            self.pulse_sck();
+/// The matrix displays the lines scanned by the display task
+impl RowSink for Matrix {
+    fn send_row_raw(&mut self, line: usize, pixels: &[Color]) {
+        if let Err(e) = Matrix::send_row_raw(self, line, pixels) {
+            warn!("matrix: {}", e);
+        }
+    }
+
+    fn orientation(&self) -> Orientation {
+        self.orientation
+    }
+}
+
+/// The control sequences of [pins] drive the pins of the matrix
+impl PanelPins for Matrix {
+    fn set_sb(&mut self, high: bool) {
+        self.sb.set_state(high.into());
+    }
+
+    fn set_lat(&mut self, high: bool) {
+        self.lat.set_state(high.into());
+    }
+
+    fn set_rst(&mut self, high: bool) {
+        self.rst.set_state(high.into());
+    }
+
+    fn set_sck(&mut self, high: bool) {
+        self.sck.set_state(high.into());
+    }
+
+    fn set_sda(&mut self, high: bool) {
+        self.sda.set_state(high.into());
+    }
+
+    fn set_row(&mut self, row: usize, high: bool) {
+        if let Err(e) = self.row(row, high.into()) {
+            warn!("matrix: {}", e);
         }
-        self.pulse_lat();
-        self.sb.set_high();
     }
 
-    /// Display a full image, row by row, as fast as possible.
-    pub fn display_image(&mut self, image: &Image) {
-        // Do not forget that image.row(n) gives access to the content of row n,
-        // and that self.send_row() uses the same format.
-        for i in 1..=8 {
-            self.send_row(i, image.row(i));
+    fn row_blanked(&mut self) {
+        // The dead time counts from here, the bytes sent until the latch already count
+        self.blanked_at = DWT::cycle_count();
+    }
+
+    fn before_latch(&mut self) {
+        if self.dead_cycles > 0 {
+            while DWT::cycle_count().wrapping_sub(self.blanked_at) < self.dead_cycles {}
         }
     }
 }
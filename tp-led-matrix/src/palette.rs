@@ -0,0 +1,94 @@
+//! This module builds indexed images and palette cycling animations
+
+use crate::animation::Animation;
+use crate::{Color, Image};
+use core::ops::Range;
+
+/// Errors returned by palette operations
+#[derive(Clone, Copy)]
+pub enum PaletteError {
+    /// The cycled range goes beyond the end of the palette
+    RangeOutOfPalette,
+}
+
+/// Image whose pixels are indices into a palette, row by row
+#[derive(Clone)]
+pub struct IndexedImage(pub [u8; 64]);
+
+/// Implements functions for indexed image structure
+impl IndexedImage {
+    /// Creates an indexed image with every pixel using the same index
+    pub fn new_solid(index: u8) -> Self {
+        IndexedImage([index; 64])
+    }
+
+    /// Builds the colored image, indices outside of the palette are black
+    pub fn expand(&self, palette: &[Color]) -> Image {
+        let mut image = Image::default();
+        for (pos, &index) in self.0.iter().enumerate() {
+            image[(pos / 8 + 1, pos % 8 + 1)] =
+                palette.get(index as usize).copied().unwrap_or_default();
+        }
+        image
+    }
+
+    /// Rotates the colors of `palette` in `range` by one step: each entry takes the
+    /// color of the previous one and the first takes the color of the last.
+    /// Empty ranges and ranges of one entry leave the palette unchanged.
+    pub fn cycle(&self, palette: &mut [Color], range: Range<usize>) -> Result<(), PaletteError> {
+        let cycled = palette
+            .get_mut(range)
+            .ok_or(PaletteError::RangeOutOfPalette)?;
+        if !cycled.is_empty() {
+            cycled.rotate_right(1);
+        }
+        Ok(())
+    }
+}
+
+/// Implements default function for indexed image type objects
+impl Default for IndexedImage {
+    fn default() -> Self {
+        IndexedImage([0; 64])
+    }
+}
+
+/// Animation displaying an indexed image while cycling part of its palette
+pub struct PaletteCycle<const P: usize> {
+    image: IndexedImage,
+    palette: [Color; P],
+    range: Range<usize>,
+}
+
+/// Implements functions for palette cycle structure
+impl<const P: usize> PaletteCycle<P> {
+    /// Creates a palette cycling animation, the range must be inside the palette
+    pub fn new(
+        image: IndexedImage,
+        palette: [Color; P],
+        range: Range<usize>,
+    ) -> Result<Self, PaletteError> {
+        if range.start > range.end || range.end > P {
+            return Err(PaletteError::RangeOutOfPalette);
+        }
+        Ok(PaletteCycle {
+            image,
+            palette,
+            range,
+        })
+    }
+
+    /// Current palette
+    pub fn palette(&self) -> &[Color; P] {
+        &self.palette
+    }
+}
+
+/// Each frame shows the image with the current palette, then cycles the palette
+impl<const P: usize> Animation for PaletteCycle<P> {
+    fn next_frame(&mut self, frame: &mut Image) {
+        *frame = self.image.expand(&self.palette);
+        // The range was checked by new()
+        self.image.cycle(&mut self.palette, self.range.clone()).ok();
+    }
+}
@@ -0,0 +1,586 @@
+//! This module drives the control sequences of the DM163 and of the row outputs
+//! through [PanelPins], so that the pin states they leave can be checked on the host
+//!
+//! Bits are sent MSB first on SDA, each one clocked by a high pulse of SCK, and the
+//! received bits are latched by a low pulse of LAT into the bank selected by SB (low
+//! for bank 0, the dot correction, high for bank 1, the PWM values).
+
+use crate::scan::MatrixError;
+use crate::Color;
+
+/// Number of bytes of a row of PWM values, 8 pixels of 3 channels
+const ROW_BYTES: usize = 24;
+
+/// Highest current level of the DM163 outputs, its bank 0 registers being 6 bits wide
+pub const MAX_BRIGHTNESS: u8 = 63;
+
+/// Output pins of the panel
+pub trait PanelPins {
+    /// Drives SB, the bank selection of the DM163
+    fn set_sb(&mut self, high: bool);
+    /// Drives LAT, the latch of the DM163, active low
+    fn set_lat(&mut self, high: bool);
+    /// Drives RST, the reset of the DM163, active low
+    fn set_rst(&mut self, high: bool);
+    /// Drives SCK, the serial clock of the DM163
+    fn set_sck(&mut self, high: bool);
+    /// Drives SDA, the serial data of the DM163
+    fn set_sda(&mut self, high: bool);
+    /// Drives the output of a row, from 1 to 8
+    fn set_row(&mut self, row: usize, high: bool);
+
+    /// Called by [send_row] right after switching the previous row off, for
+    /// example to start counting the dead time between rows
+    fn row_blanked(&mut self) {}
+
+    /// Called by [send_row] just before latching the new row, for example to wait
+    /// for the end of the dead time
+    fn before_latch(&mut self) {}
+}
+
+/// Sends a byte on SDA starting with the MSB, pulsing SCK high after each bit.
+/// The SPI peripherals cannot do it: on the STM32L475, SCK (PB1) has no SPI clock
+/// function and SDA (PA4) is only the NSS line of SPI1 and SPI3.
+pub fn send_byte<P: PanelPins>(pins: &mut P, byte: u8) {
+    for i in (0..8).rev() {
+        pins.set_sda(byte & (1 << i) != 0);
+        pins.set_sck(true);
+        pins.set_sck(false);
+    }
+}
+
+/// Returns the row displayed before `row`, row 8 coming before row 1
+pub fn previous_row(row: usize) -> Result<usize, MatrixError> {
+    match row {
+        1 => Ok(8),
+        2..=8 => Ok(row - 1),
+        _ => Err(MatrixError::InvalidRow(row)),
+    }
+}
+
+/// Sends a row of pixels, given from the first to the last, and displays it on row
+/// `row` (from 1 to 8) instead of the previous one. The bytes go from the last pixel
+/// to the first one, each in B, G, R order. The previous row is switched off between
+/// the G and R bytes of the fifth pixel sent, or after the last byte of a shorter
+/// row, then LAT is pulsed and `row` switched on. Nothing is sent for other rows.
+pub fn send_row<P: PanelPins>(
+    pins: &mut P,
+    row: usize,
+    pixels: impl DoubleEndedIterator<Item = Color>,
+) -> Result<(), MatrixError> {
+    // Checked before sending anything so that an invalid row leaves the panel untouched
+    let previous = previous_row(row)?;
+    let mut blanked = false;
+    for (i, pixel) in pixels.rev().enumerate() {
+        send_byte(pins, pixel.b);
+        send_byte(pins, pixel.g);
+        if i == 4 {
+            pins.set_row(previous, false); //turn off row at 5e beetween bg and r send
+            pins.row_blanked();
+            blanked = true;
+        }
+        send_byte(pins, pixel.r);
+    }
+    // A row of less than 5 pixels never reached the blanking point above
+    if !blanked {
+        pins.set_row(previous, false);
+        pins.row_blanked();
+    }
+    pins.before_latch();
+    pulse_lat(pins);
+    pins.set_row(row, true);
+    Ok(())
+}
+
+/// Makes a brief low pulse of LAT, latching the received bits
+pub fn pulse_lat<P: PanelPins>(pins: &mut P) {
+    pins.set_lat(false);
+    pins.set_lat(true);
+}
+
+/// Programs bank 0 with a 6 bit current value per output, the last output first
+/// like the bytes of a row, then selects bank 1 again
+pub fn write_bank0<P: PanelPins>(pins: &mut P, values: &[u8; ROW_BYTES]) {
+    pins.set_sb(false);
+    for &value in values.iter().rev() {
+        for bit in (0..6).rev() {
+            pins.set_sda(value & (1 << bit) != 0);
+            pins.set_sck(true);
+            pins.set_sck(false);
+        }
+    }
+    pulse_lat(pins);
+    pins.set_sb(true);
+}
+
+/// Returns the bank 0 values of `dot_correction` scaled by `brightness` out of
+/// [MAX_BRIGHTNESS], rounded
+pub fn scale_bank0(dot_correction: &[u8; ROW_BYTES], brightness: u8) -> [u8; ROW_BYTES] {
+    let (brightness, max) = (brightness as u16, MAX_BRIGHTNESS as u16);
+    dot_correction.map(|value| ((value as u16 * brightness + max / 2) / max) as u8)
+}
+
+/// Switches every row off and latches black pixels
+pub fn blank<P: PanelPins>(pins: &mut P) {
+    for row in 1..=8 {
+        pins.set_row(row, false);
+    }
+    for _ in 0..ROW_BYTES {
+        send_byte(pins, 0);
+    }
+    pulse_lat(pins);
+}
+
+/// Blanks the panel and holds the DM163 in reset
+pub fn power_down<P: PanelPins>(pins: &mut P) {
+    blank(pins);
+    pins.set_rst(false);
+}
+
+/// Starts the reset sequence of a new matrix: SB and LAT high, SCK, SDA, RST and
+/// the rows low. RST must then stay low for 100ms before [finish_wake].
+pub fn begin_wake<P: PanelPins>(pins: &mut P) {
+    pins.set_sb(true);
+    pins.set_lat(true);
+    pins.set_sck(false);
+    pins.set_sda(false);
+    pins.set_rst(false);
+    for row in 1..=8 {
+        pins.set_row(row, false);
+    }
+}
+
+/// Ends the reset sequence started by [begin_wake]: releases RST and programs bank 0
+/// with `bank0`
+pub fn finish_wake<P: PanelPins>(pins: &mut P, bank0: &[u8; ROW_BYTES]) {
+    pins.set_rst(true);
+    write_bank0(pins, bank0);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::scan::oriented_line;
+    use crate::{Image, Orientation, ScanMode};
+
+    /// Row switches and hook calls, with the number of bits shifted since the last
+    /// latch when they happened
+    #[derive(Clone, Copy, Debug, PartialEq, Eq)]
+    enum Event {
+        Row(usize, bool, usize),
+        Blanked(usize),
+        BeforeLatch(usize),
+    }
+
+    /// Pin levels, the bits latched into each bank and the events
+    #[derive(Default)]
+    struct MockPins {
+        sb: bool,
+        lat: bool,
+        rst: bool,
+        sck: bool,
+        sda: bool,
+        rows: [bool; 8],
+        shifted: heapless::Vec<bool, 256>,
+        bank0: heapless::Vec<bool, 256>,
+        bank1: heapless::Vec<bool, 256>,
+        events: heapless::Vec<Event, 64>,
+    }
+
+    impl PanelPins for MockPins {
+        fn set_sb(&mut self, high: bool) {
+            self.sb = high;
+        }
+
+        fn set_lat(&mut self, high: bool) {
+            if high && !self.lat {
+                let bank = if self.sb {
+                    &mut self.bank1
+                } else {
+                    &mut self.bank0
+                };
+                *bank = core::mem::take(&mut self.shifted);
+            }
+            self.lat = high;
+        }
+
+        fn set_rst(&mut self, high: bool) {
+            if !high {
+                self.shifted.clear();
+            }
+            self.rst = high;
+        }
+
+        fn set_sck(&mut self, high: bool) {
+            // The shift register stays cleared while the DM163 is held in reset
+            if high && !self.sck && self.rst {
+                self.shifted.push(self.sda).unwrap();
+            }
+            self.sck = high;
+        }
+
+        fn set_sda(&mut self, high: bool) {
+            self.sda = high;
+        }
+
+        fn set_row(&mut self, row: usize, high: bool) {
+            self.rows[row - 1] = high;
+            let event = Event::Row(row, high, self.shifted.len());
+            self.events.push(event).unwrap();
+        }
+
+        fn row_blanked(&mut self) {
+            let event = Event::Blanked(self.shifted.len());
+            self.events.push(event).unwrap();
+        }
+
+        fn before_latch(&mut self) {
+            let event = Event::BeforeLatch(self.shifted.len());
+            self.events.push(event).unwrap();
+        }
+    }
+
+    /// Pins of a panel displaying a row, as left by the display task
+    fn displaying() -> MockPins {
+        let mut pins = MockPins {
+            sb: true,
+            lat: true,
+            rst: true,
+            ..MockPins::default()
+        };
+        for _ in 0..ROW_BYTES {
+            send_byte(&mut pins, 0xa5);
+        }
+        pulse_lat(&mut pins);
+        pins.set_row(3, true);
+        pins
+    }
+
+    #[test]
+    fn blank_switches_rows_off_and_latches_zeros() {
+        let mut pins = displaying();
+        blank(&mut pins);
+        assert_eq!(pins.rows, [false; 8]);
+        assert_eq!(pins.bank1.len(), 8 * ROW_BYTES);
+        assert!(pins.bank1.iter().all(|&bit| !bit));
+        assert!(pins.rst && pins.sb && pins.lat);
+    }
+
+    #[test]
+    fn power_down_holds_the_reset() {
+        let mut pins = displaying();
+        power_down(&mut pins);
+        assert_eq!(pins.rows, [false; 8]);
+        assert!(pins.bank1.iter().all(|&bit| !bit));
+        assert!(!pins.rst);
+    }
+
+    #[test]
+    fn wake_restores_the_reset_defaults() {
+        let mut pins = displaying();
+        power_down(&mut pins);
+        pins.set_sb(false);
+        pins.set_lat(false);
+        pins.set_sda(true);
+        begin_wake(&mut pins);
+        // Same levels as a new matrix still in reset
+        assert!(pins.sb && pins.lat);
+        assert!(!pins.rst && !pins.sck && !pins.sda);
+        assert_eq!(pins.rows, [false; 8]);
+
+        finish_wake(&mut pins, &[63; ROW_BYTES]);
+        assert!(pins.rst && pins.sb && pins.lat);
+        assert_eq!(pins.bank0.len(), 6 * ROW_BYTES);
+        assert!(pins.bank0.iter().all(|&bit| bit));
+    }
+
+    #[test]
+    fn bank0_is_sent_last_output_first() {
+        let mut pins = MockPins {
+            rst: true,
+            ..MockPins::default()
+        };
+        let mut values = [0; ROW_BYTES];
+        values[ROW_BYTES - 1] = 0b10_0001;
+        write_bank0(&mut pins, &values);
+        assert_eq!(&pins.bank0[..6], &[true, false, false, false, false, true]);
+        assert!(pins.bank0[6..].iter().all(|&bit| !bit));
+        assert!(pins.sb);
+    }
+
+    /// Returns the 6 bit values latched into bank 0, the first output first
+    fn bank0_values(pins: &MockPins) -> [u8; ROW_BYTES] {
+        assert_eq!(pins.bank0.len(), 6 * ROW_BYTES);
+        let mut values = [0; ROW_BYTES];
+        for (value, bits) in values.iter_mut().rev().zip(pins.bank0.chunks(6)) {
+            *value = bits.iter().fold(0, |v, &bit| v << 1 | bit as u8);
+        }
+        values
+    }
+
+    #[test]
+    fn brightness_scales_the_bank0_bits() {
+        let full = [MAX_BRIGHTNESS; ROW_BYTES];
+        let mut pins = MockPins {
+            rst: true,
+            ..MockPins::default()
+        };
+        write_bank0(&mut pins, &scale_bank0(&full, 32));
+        // 63 * 32 / 63 = 32, that is 0b100000 for every output
+        for bits in pins.bank0.chunks(6) {
+            assert_eq!(bits, &[true, false, false, false, false, false]);
+        }
+        assert!(pins.sb && pins.lat);
+
+        write_bank0(&mut pins, &scale_bank0(&full, 0));
+        assert!(pins.bank0.iter().all(|&bit| !bit));
+
+        let mut dot_correction = [0; ROW_BYTES];
+        for (i, value) in dot_correction.iter_mut().enumerate() {
+            *value = 2 * i as u8 + 10;
+        }
+        write_bank0(&mut pins, &scale_bank0(&dot_correction, MAX_BRIGHTNESS));
+        assert_eq!(bank0_values(&pins), dot_correction);
+        write_bank0(&mut pins, &scale_bank0(&dot_correction, 21));
+        assert_eq!(bank0_values(&pins)[0], 3);
+        assert_eq!(bank0_values(&pins)[ROW_BYTES - 1], 19);
+        // Bank 1 is left alone
+        assert!(pins.bank1.is_empty());
+    }
+
+    /// Returns the bytes latched into bank 1, in the order they were sent
+    fn bank1_bytes(pins: &MockPins) -> heapless::Vec<u8, 32> {
+        pins.bank1
+            .chunks(8)
+            .map(|bits| bits.iter().fold(0, |byte, &bit| byte << 1 | bit as u8))
+            .collect()
+    }
+
+    /// Row of pixels whose channels all differ
+    fn test_row() -> [Color; 8] {
+        let mut pixels = [Color::BLACK; 8];
+        for (i, pixel) in pixels.iter_mut().enumerate() {
+            let i = i as u8;
+            *pixel = Color {
+                r: 10 * i + 1,
+                g: 10 * i + 2,
+                b: 10 * i + 3,
+            };
+        }
+        pixels
+    }
+
+    #[test]
+    fn row_switches_after_the_fifth_pixel() {
+        let mut pins = displaying();
+        pins.events.clear();
+        assert!(send_row(&mut pins, 4, test_row().into_iter()).is_ok());
+        // Row 3 goes off between the G and R bytes of the fifth pixel sent, and row
+        // 4 comes on once the whole row is latched
+        assert_eq!(
+            pins.events,
+            [
+                Event::Row(3, false, 4 * 24 + 16),
+                Event::Blanked(4 * 24 + 16),
+                Event::BeforeLatch(8 * 24),
+                Event::Row(4, true, 0),
+            ]
+        );
+        let expected: heapless::Vec<u8, 32> = test_row()
+            .iter()
+            .rev()
+            .flat_map(|pixel| [pixel.b, pixel.g, pixel.r])
+            .collect();
+        assert_eq!(bank1_bytes(&pins), expected);
+    }
+
+    #[test]
+    fn row_1_follows_row_8() {
+        assert_eq!(previous_row(1), Ok(8));
+        assert_eq!(previous_row(8), Ok(7));
+        let mut pins = displaying();
+        pins.set_row(3, false);
+        pins.set_row(8, true);
+        pins.events.clear();
+        assert!(send_row(&mut pins, 1, test_row().into_iter()).is_ok());
+        assert_eq!(pins.events[0], Event::Row(8, false, 4 * 24 + 16));
+        assert_eq!(
+            pins.rows,
+            [true, false, false, false, false, false, false, false]
+        );
+    }
+
+    #[test]
+    fn one_row_is_lit_at_a_time() {
+        // Frames go from row 1 to row 8, so row 8 is on before a frame starts
+        let mut pins = displaying();
+        pins.set_row(3, false);
+        pins.set_row(8, true);
+        for _ in 0..2 {
+            for row in 1..=8 {
+                assert!(send_row(&mut pins, row, test_row().into_iter()).is_ok());
+                for (i, &lit) in pins.rows.iter().enumerate() {
+                    assert_eq!(lit, i + 1 == row, "row {} after sending {}", i + 1, row);
+                }
+            }
+            pins.events.clear();
+        }
+    }
+
+    #[test]
+    fn short_rows_blank_after_the_last_byte() {
+        let mut pins = displaying();
+        pins.events.clear();
+        assert!(send_row(&mut pins, 4, test_row()[..3].iter().copied()).is_ok());
+        assert_eq!(pins.events[0], Event::Row(3, false, 3 * 24));
+        assert_eq!(bank1_bytes(&pins).len(), 9);
+        assert!(pins.rows[3] && !pins.rows[2]);
+    }
+
+    #[test]
+    fn invalid_rows_send_nothing() {
+        for row in [0, 9, usize::MAX] {
+            let mut pins = displaying();
+            pins.events.clear();
+            let bank1 = pins.bank1.clone();
+            assert_eq!(
+                send_row(&mut pins, row, test_row().into_iter()),
+                Err(MatrixError::InvalidRow(row))
+            );
+            assert_eq!(previous_row(row), Err(MatrixError::InvalidRow(row)));
+            assert!(pins.events.is_empty() && pins.shifted.is_empty());
+            assert_eq!(pins.bank1, bank1);
+            assert!(pins.rows[2]);
+        }
+    }
+
+    /// Sends the 8 lines of `image` like the display task does, and returns every
+    /// (line, position) whose red byte was latched lit, positions going from 1 to 8
+    fn lit_positions(
+        image: &Image,
+        o: Orientation,
+        mode: ScanMode,
+    ) -> heapless::Vec<(usize, usize), 64> {
+        let mut pins = displaying();
+        let mut lit = heapless::Vec::new();
+        for line in 1..=8 {
+            let pixels = oriented_line(image, o, mode, line);
+            assert!(send_row(&mut pins, line, pixels.into_iter()).is_ok());
+            assert!(pins.rows[line - 1]);
+            for (i, &byte) in bank1_bytes(&pins).iter().enumerate() {
+                // Red byte of the pixels sent from the last to the first one
+                if byte != 0 && i % 3 == 2 {
+                    lit.push((line, 8 - i / 3)).unwrap();
+                }
+            }
+            pins.events.clear();
+        }
+        lit
+    }
+
+    #[test]
+    fn corner_pixel_follows_the_orientation() {
+        let mut image = Image::default();
+        image[(1, 1)] = Color::RED;
+        // Row and column where the top left pixel of the image is displayed
+        for (o, displayed) in [
+            (Orientation::Identity, (1, 1)),
+            (Orientation::Rotate90, (1, 8)),
+            (Orientation::Rotate180, (8, 8)),
+            (Orientation::Rotate270, (8, 1)),
+            (Orientation::FlipHorizontal, (1, 8)),
+            (Orientation::FlipVertical, (8, 1)),
+            (Orientation::Transpose, (1, 1)),
+            (Orientation::AntiTranspose, (8, 8)),
+        ] {
+            assert_eq!(
+                lit_positions(&image, o, ScanMode::Rows),
+                [displayed],
+                "{:?}",
+                o
+            );
+            // With columns on the row outputs, the line is the column
+            let (row, col) = displayed;
+            assert_eq!(
+                lit_positions(&image, o, ScanMode::Columns),
+                [(col, row)],
+                "{:?}",
+                o
+            );
+        }
+    }
+
+    #[test]
+    fn released_pins_are_rebuilt_into_a_working_panel() {
+        // Matrix::release powers the panel down before giving the pins back
+        let mut pins = displaying();
+        power_down(&mut pins);
+        assert_eq!(pins.rows, [false; 8]);
+        assert!(!pins.rst);
+
+        // The firmware uses the pins for something else meanwhile
+        pins.set_sb(false);
+        pins.set_sda(true);
+        pins.set_sck(true);
+        pins.set_row(2, true);
+        pins.set_row(7, true);
+
+        // Matrix::from_configured_pins restores the reset levels, then releases RST
+        let dot_correction = scale_bank0(&[40; ROW_BYTES], MAX_BRIGHTNESS);
+        begin_wake(&mut pins);
+        assert!(pins.sb && pins.lat && !pins.sck && !pins.sda && !pins.rst);
+        assert_eq!(pins.rows, [false; 8]);
+        finish_wake(&mut pins, &dot_correction);
+        assert!(pins.rst && pins.sb && pins.lat);
+        assert_eq!(bank0_values(&pins), dot_correction);
+
+        // Rows go to bank 1 again
+        pins.events.clear();
+        assert!(send_row(&mut pins, 1, test_row().into_iter()).is_ok());
+        assert_eq!(bank1_bytes(&pins).len(), ROW_BYTES);
+        assert_eq!(
+            pins.rows,
+            [true, false, false, false, false, false, false, false]
+        );
+        assert_eq!(bank0_values(&pins), dot_correction);
+    }
+
+    #[test]
+    fn bank0_bitstream_is_144_bits_last_output_first() {
+        let mut pins = MockPins {
+            rst: true,
+            ..MockPins::default()
+        };
+        let mut values = [0; ROW_BYTES];
+        for (i, value) in values.iter_mut().enumerate() {
+            // Distinct 6 bit values using the top and bottom bits
+            *value = (i as u8 + 1) | if i % 2 == 0 { 0b10_0000 } else { 0 };
+        }
+        write_bank0(&mut pins, &values);
+
+        let mut expected = heapless::Vec::<bool, 256>::new();
+        for &value in values.iter().rev() {
+            for bit in (0..6).rev() {
+                expected.push(value & (1 << bit) != 0).unwrap();
+            }
+        }
+        assert_eq!(expected.len(), 144);
+        assert_eq!(pins.bank0, expected);
+        // Blue of the last pixel goes first: 24 | 0 = 0b011000
+        assert_eq!(&pins.bank0[..6], &[false, true, true, false, false, false]);
+        // Red of the first pixel goes last: 1 | 32 = 0b100001
+        assert_eq!(
+            &pins.bank0[138..],
+            &[true, false, false, false, false, true]
+        );
+        assert!(pins.bank1.is_empty());
+
+        // The default dot correction is 144 one bits
+        write_bank0(
+            &mut pins,
+            &scale_bank0(&[MAX_BRIGHTNESS; ROW_BYTES], MAX_BRIGHTNESS),
+        );
+        assert_eq!(pins.bank0.len(), 144);
+        assert!(pins.bank0.iter().all(|&bit| bit));
+    }
+}
@@ -0,0 +1,247 @@
+//! This module lets the firmware modify every frame just before it is displayed
+
+use crate::settings::DisplaySettings;
+use crate::Image;
+use core::sync::atomic::{AtomicBool, AtomicU32, Ordering};
+use heapless::Vec;
+
+/// Transformation applied to every new frame
+///
+/// Besides the frame, [process](FramePostProcessor::process) receives the settings
+/// the frame is displayed with, copied by the display task at the frame boundary.
+/// The built-in processors read their parameters (zone gains, power cap) from them,
+/// which keeps those parameters changeable by setting commands without a second
+/// copy inside every processor. Processors which do not need them ignore them.
+pub trait FramePostProcessor {
+    /// Modifies the frame in place, `settings` being the ones of the frame
+    fn process(&mut self, frame: &mut Image, settings: &DisplaySettings);
+}
+
+/// Built-in post processor applying the row and column gains of the settings
+pub struct ZoneGains;
+
+/// Applies the zone gains of the frame settings
+impl FramePostProcessor for ZoneGains {
+    fn process(&mut self, frame: &mut Image, settings: &DisplaySettings) {
+        settings.apply_zone_gains(frame);
+    }
+}
+
+/// Record of the frames scaled down by [PowerCap], readable from any task
+pub struct PowerStatus {
+    limited_frames: AtomicU32,
+    last_frame_limited: AtomicBool,
+}
+
+/// Implements functions for power status structure
+impl PowerStatus {
+    /// Creates a status where no frame was limited
+    pub const fn new() -> Self {
+        PowerStatus {
+            limited_frames: AtomicU32::new(0),
+            last_frame_limited: AtomicBool::new(false),
+        }
+    }
+
+    /// Records whether the last processed frame was scaled down
+    pub fn record(&self, limited: bool) {
+        if limited {
+            self.limited_frames.fetch_add(1, Ordering::Relaxed);
+        }
+        self.last_frame_limited.store(limited, Ordering::Relaxed);
+    }
+
+    /// Number of frames scaled down since creation
+    pub fn limited_frames(&self) -> u32 {
+        self.limited_frames.load(Ordering::Relaxed)
+    }
+
+    /// Returns true if the last processed frame was scaled down
+    pub fn last_frame_limited(&self) -> bool {
+        self.last_frame_limited.load(Ordering::Relaxed)
+    }
+}
+
+/// Implements default function for power status type objects
+impl Default for PowerStatus {
+    fn default() -> Self {
+        PowerStatus::new()
+    }
+}
+
+/// Built-in post processor scaling frames down to the power cap of the settings
+pub struct PowerCap {
+    status: &'static PowerStatus,
+}
+
+/// Implements functions for power cap structure
+impl PowerCap {
+    /// Creates a post processor recording in `status` when it limits a frame
+    pub const fn new(status: &'static PowerStatus) -> Self {
+        PowerCap { status }
+    }
+}
+
+/// Applies the power cap of the frame settings
+impl FramePostProcessor for PowerCap {
+    fn process(&mut self, frame: &mut Image, settings: &DisplaySettings) {
+        let limited = settings.limit_power(frame);
+        self.status.record(limited);
+    }
+}
+
+/// Fixed capacity list of post processors, applied in registration order
+pub struct PostProcessors<const N: usize> {
+    list: Vec<&'static mut (dyn FramePostProcessor + Send), N>,
+}
+
+/// Implements functions for post processors structure
+impl<const N: usize> PostProcessors<N> {
+    /// Creates an empty list
+    pub const fn new() -> Self {
+        PostProcessors { list: Vec::new() }
+    }
+
+    /// Number of registered post processors
+    pub fn len(&self) -> usize {
+        self.list.len()
+    }
+
+    /// Returns true if no post processor is registered
+    pub fn is_empty(&self) -> bool {
+        self.list.is_empty()
+    }
+
+    /// Adds a post processor after the already registered ones, or gives it
+    /// back if the list is full
+    pub fn register(
+        &mut self,
+        processor: &'static mut (dyn FramePostProcessor + Send),
+    ) -> Result<(), &'static mut (dyn FramePostProcessor + Send)> {
+        self.list.push(processor)
+    }
+
+    /// Applies every post processor to the frame, in registration order
+    pub fn process(&mut self, frame: &mut Image, settings: &DisplaySettings) {
+        for processor in self.list.iter_mut() {
+            processor.process(frame, settings);
+        }
+    }
+}
+
+/// Implements default function for post processors type objects
+impl<const N: usize> Default for PostProcessors<N> {
+    fn default() -> Self {
+        PostProcessors::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate std;
+
+    use super::*;
+    use crate::Color;
+
+    #[test]
+    fn power_cap_records_limited_frames() {
+        static STATUS: PowerStatus = PowerStatus::new();
+        let mut power_cap = PowerCap::new(&STATUS);
+        let settings = DisplaySettings {
+            max_total_brightness: Some(1000),
+            ..DisplaySettings::DEFAULT
+        };
+        power_cap.process(&mut Image::new_solid(Color::WHITE), &settings);
+        assert!(STATUS.last_frame_limited());
+        power_cap.process(&mut Image::default(), &settings);
+        assert!(!STATUS.last_frame_limited());
+        power_cap.process(&mut Image::new_solid(Color::WHITE), &settings);
+        assert_eq!(STATUS.limited_frames(), 2);
+    }
+
+    /// Adds its value to the red channel of every pixel
+    struct AddRed(u8);
+
+    impl FramePostProcessor for AddRed {
+        fn process(&mut self, frame: &mut Image, _settings: &DisplaySettings) {
+            frame.iter_mut().for_each(|pixel| pixel.r += self.0);
+        }
+    }
+
+    /// Doubles the red channel of every pixel
+    struct DoubleRed;
+
+    impl FramePostProcessor for DoubleRed {
+        fn process(&mut self, frame: &mut Image, _settings: &DisplaySettings) {
+            frame.iter_mut().for_each(|pixel| pixel.r *= 2);
+        }
+    }
+
+    fn leak<P: FramePostProcessor + Send + 'static>(
+        processor: P,
+    ) -> &'static mut (dyn FramePostProcessor + Send) {
+        std::boxed::Box::leak(std::boxed::Box::new(processor))
+    }
+
+    fn red_after<const N: usize>(post_processors: &mut PostProcessors<N>) -> u8 {
+        let mut frame = Image::new_solid(Color { r: 5, g: 0, b: 0 });
+        post_processors.process(&mut frame, &DisplaySettings::DEFAULT);
+        frame[(1, 1)].r
+    }
+
+    #[test]
+    fn processors_run_in_registration_order() {
+        let mut add_first = PostProcessors::<2>::new();
+        assert!(add_first.register(leak(AddRed(3))).is_ok());
+        assert!(add_first.register(leak(DoubleRed)).is_ok());
+        assert_eq!(red_after(&mut add_first), (5 + 3) * 2);
+
+        let mut double_first = PostProcessors::<2>::new();
+        assert!(double_first.register(leak(DoubleRed)).is_ok());
+        assert!(double_first.register(leak(AddRed(3))).is_ok());
+        assert_eq!(red_after(&mut double_first), 5 * 2 + 3);
+    }
+
+    #[test]
+    fn full_list_gives_the_processor_back() {
+        let mut post_processors = PostProcessors::<1>::new();
+        assert!(post_processors.is_empty());
+        assert!(post_processors.register(leak(AddRed(1))).is_ok());
+        let refused = match post_processors.register(leak(AddRed(10))) {
+            Err(processor) => processor,
+            Ok(()) => panic!("a full list accepted a processor"),
+        };
+        assert_eq!(post_processors.len(), 1);
+        // Only the registered processor runs, the refused one is still usable
+        assert_eq!(red_after(&mut post_processors), 6);
+        let mut frame = Image::default();
+        refused.process(&mut frame, &DisplaySettings::DEFAULT);
+        assert_eq!(frame[(1, 1)].r, 10);
+    }
+
+    #[test]
+    fn built_in_processors_compose() {
+        static STATUS: PowerStatus = PowerStatus::new();
+        let settings = DisplaySettings {
+            gamma: false,
+            max_total_brightness: Some(64 * 100),
+            row_gains: [127; 8],
+            ..DisplaySettings::DEFAULT
+        };
+        let mut post_processors = PostProcessors::<2>::new();
+        assert!(post_processors.register(leak(ZoneGains)).is_ok());
+        assert!(post_processors
+            .register(leak(PowerCap::new(&STATUS)))
+            .is_ok());
+        // Zone gains halve the frame (64 * 127 per pixel), then the cap limits it
+        let mut frame = Image::new_solid(Color::WHITE);
+        post_processors.process(&mut frame, &settings);
+        assert!(STATUS.last_frame_limited());
+        assert!(settings.total_brightness(&frame) <= 64 * 100);
+
+        let mut expected = Image::new_solid(Color::WHITE);
+        settings.apply_zone_gains(&mut expected);
+        settings.limit_power(&mut expected);
+        assert_eq!(frame, expected);
+    }
+}
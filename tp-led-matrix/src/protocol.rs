@@ -0,0 +1,466 @@
+//! This module decodes the SE203 serial protocol byte by byte
+//!
+//! A frame is a 0xff synchronization byte followed by 192 bytes (R, G, B for each
+//! pixel, row by row). Bytes received outside of a frame are commands: an opcode
+//! followed by a payload whose length depends on the opcode. Commands are only
+//! read once a frame is complete, so a partial frame is never mixed with a command.
+//!
+//! 0xff always starts a frame, even in the middle of a frame or of a command
+//! payload, so payload bytes are limited to 254 like pixel bytes. Whatever the
+//! state of the decoder after line noise, the next complete frame is decoded
+//! correctly.
+
+use crate::config::StartupImage;
+use crate::{Color, Image, Orientation};
+
+/// Synchronization byte starting a frame
+pub const SYNC: u8 = 0xff;
+/// Number of payload bytes in a frame
+pub const FRAME_LEN: usize = 3 * 64;
+
+/// Opcode of the startup image command: kind (0 black, 1 gradient, 2 solid), r, g, b
+pub const CMD_SET_STARTUP: u8 = 0x20;
+
+/// Opcode of the command storing the next received frame into a slot: slot
+pub const CMD_STORE_SLOT: u8 = 0x30;
+/// Opcode of the command displaying a slot: slot
+pub const CMD_SHOW_SLOT: u8 = 0x31;
+
+/// Opcode of the command displaying a solid color: r, g, b
+pub const CMD_SOLID: u8 = 0x40;
+/// Opcode of the command displaying a gradient: r, g, b
+pub const CMD_GRADIENT: u8 = 0x41;
+
+/// Opcode of the capture command: 0 (image after the post processors, zone gains and
+/// power cap included) or 1 (image as sent to the panel, gamma and brightness applied).
+/// The frame is sent back with the escapes described in [capture](crate::capture).
+pub const CMD_CAPTURE: u8 = 0x50;
+/// Opcode of the status command, without payload: the board logs its status, such
+/// as how many frames were scaled down to fit the power cap
+pub const CMD_STATUS: u8 = 0x51;
+
+/// Opcode of the zone gains setting command: the gains of the 8 rows, then of the
+/// 8 columns, out of 255 (at most 254 can be sent)
+pub const CMD_ZONE_GAINS: u8 = 0x60;
+
+/// Opcode of the brightness setting command: brightness out of 255 (at most 254
+/// can be sent)
+pub const CMD_BRIGHTNESS: u8 = 0x70;
+/// Opcode of the gamma correction setting command: 0 (off) or 1 (on)
+pub const CMD_GAMMA: u8 = 0x71;
+/// Opcode of the refresh rate setting command: frames per second
+pub const CMD_REFRESH_RATE: u8 = 0x72;
+/// Opcode of the power cap setting command: cap as a big endian u16, 0 for no cap
+pub const CMD_POWER_CAP: u8 = 0x73;
+/// Opcode of the orientation setting command: 0 identity, 1 to 3 quarter turns
+/// clockwise, 4 horizontal flip, 5 vertical flip, 6 transpose, 7 anti-transpose
+pub const CMD_ORIENTATION: u8 = 0x74;
+/// Opcode of the duty setting command: fraction of the row period during which a
+/// row is on, out of 255. 255 cannot be sent, so 254, the largest payload, keeps
+/// rows on for the whole period, and 0 (rows never on) is rejected.
+pub const CMD_DUTY: u8 = 0x75;
+
+/// Largest payload of a command
+const MAX_PAYLOAD: usize = 16;
+
+/// Commands which can be sent between frames
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ProtocolCommand {
+    /// Replace the startup image and display it
+    SetStartup(StartupImage),
+    /// Store the next received frame into a slot instead of displaying it
+    StoreNextFrame(u8),
+    /// Display the image stored in a slot
+    ShowSlot(u8),
+    /// Display `Image::new_solid(color)`
+    Solid(Color),
+    /// Display `Image::gradient(color)`
+    Gradient(Color),
+    /// Send back the displayed image, after brightness and gamma correction if true
+    Capture(bool),
+    /// Log the status of the board
+    Status,
+    /// Change the brightness setting
+    SetBrightness(u8),
+    /// Enable (1) or disable (0) gamma correction
+    SetGamma(u8),
+    /// Change the refresh rate setting
+    SetRefreshRate(u8),
+    /// Change the power cap setting, 0 removes the cap
+    SetPowerCap(u16),
+    /// Change the row gains and the column gains
+    SetZoneGains([u8; 8], [u8; 8]),
+    /// Change the orientation of the panel
+    SetOrientation(Orientation),
+    /// Change the duty setting, 254 keeps rows on for the whole period
+    SetDuty(u8),
+}
+
+/// Errors detected while decoding
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum DecodeError {
+    /// Opcode received outside of a frame which is not a known command
+    UnknownCommand(u8),
+    /// Command whose payload could not be interpreted
+    InvalidPayload(u8),
+    /// Command whose payload was interrupted by a synchronization byte
+    InterruptedCommand(u8),
+}
+
+/// What a byte completed
+pub enum Decoded {
+    /// The frame buffer now contains a full frame
+    Frame,
+    Command(ProtocolCommand),
+    Error(DecodeError),
+}
+
+#[derive(Clone, Copy)]
+enum State {
+    /// Waiting for a synchronization byte or a command opcode
+    Idle,
+    /// Receiving the pixel byte at the given position
+    Frame(usize),
+    /// Receiving the payload of a command
+    Command { opcode: u8, len: usize, pos: usize },
+}
+
+/// Protocol state machine fed with one byte at a time
+pub struct FrameDecoder {
+    state: State,
+    payload: [u8; MAX_PAYLOAD],
+    errors: u32,
+}
+
+/// Implements functions for frame decoder structure
+impl FrameDecoder {
+    /// Creates a decoder waiting for a frame or a command
+    pub const fn new() -> Self {
+        FrameDecoder {
+            state: State::Idle,
+            payload: [0; MAX_PAYLOAD],
+            errors: 0,
+        }
+    }
+
+    /// Number of errors detected since creation
+    pub fn errors(&self) -> u32 {
+        self.errors
+    }
+
+    /// Name of the current state, for diagnostics
+    pub fn state_name(&self) -> &'static str {
+        match self.state {
+            State::Idle => "idle",
+            State::Frame(_) => "frame",
+            State::Command { .. } => "command",
+        }
+    }
+
+    /// Handles one received byte. Pixel bytes are written into `frame`, which
+    /// contains a full frame when `Decoded::Frame` is returned.
+    pub fn push(&mut self, b: u8, frame: &mut Image) -> Option<Decoded> {
+        match self.state {
+            State::Command { opcode, .. } if b == SYNC => {
+                // A command cut short by line noise must not swallow the next frame
+                self.state = State::Frame(0);
+                self.error(DecodeError::InterruptedCommand(opcode))
+            }
+            State::Command { opcode, len, pos } => {
+                self.payload[pos] = b;
+                if pos + 1 == len {
+                    self.state = State::Idle;
+                    self.command(opcode)
+                } else {
+                    self.state = State::Command {
+                        opcode,
+                        len,
+                        pos: pos + 1,
+                    };
+                    None
+                }
+            }
+            _ if b == SYNC => {
+                if let State::Frame(pos) = self.state {
+                    trace!("protocol: frame restarted after {} bytes", pos);
+                    // Do not leave the pixels of the aborted frame behind
+                    frame.clear();
+                }
+                // Return to position 0 case
+                self.state = State::Frame(0);
+                None
+            }
+            State::Frame(pos) => {
+                // Assigns R G B led for one pixel, pos being below FRAME_LEN the
+                // pixel is always inside the image
+                let pixel = &mut frame[pos / 3];
+                match pos % 3 {
+                    0 => pixel.r = b,
+                    1 => pixel.g = b,
+                    _ => pixel.b = b,
+                }
+
+                if pos + 1 == FRAME_LEN {
+                    trace!("protocol: frame complete");
+                    self.state = State::Idle;
+                    Some(Decoded::Frame)
+                } else {
+                    self.state = State::Frame(pos + 1);
+                    None
+                }
+            }
+            State::Idle => match payload_len(b) {
+                Some(0) => self.command(b),
+                Some(len) => {
+                    self.state = State::Command {
+                        opcode: b,
+                        len,
+                        pos: 0,
+                    };
+                    None
+                }
+                None => self.error(DecodeError::UnknownCommand(b)),
+            },
+        }
+    }
+
+    /// Interprets the payload of a complete command
+    fn command(&mut self, opcode: u8) -> Option<Decoded> {
+        let p = &self.payload;
+        let command = match opcode {
+            CMD_SET_STARTUP => {
+                let color = Color {
+                    r: p[1],
+                    g: p[2],
+                    b: p[3],
+                };
+                match p[0] {
+                    0 => ProtocolCommand::SetStartup(StartupImage::Black),
+                    1 => ProtocolCommand::SetStartup(StartupImage::Gradient(color)),
+                    2 => ProtocolCommand::SetStartup(StartupImage::Solid(color)),
+                    _ => return self.error(DecodeError::InvalidPayload(opcode)),
+                }
+            }
+            CMD_STORE_SLOT => ProtocolCommand::StoreNextFrame(p[0]),
+            CMD_SHOW_SLOT => ProtocolCommand::ShowSlot(p[0]),
+            CMD_SOLID => ProtocolCommand::Solid(Color {
+                r: p[0],
+                g: p[1],
+                b: p[2],
+            }),
+            CMD_GRADIENT => ProtocolCommand::Gradient(Color {
+                r: p[0],
+                g: p[1],
+                b: p[2],
+            }),
+            CMD_CAPTURE => match p[0] {
+                0 => ProtocolCommand::Capture(false),
+                1 => ProtocolCommand::Capture(true),
+                _ => return self.error(DecodeError::InvalidPayload(opcode)),
+            },
+            CMD_STATUS => ProtocolCommand::Status,
+            CMD_ZONE_GAINS => {
+                let (mut rows, mut cols) = ([0; 8], [0; 8]);
+                rows.copy_from_slice(&p[..8]);
+                cols.copy_from_slice(&p[8..16]);
+                ProtocolCommand::SetZoneGains(rows, cols)
+            }
+            CMD_BRIGHTNESS => ProtocolCommand::SetBrightness(p[0]),
+            CMD_GAMMA => ProtocolCommand::SetGamma(p[0]),
+            CMD_REFRESH_RATE => ProtocolCommand::SetRefreshRate(p[0]),
+            CMD_POWER_CAP => ProtocolCommand::SetPowerCap(u16::from_be_bytes([p[0], p[1]])),
+            CMD_ORIENTATION => {
+                let orientation = match p[0] {
+                    0 => Orientation::Identity,
+                    1 => Orientation::Rotate90,
+                    2 => Orientation::Rotate180,
+                    3 => Orientation::Rotate270,
+                    4 => Orientation::FlipHorizontal,
+                    5 => Orientation::FlipVertical,
+                    6 => Orientation::Transpose,
+                    7 => Orientation::AntiTranspose,
+                    _ => return self.error(DecodeError::InvalidPayload(opcode)),
+                };
+                ProtocolCommand::SetOrientation(orientation)
+            }
+            CMD_DUTY => ProtocolCommand::SetDuty(p[0]),
+            _ => return self.error(DecodeError::UnknownCommand(opcode)),
+        };
+        Some(Decoded::Command(command))
+    }
+
+    /// Counts an error and reports it
+    fn error(&mut self, e: DecodeError) -> Option<Decoded> {
+        warn!("protocol: {}", e);
+        self.errors = self.errors.wrapping_add(1);
+        Some(Decoded::Error(e))
+    }
+}
+
+/// Implements default function for frame decoder type objects
+impl Default for FrameDecoder {
+    fn default() -> Self {
+        FrameDecoder::new()
+    }
+}
+
+/// Decoder owning its frame buffer, for users interested in frames only
+pub struct ProtocolDecoder {
+    decoder: FrameDecoder,
+    frame: Image,
+}
+
+/// Implements functions for protocol decoder structure
+impl ProtocolDecoder {
+    /// Creates a decoder waiting for a frame
+    pub fn new() -> Self {
+        ProtocolDecoder {
+            decoder: FrameDecoder::new(),
+            frame: Image::default(),
+        }
+    }
+
+    /// Handles one received byte and returns the frame it completed, if any.
+    /// Commands and errors are dropped.
+    pub fn push(&mut self, byte: u8) -> Option<Image> {
+        match self.decoder.push(byte, &mut self.frame)? {
+            Decoded::Frame => Some(self.frame.clone()),
+            Decoded::Command(_) | Decoded::Error(_) => None,
+        }
+    }
+}
+
+/// Implements default function for protocol decoder type objects
+impl Default for ProtocolDecoder {
+    fn default() -> Self {
+        ProtocolDecoder::new()
+    }
+}
+
+/// Returns the payload length of a command, or None if the opcode is unknown
+fn payload_len(opcode: u8) -> Option<usize> {
+    match opcode {
+        CMD_SET_STARTUP => Some(4),
+        CMD_STORE_SLOT | CMD_SHOW_SLOT => Some(1),
+        CMD_SOLID | CMD_GRADIENT => Some(3),
+        CMD_CAPTURE => Some(1),
+        CMD_STATUS => Some(0),
+        CMD_ZONE_GAINS => Some(16),
+        CMD_BRIGHTNESS | CMD_GAMMA | CMD_REFRESH_RATE | CMD_ORIENTATION | CMD_DUTY => Some(1),
+        CMD_POWER_CAP => Some(2),
+        _ => None,
+    }
+}
+
+/// Event found in a captured byte stream, with the offset of the byte which completed it
+#[cfg(feature = "std")]
+#[derive(Debug, PartialEq)]
+pub enum DecodedEvent {
+    FrameCompleted(usize, Image),
+    Command(usize, ProtocolCommand),
+    Error(usize, DecodeError),
+}
+
+/// Runs a decoder over a whole captured byte stream and lists what it decoded
+#[cfg(feature = "std")]
+pub fn decode_stream(bytes: &[u8]) -> std::vec::Vec<DecodedEvent> {
+    let mut decoder = FrameDecoder::new();
+    let mut frame = Image::default();
+    let mut events = std::vec::Vec::new();
+    for (offset, &b) in bytes.iter().enumerate() {
+        match decoder.push(b, &mut frame) {
+            Some(Decoded::Frame) => {
+                events.push(DecodedEvent::FrameCompleted(offset, frame.clone()))
+            }
+            Some(Decoded::Command(command)) => events.push(DecodedEvent::Command(offset, command)),
+            Some(Decoded::Error(error)) => events.push(DecodedEvent::Error(offset, error)),
+            None => {}
+        }
+    }
+    events
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Image whose bytes all differ from their neighbours and stay below SYNC
+    fn test_image(seed: u8) -> Image {
+        let mut bytes = [0; FRAME_LEN];
+        for (i, b) in bytes.iter_mut().enumerate() {
+            *b = ((i * 7 + seed as usize) % 250) as u8;
+        }
+        Image::from_bytes(&bytes)
+    }
+
+    #[test]
+    fn protocol_decoder_returns_frames_only() {
+        let image = test_image(3);
+        let mut decoder = ProtocolDecoder::new();
+        for &b in [CMD_BRIGHTNESS, 10, SYNC].iter().chain(image.as_ref()) {
+            if let Some(frame) = decoder.push(b) {
+                assert_eq!(frame, image);
+                return;
+            }
+        }
+        panic!("no frame decoded");
+    }
+
+    #[cfg(feature = "std")]
+    #[test]
+    fn decode_stream_golden() {
+        let (a, b) = (test_image(0), test_image(100));
+        let mut stream = std::vec::Vec::new();
+        stream.push(SYNC);
+        stream.extend_from_slice(a.as_ref());
+        stream.extend_from_slice(&[CMD_BRIGHTNESS, 100]);
+        // Frame cut short by a synchronization byte, B is then sent again whole
+        stream.push(SYNC);
+        stream.extend_from_slice(&b.as_ref()[..50]);
+        stream.push(SYNC);
+        stream.extend_from_slice(b.as_ref());
+        stream.extend_from_slice(&[CMD_GAMMA, 0, 0x01, CMD_POWER_CAP, 3]);
+        stream.push(SYNC);
+        stream.extend_from_slice(a.as_ref());
+
+        assert_eq!(
+            decode_stream(&stream),
+            [
+                DecodedEvent::FrameCompleted(192, a.clone()),
+                DecodedEvent::Command(194, ProtocolCommand::SetBrightness(100)),
+                DecodedEvent::FrameCompleted(438, b),
+                DecodedEvent::Command(440, ProtocolCommand::SetGamma(0)),
+                DecodedEvent::Error(441, DecodeError::UnknownCommand(0x01)),
+                DecodedEvent::Error(444, DecodeError::InterruptedCommand(CMD_POWER_CAP)),
+                DecodedEvent::FrameCompleted(636, a),
+            ]
+        );
+    }
+
+    #[test]
+    fn encoded_images_decode_back() {
+        for image in [test_image(0), test_image(42), Image::default()] {
+            let mut decoder = ProtocolDecoder::new();
+            let mut frames = image.to_protocol_bytes().filter_map(|b| decoder.push(b));
+            assert_eq!(frames.next(), Some(image.clone()));
+            assert_eq!(frames.next(), None);
+            assert!(image.to_protocol_bytes().eq(image.encode_se203()));
+        }
+        // Channels at SYNC are sent as SYNC - 1 so they do not restart the frame
+        let white = Image::new_solid(Color::WHITE);
+        let mut decoder = ProtocolDecoder::new();
+        let decoded = white
+            .to_protocol_bytes()
+            .filter_map(|b| decoder.push(b))
+            .last();
+        assert_eq!(
+            decoded,
+            Some(Image::new_solid(Color {
+                r: 254,
+                g: 254,
+                b: 254
+            }))
+        );
+    }
+}
@@ -0,0 +1,66 @@
+//! This module implements a tiny xorshift pseudo-random generator, enough for
+//! visual noise without pulling a random crate into the firmware
+
+/// Xorshift32 generator (Marsaglia), whose sequence only depends on its seed
+#[derive(Clone)]
+pub struct XorShift32 {
+    state: u32,
+}
+
+/// Implements functions for xorshift generator structure
+impl XorShift32 {
+    /// Creates a generator from a seed. 0 would only ever produce 0, so it is
+    /// replaced by another fixed value.
+    pub const fn new(seed: u32) -> Self {
+        XorShift32 {
+            state: if seed == 0 { 0x9e37_79b9 } else { seed },
+        }
+    }
+
+    /// Returns the next 32 bits of the sequence
+    pub fn next_u32(&mut self) -> u32 {
+        let mut x = self.state;
+        x ^= x << 13;
+        x ^= x >> 17;
+        x ^= x << 5;
+        self.state = x;
+        x
+    }
+
+    /// Fills `dest` with pseudo-random bytes
+    pub fn fill_bytes(&mut self, dest: &mut [u8]) {
+        for chunk in dest.chunks_mut(4) {
+            let bytes = self.next_u32().to_le_bytes();
+            chunk.copy_from_slice(&bytes[..chunk.len()]);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn sequence_depends_only_on_the_seed() {
+        // First output of Marsaglia's xorshift32 from state 1
+        assert_eq!(XorShift32::new(1).next_u32(), 270369);
+        let (mut a, mut b) = (XorShift32::new(1234), XorShift32::new(1234));
+        for _ in 0..100 {
+            assert_eq!(a.next_u32(), b.next_u32());
+        }
+        // Seed 0 does not get stuck at 0
+        let mut zero = XorShift32::new(0);
+        assert!((0..10).all(|_| zero.next_u32() != 0));
+    }
+
+    #[test]
+    fn fill_bytes_handles_partial_chunks() {
+        let mut bytes = [0; 7];
+        XorShift32::new(5).fill_bytes(&mut bytes);
+        let mut rng = XorShift32::new(5);
+        let first = rng.next_u32().to_le_bytes();
+        let second = rng.next_u32().to_le_bytes();
+        assert_eq!(&bytes[..4], &first);
+        assert_eq!(&bytes[4..], &second[..3]);
+    }
+}
@@ -0,0 +1,264 @@
+//! This module turns images into the lines sent to the panel, independently of the
+//! hardware so that the display logic can also run on the host
+
+use crate::settings::DisplaySettings;
+use crate::{Color, Image, Orientation, ScanMode};
+
+/// Errors returned by the matrix driver and its simulator
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum MatrixError {
+    /// Row number outside of 1 to 8, the given number
+    InvalidRow(usize),
+    /// Bit plane above 7, the given plane
+    InvalidBitPlane(u8),
+}
+
+/// Destination of the lines of a frame, such as the LED matrix
+pub trait RowSink {
+    /// Switches the previous line off, sends the pixels of line `line` (from 1 to 8)
+    /// as they are and switches the line on
+    fn send_row_raw(&mut self, line: usize, pixels: &[Color]);
+
+    /// Orientation in which the images are laid out on the panel
+    fn orientation(&self) -> Orientation {
+        Orientation::Identity
+    }
+}
+
+/// Number of bit planes of binary code modulation, one per bit of a channel
+pub const BCM_PLANES: u8 = 8;
+
+/// Sends line `line` of `image`, laid out in the orientation of `sink`, to `sink`
+/// after brightness and gamma correction according to `settings`
+pub fn scan_line<S: RowSink>(
+    sink: &mut S,
+    image: &Image,
+    mode: ScanMode,
+    line: usize,
+    settings: &DisplaySettings,
+) {
+    let pixels = output_line(image, sink.orientation(), mode, line, settings);
+    sink.send_row_raw(line, &pixels);
+}
+
+/// Returns the pixels of line `line` of `image` as they are sent to the panel: laid
+/// out with orientation `o`, then after brightness and gamma correction according
+/// to `settings`
+pub fn output_line(
+    image: &Image,
+    o: Orientation,
+    mode: ScanMode,
+    line: usize,
+    settings: &DisplaySettings,
+) -> [Color; 8] {
+    oriented_line(image, o, mode, line).map(|pixel| settings.output_color(pixel))
+}
+
+/// Returns the color sent during bit plane `bit_plane` (0 for the LSB to 7) of
+/// binary code modulation: every channel whose bit is set is fully on, the others
+/// are off. Planes above 7 are all off.
+pub fn bcm_plane(color: Color, bit_plane: u8) -> Color {
+    let plane = |v: u8| {
+        if bit_plane < BCM_PLANES && v & (1 << bit_plane) != 0 {
+            255
+        } else {
+            0
+        }
+    };
+    Color {
+        r: plane(color.r),
+        g: plane(color.g),
+        b: plane(color.b),
+    }
+}
+
+/// Returns the pixels of line `line` of `image` laid out with orientation `o`,
+/// without building the oriented image
+pub fn oriented_line(image: &Image, o: Orientation, mode: ScanMode, line: usize) -> [Color; 8] {
+    let mut pixels = [Color::default(); 8];
+    for (pos, pixel) in (1..=8).zip(pixels.iter_mut()) {
+        let (row, col) = match mode {
+            ScanMode::Rows => (line, pos),
+            ScanMode::Columns => (pos, line),
+        };
+        *pixel = image[o.source(row, col)];
+    }
+    pixels
+}
+
+/// Sends every line of `image` to `sink`, like the display task does during a frame
+pub fn scan_frame<S: RowSink>(
+    sink: &mut S,
+    image: &Image,
+    mode: ScanMode,
+    settings: &DisplaySettings,
+) {
+    for line in 1..=8 {
+        scan_line(sink, image, mode, line, settings);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn bcm_planes_rebuild_the_channels() {
+        let pixels = [
+            Color { r: 0, g: 1, b: 2 },
+            Color {
+                r: 3,
+                g: 128,
+                b: 255,
+            },
+            Color {
+                r: 0x5a,
+                g: 0xa5,
+                b: 0x0f,
+            },
+        ];
+        for pixel in pixels {
+            let mut rebuilt = [0u32; 3];
+            for plane in 0..BCM_PLANES {
+                let sent = bcm_plane(pixel, plane);
+                for (sum, channel) in rebuilt.iter_mut().zip([sent.r, sent.g, sent.b]) {
+                    assert!(channel == 0 || channel == 255);
+                    // Plane n is displayed for 2^n units
+                    *sum += (channel as u32 / 255) << plane;
+                }
+            }
+            assert_eq!(rebuilt, [pixel.r as u32, pixel.g as u32, pixel.b as u32]);
+        }
+    }
+
+    #[test]
+    fn bcm_plane_bitstream() {
+        // Channels 0x5a = 0b0101_1010, 0xa5 = 0b1010_0101 and 0x0f = 0b0000_1111
+        let pixel = Color {
+            r: 0x5a,
+            g: 0xa5,
+            b: 0x0f,
+        };
+        let planes = (0..BCM_PLANES).map(|plane| bcm_plane(pixel, plane));
+        let expected = [
+            (0, 255, 255),
+            (255, 0, 255),
+            (0, 255, 255),
+            (255, 0, 255),
+            (255, 0, 0),
+            (0, 255, 0),
+            (255, 0, 0),
+            (0, 255, 0),
+        ];
+        for (sent, (r, g, b)) in planes.zip(expected) {
+            assert_eq!(sent, Color { r, g, b });
+        }
+        assert_eq!(bcm_plane(Color::WHITE, BCM_PLANES), Color::BLACK);
+    }
+
+    #[test]
+    fn output_line_applies_the_settings() {
+        let image = Image::gradient(Color::WHITE);
+        let settings = DisplaySettings {
+            brightness: 100,
+            ..DisplaySettings::DEFAULT
+        };
+        let line = output_line(&image, Orientation::Identity, ScanMode::Rows, 3, &settings);
+        for (col, &pixel) in (1..=8).zip(line.iter()) {
+            assert_eq!(pixel, settings.output_color(image[(3, col)]));
+        }
+    }
+}
+
+#[cfg(all(test, feature = "sim"))]
+mod sim_tests {
+    use super::*;
+    use crate::protocol::{Decoded, FrameDecoder, CMD_BRIGHTNESS, CMD_GAMMA};
+    use crate::simulator::MockMatrix;
+    use core::mem::MaybeUninit;
+    use heapless::pool::{Box, Node, Pool};
+
+    /// Frames drawn with the image API, each one preceded by the setting commands
+    /// the host sends before it
+    fn host_frames() -> [(&'static [u8], Image); 3] {
+        let mut diagonal = Image::new_solid(Color {
+            r: 10,
+            g: 20,
+            b: 30,
+        });
+        for i in 1..=8 {
+            diagonal[(i, i)] = Color {
+                r: 200,
+                g: 100,
+                b: 50,
+            };
+        }
+        [
+            (&[], Image::gradient(Color::BLUE)),
+            (
+                &[CMD_BRIGHTNESS, 100],
+                Image::new_solid(Color {
+                    r: 250,
+                    g: 128,
+                    b: 3,
+                }),
+            ),
+            (&[CMD_GAMMA, 0, CMD_BRIGHTNESS, 254], diagonal),
+        ]
+    }
+
+    /// Receives the host stream and displays every frame like main.rs does: the
+    /// decoder fills a pooled reception buffer which is swapped into next_image,
+    /// then at the start of a frame the display task swaps next_image with the
+    /// current image, frees the old one and scans the lines
+    #[test]
+    fn host_frames_reach_the_mock_matrix() {
+        let memory: &'static mut MaybeUninit<[Node<Image>; 3]> =
+            std::boxed::Box::leak(std::boxed::Box::new(MaybeUninit::uninit()));
+        let pool: Pool<Image> = Pool::new();
+        assert_eq!(pool.grow_exact(memory), 3);
+        let mut rx_image: Box<Image> = pool.alloc().unwrap().init(Image::default());
+        let mut current_image: Box<Image> = pool.alloc().unwrap().init(Image::default());
+        let mut next_image: Option<Box<Image>> = None;
+
+        let mut decoder = FrameDecoder::new();
+        let mut settings = DisplaySettings::default();
+        let mut matrix = MockMatrix::new();
+
+        for (commands, image) in host_frames() {
+            let stream = commands.iter().copied().chain(image.encode_se203());
+            for b in stream {
+                match decoder.push(b, &mut rx_image) {
+                    Some(Decoded::Frame) => {
+                        let mut future_image = match next_image.take() {
+                            Some(image_nt_displayed) => image_nt_displayed,
+                            None => pool.alloc().unwrap().init(Image::default()),
+                        };
+                        core::mem::swap(&mut future_image, &mut rx_image);
+                        next_image = Some(future_image);
+                    }
+                    Some(Decoded::Command(cmd)) => assert!(settings.apply_command(cmd).is_ok()),
+                    Some(Decoded::Error(_)) => panic!("decoding error"),
+                    None => {}
+                }
+            }
+
+            // Display task, at the start of the frame
+            let frame_settings = settings;
+            let mut image_displayed = next_image.take().expect("frame not published");
+            core::mem::swap(&mut current_image, &mut image_displayed);
+            pool.free(image_displayed);
+            scan_frame(&mut matrix, &current_image, ScanMode::Rows, &frame_settings);
+
+            let mut expected = image.clone();
+            for row in 1..=8 {
+                for col in 1..=8 {
+                    expected[(row, col)] = frame_settings.output_color(image[(row, col)]);
+                }
+            }
+            assert_eq!(matrix.frame().as_ref(), expected.as_ref());
+            assert_eq!(matrix.active_row(), Some(8));
+        }
+    }
+}
@@ -0,0 +1,328 @@
+//! This module gathers display settings which can change at runtime
+
+use crate::protocol::ProtocolCommand;
+use crate::{Color, Image, Orientation};
+
+/// Lowest accepted refresh rate, below it the panel visibly flickers
+pub const MIN_REFRESH_RATE: u8 = 30;
+/// Highest accepted refresh rate, above it rows are not sent in time
+pub const MAX_REFRESH_RATE: u8 = 200;
+/// Lowest refresh rate used when the duty is below 255, see
+/// [DisplaySettings::effective_refresh_rate]
+pub const MIN_DUTY_REFRESH_RATE: u8 = 200;
+/// Largest duty payload, which keeps rows on for the whole period since 255 cannot
+/// be sent
+const FULL_DUTY_PAYLOAD: u8 = 254;
+
+/// Errors returned when applying a command to the settings
+#[derive(Clone, Copy)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum SettingsError {
+    /// The command does not change a setting
+    NotASetting,
+    /// The payload of the command is not a valid value for the setting
+    InvalidValue,
+}
+
+/// Settings shared by the tasks, copied by the display task at every frame
+/// boundary so it always works with a coherent set of values
+#[derive(Clone, Copy)]
+pub struct DisplaySettings {
+    /// Scale applied to every channel, 255 is full brightness
+    pub brightness: u8,
+    /// Whether gamma correction is applied when sending pixels
+    pub gamma: bool,
+    /// Number of frames displayed per second
+    pub refresh_rate: u8,
+    /// Largest sum of all channel values of a frame as sent to the panel (see
+    /// [output_color](DisplaySettings::output_color)), or None for no limit
+    pub max_total_brightness: Option<u32>,
+    /// Gain of each row (from top to bottom), 255 leaves the row unchanged
+    pub row_gains: [u8; 8],
+    /// Gain of each column (from left to right), 255 leaves the column unchanged
+    pub col_gains: [u8; 8],
+    /// How the images are laid out on the panel
+    pub orientation: Orientation,
+    /// Fraction of the row period during which a row is on, out of 255
+    pub duty: u8,
+    /// Whether rows are displayed with binary code modulation (see
+    /// `Matrix::send_row_bcm`) instead of the DM163 PWM, the duty being ignored
+    pub bcm: bool,
+}
+
+/// Implements functions for display settings structure
+impl DisplaySettings {
+    /// Default settings: full brightness, gamma correction, 60 frames per second, no
+    /// power cap, no zone correction, rows on during their whole period and no
+    /// binary code modulation
+    pub const DEFAULT: DisplaySettings = DisplaySettings {
+        brightness: 255,
+        gamma: true,
+        refresh_rate: 60,
+        max_total_brightness: None,
+        row_gains: [255; 8],
+        col_gains: [255; 8],
+        orientation: Orientation::Identity,
+        duty: 255,
+        bcm: false,
+    };
+
+    /// Applies a setting command. Invalid values are rejected and leave the
+    /// settings unchanged.
+    pub fn apply_command(&mut self, cmd: ProtocolCommand) -> Result<(), SettingsError> {
+        match cmd {
+            ProtocolCommand::SetBrightness(brightness) => self.brightness = brightness,
+            ProtocolCommand::SetGamma(0) => self.gamma = false,
+            ProtocolCommand::SetGamma(1) => self.gamma = true,
+            ProtocolCommand::SetRefreshRate(rate)
+                if (MIN_REFRESH_RATE..=MAX_REFRESH_RATE).contains(&rate) =>
+            {
+                self.refresh_rate = rate
+            }
+            ProtocolCommand::SetPowerCap(0) => self.max_total_brightness = None,
+            ProtocolCommand::SetPowerCap(cap) => self.max_total_brightness = Some(cap as u32),
+            ProtocolCommand::SetZoneGains(rows, cols) => {
+                self.row_gains = rows;
+                self.col_gains = cols;
+            }
+            ProtocolCommand::SetOrientation(orientation) => self.orientation = orientation,
+            ProtocolCommand::SetDuty(FULL_DUTY_PAYLOAD..) => self.duty = 255,
+            ProtocolCommand::SetDuty(duty) if duty > 0 => self.duty = duty,
+            ProtocolCommand::SetGamma(_)
+            | ProtocolCommand::SetRefreshRate(_)
+            | ProtocolCommand::SetDuty(_) => return Err(SettingsError::InvalidValue),
+            _ => return Err(SettingsError::NotASetting),
+        }
+        Ok(())
+    }
+
+    /// Returns the number of frames displayed per second. Below full duty, a row is
+    /// lit for `duty / 255` of its period `1 / (8 * rate)`, then dark until the next
+    /// frame: every LED flashes once per frame, a short flash followed by a long
+    /// dark gap which the eye notices at low rates. The rate is then raised to at
+    /// least [MIN_DUTY_REFRESH_RATE], above which the flashes blend. The duty is
+    /// ignored with binary code modulation.
+    pub fn effective_refresh_rate(&self) -> u8 {
+        if self.duty < 255 && !self.bcm {
+            self.refresh_rate.max(MIN_DUTY_REFRESH_RATE)
+        } else {
+            self.refresh_rate
+        }
+    }
+
+    /// Applies the brightness setting to a color
+    pub fn adjust(&self, color: Color) -> Color {
+        color * self.brightness
+    }
+
+    /// Returns a color as sent to the panel: after brightness, then gamma
+    /// correction if it is enabled
+    pub fn output_color(&self, color: Color) -> Color {
+        let color = self.adjust(color);
+        if self.gamma {
+            color.gamma_correct()
+        } else {
+            color
+        }
+    }
+
+    /// Applies the row and column gains to a frame
+    pub fn apply_zone_gains(&self, image: &mut Image) {
+        apply_zone_gains(image, &self.row_gains, &self.col_gains);
+    }
+
+    /// Returns the sum of all channel values of a frame as sent to the panel, which
+    /// is proportional to the current drawn by the panel
+    pub fn total_brightness(&self, image: &Image) -> u32 {
+        total_brightness(image, |color| self.output_color(color))
+    }
+
+    /// Scales the frame down if it exceeds the power cap, returns true if it was limited
+    pub fn limit_power(&self, image: &mut Image) -> bool {
+        match self.max_total_brightness {
+            Some(max) => limit_power(image, max, |color| self.output_color(color)),
+            None => false,
+        }
+    }
+}
+
+/// Default settings are [DisplaySettings::DEFAULT]
+impl Default for DisplaySettings {
+    fn default() -> Self {
+        DisplaySettings::DEFAULT
+    }
+}
+
+/// Returns the sum of all channel values of an image once every pixel went through
+/// `output`, the transformation applied when sending it to the panel
+pub fn total_brightness(image: &Image, output: impl Fn(Color) -> Color) -> u32 {
+    image
+        .iter()
+        .map(|&pixel| {
+            let color = output(pixel);
+            color.r as u32 + color.g as u32 + color.b as u32
+        })
+        .sum()
+}
+
+/// Scales every pixel by the gain of its row times the gain of its column, each
+/// gain being out of 255, with rounding
+pub fn apply_zone_gains(image: &mut Image, row_gains: &[u8; 8], col_gains: &[u8; 8]) {
+    for (row, &row_gain) in row_gains.iter().enumerate() {
+        for (col, &col_gain) in col_gains.iter().enumerate() {
+            let gain = row_gain as u32 * col_gain as u32;
+            let scale = |c: u8| ((c as u32 * gain + 255 * 255 / 2) / (255 * 255)) as u8;
+            let pixel = &mut image[(row + 1, col + 1)];
+            *pixel = Color {
+                r: scale(pixel.r),
+                g: scale(pixel.g),
+                b: scale(pixel.b),
+            };
+        }
+    }
+}
+
+/// Scales the image down with the largest factor keeping its total brightness,
+/// measured after `output`, under `max_total`. Returns true if the image had to
+/// be scaled.
+pub fn limit_power(image: &mut Image, max_total: u32, output: impl Fn(Color) -> Color) -> bool {
+    if total_brightness(image, &output) <= max_total {
+        return false;
+    }
+    // Binary search of the largest scale fitting the cap, scale 0 (black) always fits
+    let (mut fits, mut too_bright) = (0u16, 255u16);
+    while too_bright - fits > 1 {
+        let mid = (fits + too_bright) / 2;
+        if total_brightness(&image.dimmed(mid as u8), &output) <= max_total {
+            fits = mid;
+        } else {
+            too_bright = mid;
+        }
+    }
+    *image = image.dimmed(fits as u8);
+    trace!(
+        "settings: frame scaled by {}/255 to fit the power cap",
+        fits
+    );
+    true
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Settings sending colors unchanged, so totals are plain channel sums
+    const RAW: DisplaySettings = DisplaySettings {
+        gamma: false,
+        ..DisplaySettings::DEFAULT
+    };
+
+    #[test]
+    fn duty_payloads() {
+        let mut settings = DisplaySettings::DEFAULT;
+        assert!(settings
+            .apply_command(ProtocolCommand::SetDuty(100))
+            .is_ok());
+        assert_eq!(settings.duty, 100);
+        assert!(matches!(
+            settings.apply_command(ProtocolCommand::SetDuty(0)),
+            Err(SettingsError::InvalidValue)
+        ));
+        assert_eq!(settings.duty, 100);
+        assert!(settings
+            .apply_command(ProtocolCommand::SetDuty(254))
+            .is_ok());
+        assert_eq!(settings.duty, 255);
+    }
+
+    #[test]
+    fn dimmed_duty_raises_the_refresh_rate() {
+        let settings = DisplaySettings {
+            refresh_rate: 60,
+            ..DisplaySettings::DEFAULT
+        };
+        assert_eq!(settings.effective_refresh_rate(), 60);
+        let dimmed = DisplaySettings {
+            duty: 128,
+            ..settings
+        };
+        assert_eq!(dimmed.effective_refresh_rate(), MIN_DUTY_REFRESH_RATE);
+    }
+
+    #[test]
+    fn total_follows_the_output_settings() {
+        let image = Image::new_solid(Color {
+            r: 10,
+            g: 20,
+            b: 30,
+        });
+        assert_eq!(RAW.total_brightness(&image), 64 * 60);
+        let dimmed = DisplaySettings {
+            brightness: 127,
+            ..RAW
+        };
+        assert!(dimmed.total_brightness(&image) < 64 * 60);
+        let corrected = DisplaySettings::DEFAULT;
+        let expected = corrected.output_color(Color {
+            r: 10,
+            g: 20,
+            b: 30,
+        });
+        assert_eq!(
+            corrected.total_brightness(&image),
+            64 * (expected.r as u32 + expected.g as u32 + expected.b as u32)
+        );
+    }
+
+    #[test]
+    fn frame_exactly_at_the_cap_is_kept() {
+        let mut image = Image::new_solid(Color {
+            r: 10,
+            g: 20,
+            b: 30,
+        });
+        let settings = DisplaySettings {
+            max_total_brightness: Some(64 * 60),
+            ..RAW
+        };
+        assert!(!settings.limit_power(&mut image));
+        assert_eq!(
+            image,
+            Image::new_solid(Color {
+                r: 10,
+                g: 20,
+                b: 30
+            })
+        );
+    }
+
+    #[test]
+    fn frame_far_over_the_cap_is_scaled_to_fit() {
+        for settings in [RAW, DisplaySettings::DEFAULT] {
+            let settings = DisplaySettings {
+                max_total_brightness: Some(1000),
+                ..settings
+            };
+            let mut image = Image::new_solid(Color::WHITE);
+            assert!(settings.limit_power(&mut image));
+            assert!(settings.total_brightness(&image) <= 1000);
+            assert!(image[(1, 1)].r > 0);
+            // The next brighter scale would exceed the cap
+            let factor = image[(1, 1)].r;
+            let brighter = Image::new_solid(Color::WHITE).dimmed(factor + 1);
+            assert!(settings.total_brightness(&brighter) > 1000);
+        }
+    }
+
+    #[test]
+    fn black_frame_is_never_limited() {
+        let mut image = Image::default();
+        let settings = DisplaySettings {
+            max_total_brightness: Some(0),
+            ..DisplaySettings::DEFAULT
+        };
+        assert_eq!(settings.total_brightness(&image), 0);
+        assert!(!settings.limit_power(&mut image));
+        assert_eq!(image, Image::default());
+    }
+}
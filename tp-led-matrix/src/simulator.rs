@@ -0,0 +1,202 @@
+//! This module simulates the LED matrix on the host, so that display code can be
+//! tested and watched in a terminal without flashing the board
+
+use crate::scan::{oriented_line, MatrixError, RowSink};
+use crate::{Color, Image, Orientation, RowCursor, ScanMode};
+use std::fmt::Write;
+use std::string::String;
+use std::vec::Vec;
+
+/// Stand-in for [Matrix](crate::matrix::Matrix) recording what it is sent
+pub struct MockMatrix {
+    scan: ScanMode,
+    sent: Vec<(usize, Vec<u8>)>,
+    frame: Image,
+    active_row: Option<usize>,
+    orientation: Orientation,
+}
+
+/// Implements functions for mock matrix structure
+impl MockMatrix {
+    /// Creates a simulated panel scanned by rows, all LEDs off
+    pub fn new() -> Self {
+        MockMatrix {
+            scan: ScanMode::Rows,
+            sent: Vec::new(),
+            frame: Image::default(),
+            active_row: None,
+            orientation: Orientation::Identity,
+        }
+    }
+
+    /// Changes how the row outputs are wired, like the scan mode of the matrix configuration
+    pub fn set_scan(&mut self, scan: ScanMode) {
+        self.scan = scan;
+    }
+
+    /// Same as [Matrix::set_orientation](crate::matrix::Matrix::set_orientation)
+    pub fn set_orientation(&mut self, o: Orientation) {
+        self.orientation = o;
+    }
+
+    /// Same as [Matrix::send_row](crate::matrix::Matrix::send_row)
+    pub fn send_row(&mut self, row: usize, pixels: &[Color]) -> Result<(), MatrixError> {
+        self.send_row_with(row, pixels, Color::gamma_correct)
+    }
+
+    /// Same as [Matrix::send_row_raw](crate::matrix::Matrix::send_row_raw)
+    pub fn send_row_raw(&mut self, row: usize, pixels: &[Color]) -> Result<(), MatrixError> {
+        self.send_row_with(row, pixels, |pixel| *pixel)
+    }
+
+    /// Records the bytes of a row in the order the matrix shifts them out: the
+    /// pixels from last to first, each in B, G, R order
+    fn send_row_with(
+        &mut self,
+        row: usize,
+        pixels: &[Color],
+        correct: impl Fn(&Color) -> Color,
+    ) -> Result<(), MatrixError> {
+        if !(1..=8).contains(&row) {
+            return Err(MatrixError::InvalidRow(row));
+        }
+        let mut bytes = Vec::with_capacity(3 * pixels.len());
+        for (i, pixel) in pixels.iter().map(correct).enumerate().rev() {
+            bytes.extend_from_slice(&[pixel.b, pixel.g, pixel.r]);
+            if let Some(lit) = self.lit_pixel(row, i + 1) {
+                *lit = pixel;
+            }
+        }
+        self.sent.push((row, bytes));
+        self.active_row = Some(row);
+        Ok(())
+    }
+
+    /// Returns the pixel lit by position `pos` (from 1) of line `line`, if any
+    fn lit_pixel(&mut self, line: usize, pos: usize) -> Option<&mut Color> {
+        match self.scan {
+            ScanMode::Rows => self.frame.get_mut(line, pos),
+            ScanMode::Columns => self.frame.get_mut(pos, line),
+        }
+    }
+
+    /// Same as [Matrix::display_image](crate::matrix::Matrix::display_image)
+    pub fn display_image(&mut self, image: &Image) -> Result<(), MatrixError> {
+        let mut cursor = RowCursor::new();
+        for _ in 0..8 {
+            let line = cursor.next_line();
+            self.send_row(
+                line,
+                &oriented_line(image, self.orientation, self.scan, line),
+            )?;
+        }
+        Ok(())
+    }
+
+    /// Rows sent so far with their bytes, in the order they were sent
+    pub fn sent(&self) -> &[(usize, Vec<u8>)] {
+        &self.sent
+    }
+
+    /// Forgets the rows sent so far
+    pub fn clear_sent(&mut self) {
+        self.sent.clear();
+    }
+
+    /// Row which is switched on, None before the first row is sent
+    pub fn active_row(&self) -> Option<usize> {
+        self.active_row
+    }
+
+    /// Last values sent to every LED, as they appear on the panel
+    pub fn frame(&self) -> &Image {
+        &self.frame
+    }
+
+    /// Draws the panel with ANSI true color backgrounds, two spaces per pixel and
+    /// one line per row
+    pub fn render_ansi(&self) -> String {
+        let mut out = String::new();
+        for row in self.frame.rows() {
+            for pixel in row {
+                // Writing to a String cannot fail
+                let _ = write!(out, "\x1b[48;2;{};{};{}m  ", pixel.r, pixel.g, pixel.b);
+            }
+            out.push_str("\x1b[0m\n");
+        }
+        out
+    }
+}
+
+/// Implements default function for mock matrix type objects
+impl Default for MockMatrix {
+    fn default() -> Self {
+        MockMatrix::new()
+    }
+}
+
+/// The simulated panel displays the lines scanned by the display logic
+impl RowSink for MockMatrix {
+    fn send_row_raw(&mut self, line: usize, pixels: &[Color]) {
+        if let Err(e) = MockMatrix::send_row_raw(self, line, pixels) {
+            warn!("simulator: {}", e);
+        }
+    }
+
+    fn orientation(&self) -> Orientation {
+        self.orientation
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Image whose pixels all differ, red giving the row and green the column
+    fn test_image() -> Image {
+        let mut image = Image::default();
+        for row in 1..=8 {
+            for col in 1..=8 {
+                image[(row, col)] = Color {
+                    r: 30 * row as u8,
+                    g: 30 * col as u8,
+                    b: 100,
+                };
+            }
+        }
+        image
+    }
+
+    #[test]
+    fn display_image_fills_the_framebuffer() {
+        let image = test_image();
+        let mut matrix = MockMatrix::new();
+        matrix.display_image(&image).unwrap();
+
+        assert_eq!(*matrix.frame(), image.map(|pixel| pixel.gamma_correct()));
+        let mut cursor = RowCursor::new();
+        let expected: Vec<usize> = (0..8).map(|_| cursor.next_line()).collect();
+        let rows: Vec<usize> = matrix.sent().iter().map(|(row, _)| *row).collect();
+        assert_eq!(rows, expected);
+        assert_eq!(matrix.active_row(), expected.last().copied());
+        // Bytes are shifted out from the last pixel to the first one, in B, G, R order
+        let last = image[(expected[0], 8)].gamma_correct();
+        assert_eq!(matrix.sent()[0].1[..3], [last.b, last.g, last.r]);
+    }
+
+    #[test]
+    fn display_image_follows_scan_and_orientation() {
+        let image = test_image();
+        let mut matrix = MockMatrix::new();
+        matrix.set_scan(ScanMode::Columns);
+        matrix.set_orientation(Orientation::Rotate270);
+        matrix.display_image(&image).unwrap();
+
+        for row in 1..=8 {
+            for col in 1..=8 {
+                let expected = image[Orientation::Rotate270.source(row, col)].gamma_correct();
+                assert_eq!(matrix.frame()[(row, col)], expected);
+            }
+        }
+    }
+}
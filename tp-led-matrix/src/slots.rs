@@ -0,0 +1,86 @@
+//! This module stores a few images which can be displayed on request
+
+use crate::Image;
+
+/// Number of image slots
+pub const SLOT_COUNT: usize = 4;
+
+/// Errors returned when accessing a slot
+#[derive(Clone, Copy)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum SlotError {
+    /// The slot number is not smaller than SLOT_COUNT
+    InvalidSlot(u8),
+    /// Nothing was stored in this slot yet
+    Empty(u8),
+}
+
+/// Fixed set of images kept until overwritten
+pub struct ImageSlots {
+    images: [Image; SLOT_COUNT],
+    occupied: [bool; SLOT_COUNT],
+    errors: u32,
+}
+
+/// Implements functions for image slots structure
+impl ImageSlots {
+    /// Creates empty slots
+    pub fn new() -> Self {
+        ImageSlots {
+            images: Default::default(),
+            occupied: [false; SLOT_COUNT],
+            errors: 0,
+        }
+    }
+
+    /// Number of failed accesses since creation
+    pub fn errors(&self) -> u32 {
+        self.errors
+    }
+
+    /// Returns true if an image was stored in the slot
+    pub fn is_occupied(&self, slot: u8) -> bool {
+        self.occupied.get(slot as usize).copied().unwrap_or(false)
+    }
+
+    /// Copies an image into a slot, replacing its previous content
+    pub fn store(&mut self, slot: u8, image: &Image) -> Result<(), SlotError> {
+        let index = self.check(slot)?;
+        self.images[index].clone_from(image);
+        self.occupied[index] = true;
+        trace!("slots: image stored in slot {}", slot);
+        Ok(())
+    }
+
+    /// Returns the image stored in a slot
+    pub fn get(&mut self, slot: u8) -> Result<&Image, SlotError> {
+        let index = self.check(slot)?;
+        if !self.occupied[index] {
+            return Err(self.error(SlotError::Empty(slot)));
+        }
+        Ok(&self.images[index])
+    }
+
+    /// Returns the array index of a slot number
+    fn check(&mut self, slot: u8) -> Result<usize, SlotError> {
+        if (slot as usize) < SLOT_COUNT {
+            Ok(slot as usize)
+        } else {
+            Err(self.error(SlotError::InvalidSlot(slot)))
+        }
+    }
+
+    /// Counts an error and returns it
+    fn error(&mut self, e: SlotError) -> SlotError {
+        warn!("slots: {}", e);
+        self.errors = self.errors.wrapping_add(1);
+        e
+    }
+}
+
+/// Implements default function for image slots type objects
+impl Default for ImageSlots {
+    fn default() -> Self {
+        ImageSlots::new()
+    }
+}
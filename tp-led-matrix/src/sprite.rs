@@ -0,0 +1,419 @@
+//! This module reads sprites packed in a sprite sheet, typically stored in flash
+//!
+//! A sheet is the concatenation of `count` sprites of the same size. Each sprite is
+//! `width * height` pixels stored row by row, from top to bottom and from left to
+//! right, each pixel being 3 bytes R, G, B like in a frame. Sprite `i` starts at
+//! byte `i * width * height * 3`. Such a sheet can be generated on the host and
+//! embedded with `include_bytes!`.
+//!
+//! Small sprites can also be owned with [Sprite], which holds its pixels and a
+//! transparent color and can be blitted at any position, even partially outside.
+
+use crate::animation::Animation;
+use crate::{Color, Image};
+
+/// Errors returned when building a sprite sheet
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum SpriteError {
+    /// The sprites have no pixel
+    EmptySprite,
+    /// The data is shorter than `count` sprites
+    SheetTooShort,
+    /// The sprite has more than 8 rows or columns
+    TooLarge,
+    /// The rows of the sprite do not all have the same length
+    RaggedRows,
+}
+
+/// Sprites of the same size packed one after another
+#[derive(Clone, Copy)]
+pub struct SpriteSheet<'a> {
+    data: &'a [u8],
+    width: usize,
+    height: usize,
+    count: usize,
+}
+
+/// Sprite borrowed from a sprite sheet, its pixels are not copied
+#[derive(Clone, Copy)]
+pub struct SpriteRef<'a> {
+    data: &'a [u8],
+    width: usize,
+    height: usize,
+}
+
+/// Implements functions for sprite sheet structure
+impl<'a> SpriteSheet<'a> {
+    /// Creates a sheet of `count` sprites of `width * height` pixels. Bytes after
+    /// the last sprite are ignored.
+    pub fn new(
+        data: &'a [u8],
+        width: usize,
+        height: usize,
+        count: usize,
+    ) -> Result<Self, SpriteError> {
+        if width == 0 || height == 0 {
+            return Err(SpriteError::EmptySprite);
+        }
+        // A sheet larger than the address space cannot fit in the data either
+        let fits = width
+            .checked_mul(height)
+            .and_then(|pixels| pixels.checked_mul(3))
+            .and_then(|size| size.checked_mul(count))
+            .is_some_and(|len| len <= data.len());
+        if !fits {
+            return Err(SpriteError::SheetTooShort);
+        }
+        Ok(SpriteSheet {
+            data,
+            width,
+            height,
+            count,
+        })
+    }
+
+    /// Number of sprites of the sheet
+    pub fn len(&self) -> usize {
+        self.count
+    }
+
+    /// Returns true if the sheet has no sprite
+    pub fn is_empty(&self) -> bool {
+        self.count == 0
+    }
+
+    /// Returns sprite `index`, or None if the sheet has fewer sprites
+    pub fn get(&self, index: usize) -> Option<SpriteRef<'a>> {
+        if index >= self.count {
+            return None;
+        }
+        let size = self.width * self.height * 3;
+        Some(SpriteRef {
+            data: &self.data[index * size..(index + 1) * size],
+            width: self.width,
+            height: self.height,
+        })
+    }
+}
+
+/// Implements functions for sprite reference structure
+impl<'a> SpriteRef<'a> {
+    /// Width of the sprite in pixels
+    pub fn width(&self) -> usize {
+        self.width
+    }
+
+    /// Height of the sprite in pixels
+    pub fn height(&self) -> usize {
+        self.height
+    }
+
+    /// Returns the pixel of a row and a column, both starting at 1 like [Image] indexing
+    pub fn pixel(&self, row: usize, col: usize) -> Color {
+        let pos = ((row - 1) * self.width + col - 1) * 3;
+        Color {
+            r: self.data[pos],
+            g: self.data[pos + 1],
+            b: self.data[pos + 2],
+        }
+    }
+
+    /// Copies the sprite into `dst` with its top left pixel moved by `x` columns and
+    /// `y` rows from pixel (1, 1), like [Sprite::blit]. Pixels falling outside of the
+    /// image are skipped.
+    pub fn blit(&self, dst: &mut Image, x: i8, y: i8) {
+        for r in 1..=self.height {
+            for c in 1..=self.width {
+                let (row, col) = (y as isize + r as isize, x as isize + c as isize);
+                if row < 1 || col < 1 {
+                    continue;
+                }
+                if let Some(target) = dst.get_mut(row as usize, col as usize) {
+                    *target = self.pixel(r, c);
+                }
+            }
+        }
+    }
+}
+
+/// Animation blitting the sprites of a sheet one after another at a fixed position
+pub struct SpriteAnimation<'a> {
+    sheet: SpriteSheet<'a>,
+    x: i8,
+    y: i8,
+    next: usize,
+}
+
+/// Implements functions for sprite animation structure
+impl<'a> SpriteAnimation<'a> {
+    /// Creates an animation starting with sprite 0, blitted with its top left pixel
+    /// moved by `x` columns and `y` rows from pixel (1, 1)
+    pub fn new(sheet: SpriteSheet<'a>, x: i8, y: i8) -> Self {
+        SpriteAnimation {
+            sheet,
+            x,
+            y,
+            next: 0,
+        }
+    }
+}
+
+/// Each frame is the previous one with the next sprite blitted over it, the
+/// animation loops back to the first sprite after the last one
+impl<'a> Animation for SpriteAnimation<'a> {
+    fn next_frame(&mut self, frame: &mut Image) {
+        if let Some(sprite) = self.sheet.get(self.next) {
+            sprite.blit(frame, self.x, self.y);
+            self.next = (self.next + 1) % self.sheet.len();
+        }
+    }
+}
+
+/// Sprite of up to 8x8 pixels owning its pixels, pixels equal to the transparent
+/// color are not drawn
+#[derive(Clone)]
+pub struct Sprite {
+    pixels: [Color; 64],
+    width: usize,
+    height: usize,
+    transparent: Option<Color>,
+}
+
+/// Implements functions for sprite structure
+impl Sprite {
+    /// Creates an opaque sprite from its rows, from top to bottom, which must all
+    /// have the same length
+    pub fn from_rows(rows: &[&[Color]]) -> Result<Self, SpriteError> {
+        let height = rows.len();
+        let width = rows.first().map_or(0, |row| row.len());
+        if width == 0 || height == 0 {
+            return Err(SpriteError::EmptySprite);
+        }
+        if width > 8 || height > 8 {
+            return Err(SpriteError::TooLarge);
+        }
+        if rows.iter().any(|row| row.len() != width) {
+            return Err(SpriteError::RaggedRows);
+        }
+        let mut pixels = [Color::BLACK; 64];
+        for (r, row) in rows.iter().enumerate() {
+            pixels[r * width..(r + 1) * width].copy_from_slice(row);
+        }
+        Ok(Sprite {
+            pixels,
+            width,
+            height,
+            transparent: None,
+        })
+    }
+
+    /// Returns the sprite with pixels equal to `color` made transparent
+    pub fn with_transparent(self, color: Color) -> Self {
+        Sprite {
+            transparent: Some(color),
+            ..self
+        }
+    }
+
+    /// Width of the sprite in pixels
+    pub fn width(&self) -> usize {
+        self.width
+    }
+
+    /// Height of the sprite in pixels
+    pub fn height(&self) -> usize {
+        self.height
+    }
+
+    /// Returns the pixel of a row and a column, both starting at 1 like [Image] indexing
+    pub fn pixel(&self, row: usize, col: usize) -> Color {
+        self.pixels[(row - 1) * self.width + col - 1]
+    }
+
+    /// Draws the sprite into `dst` with its top left pixel moved by `x` columns and
+    /// `y` rows from pixel (1, 1). Pixels falling outside of the image and
+    /// transparent pixels are skipped.
+    pub fn blit(&self, dst: &mut Image, x: i8, y: i8) {
+        for r in 1..=self.height {
+            for c in 1..=self.width {
+                let (row, col) = (y as isize + r as isize, x as isize + c as isize);
+                if row < 1 || col < 1 {
+                    continue;
+                }
+                let pixel = self.pixel(r, c);
+                if Some(pixel) == self.transparent {
+                    continue;
+                }
+                if let Some(target) = dst.get_mut(row as usize, col as usize) {
+                    *target = pixel;
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Sheet data where every byte is its own position, so offsets can be read back
+    const DATA: [u8; 240] = {
+        let mut data = [0; 240];
+        let mut i = 0;
+        while i < data.len() {
+            data[i] = i as u8;
+            i += 1;
+        }
+        data
+    };
+
+    #[test]
+    fn sprites_start_at_their_offset() {
+        for (width, height) in [(1, 1), (2, 3), (4, 4), (8, 2)] {
+            let size = width * height * 3;
+            let count = DATA.len() / size;
+            let sheet = SpriteSheet::new(&DATA, width, height, count).unwrap();
+            for index in 0..count {
+                let sprite = sheet.get(index).unwrap();
+                assert_eq!((sprite.width(), sprite.height()), (width, height));
+                let first = (index * size) as u8;
+                assert_eq!(
+                    sprite.pixel(1, 1),
+                    Color {
+                        r: first,
+                        g: first + 1,
+                        b: first + 2
+                    }
+                );
+                let last = (index * size + size - 3) as u8;
+                assert_eq!(sprite.pixel(height, width).r, last);
+            }
+        }
+    }
+
+    #[test]
+    fn out_of_range_sprites_are_none() {
+        let sheet = SpriteSheet::new(&DATA, 2, 2, 3).unwrap();
+        assert_eq!(sheet.len(), 3);
+        assert!(sheet.get(2).is_some());
+        assert!(sheet.get(3).is_none());
+        assert!(sheet.get(usize::MAX).is_none());
+    }
+
+    #[test]
+    fn invalid_sheets_are_rejected() {
+        assert!(matches!(
+            SpriteSheet::new(&DATA, 0, 2, 1),
+            Err(SpriteError::EmptySprite)
+        ));
+        assert!(matches!(
+            SpriteSheet::new(&DATA, 4, 4, 6),
+            Err(SpriteError::SheetTooShort)
+        ));
+        assert!(matches!(
+            SpriteSheet::new(&DATA, usize::MAX, 2, 1),
+            Err(SpriteError::SheetTooShort)
+        ));
+        assert!(matches!(
+            SpriteSheet::new(&DATA, 1, 1, usize::MAX / 2),
+            Err(SpriteError::SheetTooShort)
+        ));
+    }
+
+    #[test]
+    fn sheet_sprites_are_clipped_at_every_edge() {
+        let sheet = SpriteSheet::new(&DATA, 2, 2, 1).unwrap();
+        let sprite = sheet.get(0).unwrap();
+        let mut image = Image::default();
+        sprite.blit(&mut image, -1, -1);
+        assert_eq!(image[(1, 1)], sprite.pixel(2, 2));
+        sprite.blit(&mut image, 7, 7);
+        assert_eq!(image[(8, 8)], sprite.pixel(1, 1));
+        let mut untouched = Image::default();
+        sprite.blit(&mut untouched, -2, 0);
+        sprite.blit(&mut untouched, 8, 0);
+        sprite.blit(&mut untouched, i8::MIN, i8::MAX);
+        assert_eq!(untouched, Image::default());
+    }
+
+    #[test]
+    fn three_sprite_sheet_animates_in_order() {
+        // Three 1x1 sprites: red, green, blue
+        let data = [200, 0, 0, 0, 200, 0, 0, 0, 200];
+        let sheet = SpriteSheet::new(&data, 1, 1, 3).unwrap();
+        let mut animation = SpriteAnimation::new(sheet, 2, 3);
+        let mut frame = Image::default();
+        let mut colors = [Color::BLACK; 4];
+        for color in colors.iter_mut() {
+            animation.next_frame(&mut frame);
+            *color = frame[(4, 3)];
+        }
+        assert_eq!(colors[0], Color { r: 200, g: 0, b: 0 });
+        assert_eq!(colors[1], Color { r: 0, g: 200, b: 0 });
+        assert_eq!(colors[2], Color { r: 0, g: 0, b: 200 });
+        assert_eq!(colors[3], colors[0]);
+    }
+
+    /// Ball of 3x3 pixels shaped like a plus, its corners being transparent
+    fn ball() -> Sprite {
+        const B: Color = Color::BLACK;
+        const W: Color = Color::WHITE;
+        Sprite::from_rows(&[&[B, W, B], &[W, W, W], &[B, W, B]])
+            .unwrap()
+            .with_transparent(B)
+    }
+
+    #[test]
+    fn bouncing_ball_is_clipped_at_every_edge() {
+        let background = Image::new_solid(Color::BLUE);
+        let lit = |image: &Image| image.iter().filter(|&&p| p == Color::WHITE).count();
+        // Bounces between columns and rows -1 and 6, half out of the image at
+        // both ends
+        let (mut x, mut y, mut dx, mut dy) = (2i8, 0i8, 1i8, -1i8);
+        let mut clipped_sides = [false; 4];
+        for _ in 0..32 {
+            let mut frame = background.clone();
+            ball().blit(&mut frame, x, y);
+            let expected = 5 - [x == -1, x == 6, y == -1, y == 6]
+                .iter()
+                .filter(|&&out| out)
+                .count();
+            assert_eq!(lit(&frame), expected, "ball at ({}, {})", x, y);
+            // Transparent corners keep the background
+            assert_eq!(frame.count_diff(&background), expected);
+            for (side, out) in clipped_sides
+                .iter_mut()
+                .zip([x == -1, x == 6, y == -1, y == 6])
+            {
+                *side |= out;
+            }
+            if !(-1..=6).contains(&(x + dx)) {
+                dx = -dx;
+            }
+            if !(-1..=6).contains(&(y + dy)) {
+                dy = -dy;
+            }
+            x += dx;
+            y += dy;
+        }
+        assert_eq!(clipped_sides, [true; 4]);
+
+        let mut corner = background.clone();
+        ball().blit(&mut corner, -1, -1);
+        assert!(corner.diff(&background).eq([(1, 1), (1, 2), (2, 1)]));
+    }
+
+    #[test]
+    fn invalid_sprites_are_rejected() {
+        let row = [Color::RED; 9];
+        assert_eq!(
+            Sprite::from_rows(&[&row[..]]).err(),
+            Some(SpriteError::TooLarge)
+        );
+        assert_eq!(
+            Sprite::from_rows(&[&row[..2], &row[..3]]).err(),
+            Some(SpriteError::RaggedRows)
+        );
+        assert_eq!(Sprite::from_rows(&[]).err(), Some(SpriteError::EmptySprite));
+    }
+}
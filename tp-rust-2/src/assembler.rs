@@ -0,0 +1,324 @@
+use crate::machine::MEMORY_SIZE;
+use std::collections::HashMap;
+
+/// Kind of an instruction operand
+#[derive(Clone, Copy)]
+enum Operand {
+    Reg, //register number, one byte
+    Imm16, //16 bit immediate, little endian
+    Imm8, //8 bit immediate
+}
+
+use Operand::*;
+
+/// Mnemonic, opcode and operands of every instruction, and of the `.byte`
+/// directive which has no opcode
+const INSTRUCTIONS: [(&str, Option<u8>, &[Operand]); 15] = [
+    ("mov_if", Some(1), &[Reg, Reg, Reg]),
+    ("store", Some(2), &[Reg, Reg]),
+    ("load", Some(3), &[Reg, Reg]),
+    ("loadimm", Some(4), &[Reg, Imm16]),
+    ("sub", Some(5), &[Reg, Reg, Reg]),
+    ("out", Some(6), &[Reg]),
+    ("exit", Some(7), &[]),
+    ("out_number", Some(8), &[Reg]),
+    ("add", Some(9), &[Reg, Reg, Reg]),
+    ("adc", Some(10), &[Reg, Reg, Reg]),
+    ("sbb", Some(11), &[Reg, Reg, Reg]),
+    ("getflag", Some(12), &[Reg]),
+    ("rdcycle", Some(13), &[Reg]),
+    ("trap", Some(14), &[Imm8]),
+    (".byte", None, &[Imm8]),
+];
+
+/// Error found by [assemble], with the line (from 1) where it happened
+#[derive(Debug, PartialEq, Eq)]
+pub struct AsmError {
+    pub line: usize,
+    pub kind: AsmErrorKind,
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum AsmErrorKind {
+    UnknownMnemonic(String),
+    OperandCount { expected: usize, found: usize },
+    InvalidRegister(String), //Operand is neither r0 to r15 nor a register constant
+    InvalidImmediate(String), //Operand is not a number, a number constant or a label
+    UndefinedSymbol(String),
+    ImmediateOutOfRange(i64),
+    Redefinition(String), //Constant, macro or label defined twice
+    RecursiveMacro(String), //Macro expanding into itself, directly or through other macros
+    UnterminatedMacro(String), //Macro without .endm
+    UnexpectedEndm, //.endm outside of a macro
+    NestedMacro, //.macro inside a macro
+    ProgramTooLarge, //Program does not fit in the machine memory
+}
+
+/// Value of a `.equ` constant
+#[derive(Clone, Copy)]
+enum Constant {
+    Number(i64),
+    Register(u8),
+}
+
+/// Statement of the program once macros are expanded
+struct Statement<'a> {
+    line: usize,
+    mnemonic: &'a str,
+    operands: Vec<&'a str>,
+}
+
+/// Source lines with their number
+type Lines<'a> = Vec<(usize, &'a str)>;
+
+/// Constants and macros of a program
+#[derive(Default)]
+struct Definitions<'a> {
+    constants: HashMap<&'a str, Constant>,
+    macros: HashMap<&'a str, Lines<'a>>,
+}
+
+/// Assemble a program into the bytes to load at address 0.
+///
+/// Each line holds an optional label `name:` and a statement, `;` starts a comment:
+///   - an instruction, its mnemonic followed by its operands separated by commas,
+///     e.g. `loadimm r1, 42`. Registers are `r0` to `r15`, immediates are decimal,
+///     `0x` hexadecimal or `0b` binary numbers, constants or labels (their address).
+///   - `.byte value` inserts a byte, as written by the [disassembler](crate::disassemble)
+///     for bytes which are not instructions.
+///   - `.equ NAME value` defines a constant usable as an immediate when `value`
+///     is a number, or as a register when it is a register, e.g. `.equ SP r15`.
+///     Constants are global and instructions may use them before their definition.
+///   - `.macro name` starts a parameterless macro ended by `.endm`. Writing `name`
+///     as a statement inserts its body, before labels are resolved.
+///
+/// Constants, macros and labels share a single namespace.
+pub fn assemble(source: &str) -> Result<Vec<u8>, AsmError> {
+    let mut definitions = Definitions::default();
+    let top_level = collect_definitions(source, &mut definitions)?;
+
+    let mut labels = Vec::new();
+    let mut statements = Vec::new();
+    expand(&top_level, &definitions, &mut Vec::new(), &mut labels, &mut statements)?;
+
+    // Give every label the address of the statement following it
+    let mut addresses: HashMap<&str, i64> = HashMap::new();
+    let mut address = 0;
+    let mut next_label = labels.iter().peekable();
+    for (index, statement) in statements.iter().enumerate() {
+        while let Some((line, name, _)) = next_label.next_if(|&&(_, _, before)| before == index) {
+            define_label(&mut addresses, &definitions, *line, name, address)?;
+        }
+        address += instruction_size(statement)? as i64;
+    }
+    for (line, name, _) in next_label {
+        define_label(&mut addresses, &definitions, *line, name, address)?;
+    }
+
+    let mut code = Vec::new();
+    for statement in &statements {
+        encode(statement, &definitions.constants, &addresses, &mut code)?;
+    }
+    if code.len() > MEMORY_SIZE {
+        return Err(AsmError { line: statements.last().map_or(0, |s| s.line), kind: AsmErrorKind::ProgramTooLarge });
+    }
+    Ok(code)
+}
+
+/// Record the constants and macros of `source`, returns the other lines with their number
+fn collect_definitions<'a>(source: &'a str, definitions: &mut Definitions<'a>) -> Result<Lines<'a>, AsmError> {
+    let mut top_level = Vec::new();
+    let mut current_macro: Option<(usize, &str, Lines)> = None;
+    for (index, line) in source.lines().enumerate() {
+        let number = index + 1;
+        let error = |kind| AsmError { line: number, kind };
+        let line = line.split(';').next().unwrap_or("").trim();
+        let (directive, rest) = line.split_once(char::is_whitespace).unwrap_or((line, ""));
+        match directive {
+            ".equ" => {
+                let (name, value) = rest.trim().split_once(char::is_whitespace).unwrap_or((rest.trim(), ""));
+                let value = value.trim();
+                let constant = match (parse_register(value), parse_number(value), definitions.constants.get(value)) {
+                    (Some(reg), _, _) => Constant::Register(reg),
+                    (_, Some(number), _) => Constant::Number(number),
+                    (_, _, Some(&constant)) => constant,
+                    _ if value.is_empty() => return Err(error(AsmErrorKind::OperandCount { expected: 2, found: 1 })),
+                    _ => return Err(error(AsmErrorKind::InvalidImmediate(value.to_string()))),
+                };
+                check_new_name(definitions, name).map_err(error)?;
+                definitions.constants.insert(name, constant);
+            }
+            ".macro" => {
+                if current_macro.is_some() {
+                    return Err(error(AsmErrorKind::NestedMacro));
+                }
+                let name = rest.trim();
+                if name.is_empty() {
+                    return Err(error(AsmErrorKind::OperandCount { expected: 1, found: 0 }));
+                }
+                check_new_name(definitions, name).map_err(error)?;
+                current_macro = Some((number, name, Vec::new()));
+            }
+            ".endm" => {
+                let (_, name, body) = current_macro.take().ok_or(error(AsmErrorKind::UnexpectedEndm))?;
+                definitions.macros.insert(name, body);
+            }
+            _ if line.is_empty() => {}
+            _ => match &mut current_macro {
+                Some((_, _, body)) => body.push((number, line)),
+                None => top_level.push((number, line)),
+            },
+        }
+    }
+    match current_macro {
+        Some((line, name, _)) => Err(AsmError { line, kind: AsmErrorKind::UnterminatedMacro(name.to_string()) }),
+        None => Ok(top_level),
+    }
+}
+
+/// Check that `name` is not already a constant or a macro
+fn check_new_name(definitions: &Definitions, name: &str) -> Result<(), AsmErrorKind> {
+    if definitions.constants.contains_key(name) || definitions.macros.contains_key(name) {
+        Err(AsmErrorKind::Redefinition(name.to_string()))
+    } else {
+        Ok(())
+    }
+}
+
+/// Append the labels and statements of `lines` to `labels` and `statements`,
+/// inserting the body of macros. `stack` holds the macros being expanded.
+///
+/// Labels are recorded with their line and the index of the statement following them.
+fn expand<'a>(
+    lines: &[(usize, &'a str)],
+    definitions: &Definitions<'a>,
+    stack: &mut Vec<&'a str>,
+    labels: &mut Vec<(usize, &'a str, usize)>,
+    statements: &mut Vec<Statement<'a>>,
+) -> Result<(), AsmError> {
+    for &(line, mut text) in lines {
+        while let Some((label, rest)) = text.split_once(':') {
+            labels.push((line, label.trim(), statements.len()));
+            text = rest.trim();
+        }
+        if text.is_empty() {
+            continue;
+        }
+        let (mnemonic, rest) = text.split_once(char::is_whitespace).unwrap_or((text, ""));
+        let operands: Vec<&str> = if rest.trim().is_empty() { Vec::new() } else { rest.split(',').map(str::trim).collect() };
+        if let Some((&name, body)) = definitions.macros.get_key_value(mnemonic) {
+            if stack.contains(&name) {
+                return Err(AsmError { line, kind: AsmErrorKind::RecursiveMacro(name.to_string()) });
+            }
+            if !operands.is_empty() {
+                return Err(AsmError { line, kind: AsmErrorKind::OperandCount { expected: 0, found: operands.len() } });
+            }
+            stack.push(name);
+            expand(body, definitions, stack, labels, statements)?;
+            stack.pop();
+        } else {
+            statements.push(Statement { line, mnemonic, operands });
+        }
+    }
+    Ok(())
+}
+
+/// Record the address of a label, which must not be already defined
+fn define_label<'a>(
+    addresses: &mut HashMap<&'a str, i64>,
+    definitions: &Definitions,
+    line: usize,
+    name: &'a str,
+    address: i64,
+) -> Result<(), AsmError> {
+    if addresses.contains_key(name) {
+        return Err(AsmError { line, kind: AsmErrorKind::Redefinition(name.to_string()) });
+    }
+    check_new_name(definitions, name).map_err(|kind| AsmError { line, kind })?;
+    addresses.insert(name, address);
+    Ok(())
+}
+
+/// Look up the opcode and operands of a mnemonic
+fn lookup(statement: &Statement) -> Result<(Option<u8>, &'static [Operand]), AsmError> {
+    INSTRUCTIONS
+        .iter()
+        .find(|(mnemonic, _, _)| *mnemonic == statement.mnemonic)
+        .map(|&(_, opcode, operands)| (opcode, operands))
+        .ok_or_else(|| AsmError { line: statement.line, kind: AsmErrorKind::UnknownMnemonic(statement.mnemonic.to_string()) })
+}
+
+/// Size in bytes of the instruction of a statement
+fn instruction_size(statement: &Statement) -> Result<usize, AsmError> {
+    let (opcode, operands) = lookup(statement)?;
+    Ok(opcode.is_some() as usize + operands.iter().map(|operand| if let Imm16 = operand { 2 } else { 1 }).sum::<usize>())
+}
+
+/// Append the bytes of the instruction of a statement to `code`
+fn encode(
+    statement: &Statement,
+    constants: &HashMap<&str, Constant>,
+    addresses: &HashMap<&str, i64>,
+    code: &mut Vec<u8>,
+) -> Result<(), AsmError> {
+    let error = |kind| AsmError { line: statement.line, kind };
+    let (opcode, kinds) = lookup(statement)?;
+    if kinds.len() != statement.operands.len() {
+        return Err(error(AsmErrorKind::OperandCount { expected: kinds.len(), found: statement.operands.len() }));
+    }
+    code.extend(opcode);
+    for (kind, &operand) in kinds.iter().zip(&statement.operands) {
+        match kind {
+            Reg => {
+                let reg = match (parse_register(operand), constants.get(operand)) {
+                    (Some(reg), _) | (_, Some(&Constant::Register(reg))) => reg,
+                    _ => return Err(error(AsmErrorKind::InvalidRegister(operand.to_string()))),
+                };
+                code.push(reg);
+            }
+            Imm16 | Imm8 => {
+                let value = match (parse_number(operand), constants.get(operand), addresses.get(operand)) {
+                    (Some(value), _, _) | (_, Some(&Constant::Number(value)), _) | (_, _, Some(&value)) => value,
+                    (_, Some(&Constant::Register(_)), _) => return Err(error(AsmErrorKind::InvalidImmediate(operand.to_string()))),
+                    _ if operand.starts_with(|c: char| c.is_ascii_alphabetic() || c == '_') => {
+                        return Err(error(AsmErrorKind::UndefinedSymbol(operand.to_string())))
+                    }
+                    _ => return Err(error(AsmErrorKind::InvalidImmediate(operand.to_string()))),
+                };
+                match kind {
+                    // Accept both signed and unsigned values, loadimm sign extends
+                    Imm16 if (i16::MIN as i64..=u16::MAX as i64).contains(&value) => {
+                        code.extend_from_slice(&(value as u16).to_le_bytes())
+                    }
+                    Imm8 if (0..=u8::MAX as i64).contains(&value) => code.push(value as u8),
+                    _ => return Err(error(AsmErrorKind::ImmediateOutOfRange(value))),
+                }
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Parse a register name from `r0` to `r15`
+fn parse_register(text: &str) -> Option<u8> {
+    let index: u8 = text.strip_prefix('r')?.parse().ok()?;
+    (index < 16 && !text[1..].starts_with('+')).then_some(index)
+}
+
+/// Parse a decimal, `0x` hexadecimal or `0b` binary number, optionally negative
+fn parse_number(text: &str) -> Option<i64> {
+    let (negative, text) = match text.strip_prefix('-') {
+        Some(text) => (true, text),
+        None => (false, text),
+    };
+    let (radix, digits) = match (text.strip_prefix("0x"), text.strip_prefix("0b")) {
+        (Some(hex), _) => (16, hex),
+        (_, Some(binary)) => (2, binary),
+        _ => (10, text),
+    };
+    if !digits.starts_with(|c: char| c.is_digit(radix)) {
+        return None;
+    }
+    let value = i64::from_str_radix(digits, radix).ok()?;
+    Some(if negative { -value } else { value })
+}
@@ -0,0 +1,42 @@
+use interpreter::{diff_programs, parse_image, Disassembled, MachineError, IMAGE_MAGIC};
+use std::fs;
+
+/// Format an item of a program as `address: item`, or `-` past its end
+fn describe(item: Option<(u32, Disassembled)>) -> String {
+    match item {
+        Some((address, item)) => format!("{address:#06x}: {item}"),
+        None => "-".to_string(),
+    }
+}
+
+/// Code of a program image, or the whole content of a raw file
+fn code(bytes: &[u8]) -> Result<&[u8], MachineError> {
+    if bytes.starts_with(IMAGE_MAGIC) {
+        Ok(parse_image(bytes)?.1)
+    } else {
+        Ok(bytes)
+    }
+}
+
+fn main() -> Result<(), MachineError> {
+    // Take the two programs to compare as arguments on the command line
+    let args: Vec<String> = std::env::args().collect();
+    if args.len() != 3 {
+        eprintln!("usage: se2-objdiff <program> <program>");
+        std::process::exit(2);
+    }
+
+    // Read both programs
+    let (left, right) = (fs::read(&args[1]).unwrap(), fs::read(&args[2]).unwrap());
+    let mismatches = diff_programs(code(&left)?, code(&right)?);
+
+    // Print one line per differing instruction, and fail if there is any
+    for mismatch in &mismatches {
+        println!("#{:<4} {:<32} | {}", mismatch.index, describe(mismatch.left), describe(mismatch.right));
+    }
+    if !mismatches.is_empty() {
+        println!("{} differing instructions", mismatches.len());
+        std::process::exit(1);
+    }
+    Ok(())
+}
@@ -0,0 +1,127 @@
+use crate::{Machine, MachineError, IMAGE_MAGIC};
+use std::fs;
+use std::path::Path;
+
+/// Maximum number of instructions run for a fixture before it is stopped
+pub const CONFORMANCE_FUEL: u64 = 100_000;
+
+/// A conformance fixture whose run did not match its expectations
+#[derive(Debug)]
+pub struct ConformanceFailure {
+    pub name: String, //File stem of the fixture
+    pub reason: String,
+}
+
+/// Run every fixture of `dir` and compare the results with their expectations.
+/// Every fixture is run even if some fail, and the number of passing fixtures
+/// is returned if none failed.
+///
+/// A fixture is a program `<name>.bin` (raw memory content or program image) and
+/// an expectation file `<name>.expected` made of lines of the form:
+///   - `status exit`, `status fuel` (still running after [CONFORMANCE_FUEL]
+///     instructions) or `status error <MachineError>`, e.g. `status error OutOfMemory`
+///   - `output <text>`, the whole output with `\n` and `\\` escapes (empty if absent)
+///   - `r<index> <value>`, the final value of a register (optional)
+///
+/// Empty lines and lines starting with `#` are ignored.
+pub fn run_conformance(dir: &Path) -> Result<usize, Vec<ConformanceFailure>> {
+    let mut names: Vec<String> = match fs::read_dir(dir) {
+        Ok(entries) => entries
+            .filter_map(|entry| entry.ok())
+            .map(|entry| entry.path())
+            .filter(|path| path.extension().is_some_and(|ext| ext == "bin"))
+            .filter_map(|path| path.file_stem().map(|stem| stem.to_string_lossy().into_owned()))
+            .collect(),
+        Err(e) => return Err(vec![ConformanceFailure { name: dir.display().to_string(), reason: e.to_string() }]),
+    };
+    names.sort();
+
+    let failures: Vec<ConformanceFailure> = names
+        .iter()
+        .filter_map(|name| {
+            run_fixture(dir, name).err().map(|reason| ConformanceFailure { name: name.clone(), reason })
+        })
+        .collect();
+    if failures.is_empty() {
+        Ok(names.len())
+    } else {
+        Err(failures)
+    }
+}
+
+/// Run one fixture, returns the description of every mismatch as an error
+fn run_fixture(dir: &Path, name: &str) -> Result<(), String> {
+    let program = fs::read(dir.join(format!("{name}.bin"))).map_err(|e| e.to_string())?;
+    let expected = fs::read_to_string(dir.join(format!("{name}.expected"))).map_err(|e| e.to_string())?;
+
+    let mut machine = if program.starts_with(IMAGE_MAGIC) {
+        Machine::load_image(&program).map_err(|e| format!("cannot load image: {e:?}"))?
+    } else {
+        Machine::new(&program)
+    };
+    let mut output = Vec::new();
+    let status = run_with_fuel(&mut machine, &mut output);
+    let output = String::from_utf8_lossy(&output);
+
+    let mut mismatches = Vec::new();
+    let mut expected_output = String::new();
+    for line in expected.lines().map(str::trim_end) {
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let (key, value) = line.split_once(' ').unwrap_or((line, ""));
+        match key {
+            "status" if value != status => mismatches.push(format!("status is `{status}`, expected `{value}`")),
+            "status" => {}
+            "output" => expected_output = unescape(value),
+            _ => match (key.strip_prefix('r').and_then(|r| r.parse::<usize>().ok()), value.parse::<u32>()) {
+                (Some(reg), Ok(value)) => match machine.regs().get(reg) {
+                    Some(&actual) if actual == value => {}
+                    Some(&actual) => mismatches.push(format!("r{reg} is {actual}, expected {value}")),
+                    None => mismatches.push(format!("no register r{reg}")),
+                },
+                _ => mismatches.push(format!("invalid expectation `{line}`")),
+            },
+        }
+    }
+    if output != expected_output {
+        mismatches.push(format!("output is {output:?}, expected {expected_output:?}"));
+    }
+
+    if mismatches.is_empty() {
+        Ok(())
+    } else {
+        Err(mismatches.join("; "))
+    }
+}
+
+/// Run a machine for at most [CONFORMANCE_FUEL] instructions and describe how it stopped
+fn run_with_fuel(machine: &mut Machine, output: &mut Vec<u8>) -> String {
+    for _ in 0..CONFORMANCE_FUEL {
+        match machine.step_on(output) {
+            Ok(false) => {}
+            Ok(true) => return "exit".to_string(),
+            Err(MachineError::IoError(e)) => return format!("error IoError {e}"),
+            Err(e) => return format!("error {e:?}"),
+        }
+    }
+    "fuel".to_string()
+}
+
+/// Replace the `\n` and `\\` escapes of an expected output
+fn unescape(text: &str) -> String {
+    let mut unescaped = String::new();
+    let mut chars = text.chars();
+    while let Some(c) = chars.next() {
+        if c != '\\' {
+            unescaped.push(c);
+            continue;
+        }
+        match chars.next() {
+            Some('n') => unescaped.push('\n'),
+            Some(other) => unescaped.push(other),
+            None => unescaped.push('\\'),
+        }
+    }
+    unescaped
+}
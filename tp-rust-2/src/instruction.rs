@@ -0,0 +1,191 @@
+use crate::MachineError;
+use std::fmt;
+
+/// Instruction of the machine, see [step_on](crate::Machine::step_on) for their effect.
+/// Register operands are register numbers, checked by [decode](Instruction::decode).
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Instruction {
+    MovIf(u8, u8, u8),
+    Store(u8, u8),
+    Load(u8, u8),
+    LoadImm(u8, i16),
+    Sub(u8, u8, u8),
+    Out(u8),
+    Exit,
+    OutNumber(u8),
+    Add(u8, u8, u8),
+    Adc(u8, u8, u8),
+    Sbb(u8, u8, u8),
+    GetFlag(u8),
+    RdCycle(u8),
+    Trap(u8),
+}
+
+use Instruction::*;
+
+impl Instruction {
+    /// First byte of the encoded instruction
+    pub fn opcode(&self) -> u8 {
+        match self {
+            MovIf(..) => 1,
+            Store(..) => 2,
+            Load(..) => 3,
+            LoadImm(..) => 4,
+            Sub(..) => 5,
+            Out(..) => 6,
+            Exit => 7,
+            OutNumber(..) => 8,
+            Add(..) => 9,
+            Adc(..) => 10,
+            Sbb(..) => 11,
+            GetFlag(..) => 12,
+            RdCycle(..) => 13,
+            Trap(..) => 14,
+        }
+    }
+
+    /// Name of the instruction in the assembler, see [assemble](crate::assemble)
+    pub fn mnemonic(&self) -> &'static str {
+        match self {
+            MovIf(..) => "mov_if",
+            Store(..) => "store",
+            Load(..) => "load",
+            LoadImm(..) => "loadimm",
+            Sub(..) => "sub",
+            Out(..) => "out",
+            Exit => "exit",
+            OutNumber(..) => "out_number",
+            Add(..) => "add",
+            Adc(..) => "adc",
+            Sbb(..) => "sbb",
+            GetFlag(..) => "getflag",
+            RdCycle(..) => "rdcycle",
+            Trap(..) => "trap",
+        }
+    }
+
+    /// Append the bytes of the instruction to `code`
+    pub fn encode(&self, code: &mut Vec<u8>) {
+        code.push(self.opcode());
+        match *self {
+            MovIf(a, b, c) | Sub(a, b, c) | Add(a, b, c) | Adc(a, b, c) | Sbb(a, b, c) => code.extend_from_slice(&[a, b, c]),
+            Store(a, b) | Load(a, b) => code.extend_from_slice(&[a, b]),
+            LoadImm(a, value) => {
+                code.push(a);
+                code.extend_from_slice(&value.to_le_bytes());
+            }
+            Out(a) | OutNumber(a) | GetFlag(a) | RdCycle(a) | Trap(a) => code.push(a),
+            Exit => {}
+        }
+    }
+
+    /// Size in bytes of the encoded instruction
+    pub fn size(&self) -> usize {
+        match self {
+            MovIf(..) | LoadImm(..) | Sub(..) | Add(..) | Adc(..) | Sbb(..) => 4,
+            Store(..) | Load(..) => 3,
+            Out(..) | OutNumber(..) | GetFlag(..) | RdCycle(..) | Trap(..) => 2,
+            Exit => 1,
+        }
+    }
+
+    /// Decode the instruction at the beginning of `code`
+    /// Returns an error if the opcode or a register is invalid, or if `code` ends
+    /// before the instruction
+    pub fn decode(code: &[u8]) -> Result<Instruction, MachineError> {
+        let byte = |i: usize| code.get(i).copied().ok_or(MachineError::OutOfMemory);
+        let reg = |i: usize| match byte(i)? {
+            reg @ 0..=15 => Ok(reg),
+            _ => Err(MachineError::InexistantRegister),
+        };
+        let instruction = match byte(0)? {
+            1 => MovIf(reg(1)?, reg(2)?, reg(3)?),
+            2 => Store(reg(1)?, reg(2)?),
+            3 => Load(reg(1)?, reg(2)?),
+            4 => LoadImm(reg(1)?, i16::from_le_bytes([byte(2)?, byte(3)?])),
+            5 => Sub(reg(1)?, reg(2)?, reg(3)?),
+            6 => Out(reg(1)?),
+            7 => Exit,
+            8 => OutNumber(reg(1)?),
+            9 => Add(reg(1)?, reg(2)?, reg(3)?),
+            10 => Adc(reg(1)?, reg(2)?, reg(3)?),
+            11 => Sbb(reg(1)?, reg(2)?, reg(3)?),
+            12 => GetFlag(reg(1)?),
+            13 => RdCycle(reg(1)?),
+            14 => Trap(byte(1)?),
+            _ => return Err(MachineError::InexistantInstruction),
+        };
+        Ok(instruction)
+    }
+}
+
+/// Instructions are written in the syntax of the assembler, e.g. `loadimm r1, -5`
+impl fmt::Display for Instruction {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.mnemonic())?;
+        match *self {
+            MovIf(a, b, c) | Sub(a, b, c) | Add(a, b, c) | Adc(a, b, c) | Sbb(a, b, c) => write!(f, " r{a}, r{b}, r{c}"),
+            Store(a, b) | Load(a, b) => write!(f, " r{a}, r{b}"),
+            LoadImm(a, value) => write!(f, " r{a}, {value}"),
+            Out(a) | OutNumber(a) | GetFlag(a) | RdCycle(a) => write!(f, " r{a}"),
+            Trap(number) => write!(f, " {number}"),
+            Exit => Ok(()),
+        }
+    }
+}
+
+/// Item of a disassembled program
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Disassembled {
+    Instruction(Instruction),
+    Byte(u8), //Byte which does not start a valid instruction
+}
+
+/// Bytes are written as a `.byte` directive of the assembler
+impl fmt::Display for Disassembled {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Disassembled::Instruction(instruction) => write!(f, "{instruction}"),
+            Disassembled::Byte(byte) => write!(f, ".byte {byte:#04x}"),
+        }
+    }
+}
+
+/// Decode `code` from its beginning, returns every item with its address. A byte
+/// which does not start a valid instruction is kept as is and decoding goes on
+/// with the next byte.
+pub fn disassemble(code: &[u8]) -> Vec<(u32, Disassembled)> {
+    let mut items = Vec::new();
+    let mut address = 0;
+    while address < code.len() {
+        let item = match Instruction::decode(&code[address..]) {
+            Ok(instruction) => Disassembled::Instruction(instruction),
+            Err(_) => Disassembled::Byte(code[address]),
+        };
+        items.push((address as u32, item));
+        address += match item {
+            Disassembled::Instruction(instruction) => instruction.size(),
+            Disassembled::Byte(_) => 1,
+        };
+    }
+    items
+}
+
+/// Difference between two programs compared instruction by instruction, see
+/// [diff_programs]
+#[derive(Debug, PartialEq, Eq)]
+pub struct Mismatch {
+    pub index: usize, //Index of the instruction in both programs
+    pub left: Option<(u32, Disassembled)>, //Address and item of the first program, None past its end
+    pub right: Option<(u32, Disassembled)>,
+}
+
+/// Disassemble two programs and compare them instruction by instruction, returns
+/// the instructions which differ or only exist in one of them
+pub fn diff_programs(left: &[u8], right: &[u8]) -> Vec<Mismatch> {
+    let (left, right) = (disassemble(left), disassemble(right));
+    (0..left.len().max(right.len()))
+        .map(|index| Mismatch { index, left: left.get(index).copied(), right: right.get(index).copied() })
+        .filter(|m| m.left.map(|(_, item)| item) != m.right.map(|(_, item)| item))
+        .collect()
+}
@@ -1,3 +1,11 @@
+mod assembler;
+mod conformance;
+mod instruction;
 mod machine;
+mod scheduler;
 
+pub use assembler::*;
+pub use conformance::*;
+pub use instruction::*;
 pub use machine::*;
+pub use scheduler::*;
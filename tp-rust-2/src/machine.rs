@@ -1,13 +1,90 @@
 use std::io::{self, Write};
 
-const MEMORY_SIZE: usize = 4096;
+pub(crate) const MEMORY_SIZE: usize = 4096;
 const NREGS: usize = 16;
 
-const IP: usize = 0;
+const IP: usize = 0; //default instruction pointer register, see set_ip_register
+
+/// Magic number at the beginning of a program image
+pub const IMAGE_MAGIC: &[u8; 4] = b"SE2M";
+/// Version of the program image format
+pub const IMAGE_VERSION: u8 = 1;
+/// Size of the program image header: magic, version, entry point (u16) and code length (u16)
+const IMAGE_HEADER_LEN: usize = 9;
+
+/// Number of opcodes, opcode 0 is not an instruction
+pub const NOPCODES: usize = 15;
+
+/// Cost in cycles of each instruction, indexed by opcode
+#[derive(Clone, Copy, Debug)]
+pub struct Timing {
+    pub costs: [u64; NOPCODES],
+}
+
+impl Timing {
+    /// Every instruction costs `cost` cycles
+    pub fn uniform(cost: u64) -> Self {
+        Timing { costs: [cost; NOPCODES] }
+    }
+}
+
+/// Default timing model: 1 cycle for register instructions, 3 for memory
+/// accesses (store, load) and 10 for outputs
+impl Default for Timing {
+    fn default() -> Self {
+        let mut costs = [1; NOPCODES];
+        costs[2] = 3; //store
+        costs[3] = 3; //load
+        costs[6] = 10; //out
+        costs[8] = 10; //out_number
+        Timing { costs }
+    }
+}
+
+/// Handler of the trap instruction, called with the trap number
+pub type TrapHandler = fn(&mut Machine, u8) -> Result<(), MachineError>;
+
+/// Trap number of the allocation service of [install_default_services](Machine::install_default_services)
+pub const TRAP_ALLOC: u8 = 1;
+/// Trap number of the heap reset service of [install_default_services](Machine::install_default_services)
+pub const TRAP_RESET_HEAP: u8 = 2;
 
 pub struct Machine {
     memory : [u8; MEMORY_SIZE],
-    registers : [u32; NREGS]
+    registers : [u32; NREGS],
+    carry : bool, //carry (or borrow) of the last add, adc, sub or sbb, not one of the registers
+    loop_detector : Option<LoopDetector>, //only used by run_debug
+    ip_reg : usize, //register used as instruction pointer
+    strict : bool, //only control flow may write the instruction pointer
+    control_flow : bool, //set while update_ip or a jump writes the instruction pointer
+    inst_ip : u32, //address of the instruction being executed
+    timing : Option<Timing>, //without timing model every instruction costs one cycle
+    cycles : u64,
+    trap_handler : Option<TrapHandler>,
+    heap : Option<Heap>, //state of the default allocation services
+}
+
+/// Bump allocator of the default services
+#[derive(Clone, Copy)]
+struct Heap {
+    start : u32,
+    end : u32, //first address after the heap
+    next : u32, //address of the next allocation
+}
+
+/// Why [run_debug](Machine::run_debug) stopped
+#[derive(Debug, PartialEq, Eq)]
+pub enum StopReason {
+    Exited,
+    LikelyHang { ip: u32 }, //IP jumped back to `ip` too many times without any state change
+}
+
+/// State of the tight loop detection, see [enable_loop_detection](Machine::enable_loop_detection)
+struct LoopDetector {
+    window : u32,
+    anchor : Option<(u32, u64)>, //target of the last backward jump and register hash at that time
+    count : u32, //number of unchanged revisits of the anchor
+    dirty : bool, //memory was written or I/O happened since the anchor was visited
 }
 
 #[derive(Debug)]
@@ -16,6 +93,12 @@ pub enum MachineError {
     InexistantInstruction,
     InexistantRegister,
     IoError(std::io::Error), //Error for out instructions
+    BadMagic, //Program image does not start with IMAGE_MAGIC
+    UnsupportedVersion(u8), //Program image version is not IMAGE_VERSION
+    TruncatedImage, //Program image is shorter than its header says
+    EntryOutsideCode(u16), //Entry point of a program image is not inside its code
+    IpWriteForbidden { at_ip: u32 }, //In strict mode, the instruction at `at_ip` wrote the instruction pointer without being a jump
+    UnhandledTrap(u8), //Trap instruction with no handler installed or a number the handler does not know
 }
 
 impl Machine {
@@ -27,12 +110,45 @@ impl Machine {
     pub fn new(memory: &[u8]) -> Self {
         let mut machine = Self {
             memory: [0; MEMORY_SIZE],
-            registers: [0; NREGS]
+            registers: [0; NREGS],
+            carry: false,
+            loop_detector: None,
+            ip_reg: IP,
+            strict: false,
+            control_flow: false,
+            inst_ip: 0,
+            timing: None,
+            cycles: 0,
+            trap_handler: None,
+            heap: None,
         };
         machine.memory[..memory.len()].copy_from_slice(memory);
         machine
     }
 
+    /// Create a new machine from a program image: the code is copied at the
+    /// beginning of the machine memory and IP is set to the entry point.
+    ///
+    /// A program image is the magic `SE2M`, a version byte, the entry point and
+    /// the code length as little endian u16, and then the code bytes.
+    pub fn load_image(bytes: &[u8]) -> Result<Self, MachineError> {
+        let (entry, code) = parse_image(bytes)?;
+        let mut machine = Machine::new(code);
+        machine.set_reg(machine.ip_reg, entry as u32)?;
+        Ok(machine)
+    }
+
+    /// Build a program image from code and an entry point, see [load_image](Machine::load_image).
+    pub fn make_image(code: &[u8], entry: u16) -> Vec<u8> {
+        let mut image = Vec::with_capacity(IMAGE_HEADER_LEN + code.len());
+        image.extend_from_slice(IMAGE_MAGIC);
+        image.push(IMAGE_VERSION);
+        image.extend_from_slice(&entry.to_le_bytes());
+        image.extend_from_slice(&(code.len() as u16).to_le_bytes());
+        image.extend_from_slice(code);
+        image
+    }
+
     /// Run until the program terminates or until an error happens.
     /// If output instructions are run, they print on `fd`.
     pub fn run_on<T: Write>(&mut self, fd: &mut T) -> Result<(), MachineError> {
@@ -46,8 +162,71 @@ impl Machine {
         self.run_on(&mut io::stdout().lock())
     }
 
+    /// Make [run_debug](Machine::run_debug) stop when IP jumps back `window` times
+    /// to the same address while registers, memory and outputs do not change.
+    /// Output instructions count as a change so loops printing forever are not flagged.
+    pub fn enable_loop_detection(&mut self, window: u32) {
+        self.loop_detector = Some(LoopDetector { window, anchor: None, count: 0, dirty: false });
+    }
+
+    /// Like [run_on](Machine::run_on), but also stops on a likely infinite loop
+    /// if loop detection is enabled.
+    pub fn run_debug_on<T: Write>(&mut self, fd: &mut T) -> Result<StopReason, MachineError> {
+        loop {
+            let before = self.ip();
+            if self.step_on(fd)? {
+                return Ok(StopReason::Exited);
+            }
+            let ip = self.ip();
+            if ip <= before && self.loop_revisited(ip) {
+                return Ok(StopReason::LikelyHang { ip });
+            }
+        }
+    }
+
+    /// Similar to [run_debug_on](Machine::run_debug_on).
+    /// If output instructions are run, they print on standard output.
+    pub fn run_debug(&mut self) -> Result<StopReason, MachineError> {
+        self.run_debug_on(&mut io::stdout().lock())
+    }
+
+    /// Record a backward jump to `ip`, returns true once the loop detection window
+    /// is reached without any state change
+    fn loop_revisited(&mut self, ip: u32) -> bool {
+        let hash = self.registers_hash();
+        let Some(detector) = &mut self.loop_detector else { return false };
+        match detector.anchor {
+            Some((anchor, anchor_hash)) if anchor == ip && anchor_hash == hash && !detector.dirty => {
+                detector.count += 1;
+            }
+            _ => {
+                detector.anchor = Some((ip, hash));
+                detector.count = 0;
+            }
+        }
+        detector.dirty = false;
+        detector.count >= detector.window
+    }
+
+    /// Cheap hash of the registers and carry flag (FNV-1a on the register values)
+    fn registers_hash(&self) -> u64 {
+        let mut hash: u64 = 0xcbf29ce484222325;
+        for &value in self.registers.iter().chain([self.carry as u32].iter()) {
+            hash = (hash ^ value as u64).wrapping_mul(0x100000001b3);
+        }
+        hash
+    }
+
+    /// Tell the loop detector that the state changed outside of the registers
+    fn mark_dirty(&mut self) {
+        if let Some(detector) = &mut self.loop_detector {
+            detector.dirty = true;
+        }
+    }
+
     /// Execute the next instruction by doing the following steps:
-    ///   - decode the instruction located at IP (register 0)
+    ///   - decode the instruction located at IP (register 0 unless changed by
+    ///     [set_ip_register](Machine::set_ip_register))
     ///   - increment the IP by the size of the instruction
     ///   - execute the decoded instruction
     ///
@@ -59,10 +238,11 @@ impl Machine {
     /// terminated (upon encountering an exit instruction), or
     /// `false` if the execution must continue.
     pub fn step_on<T: Write>(&mut self, fd: &mut T) -> Result<bool, MachineError> {
-        let adr : u32 = self.registers[IP];
+        let adr : u32 = self.ip();
+        self.inst_ip = adr;
         if adr > 4095 {return Err(MachineError::OutOfMemory);} //check if instruction pointer does not overflow memory
         let inst: u8 = self.memory[adr as usize];
-        match inst {
+        let result = match inst {
             1 => self.mov_if(adr,4),
             2 => self.store(adr,3),
             3 => self.load(adr,3),
@@ -71,8 +251,52 @@ impl Machine {
             6 => self.out(adr,2, fd),
             7 => self.exit(adr,1),
             8 => self.out_number(adr,2, fd),
+            9 => self.arith(adr,4, |b, c, _| b.overflowing_add(c)), //add
+            10 => self.arith(adr,4, add_with_carry), //adc
+            11 => self.arith(adr,4, sub_with_borrow), //sbb
+            12 => self.getflag(adr,2),
+            13 => self.rdcycle(adr,2),
+            14 => self.trap(adr,2),
             _ => Err(MachineError::InexistantInstruction)          
+        };
+        if result.is_ok() {
+            self.cycles += self.timing.map_or(1, |timing| timing.costs[inst as usize]);
         }
+        result
+    }
+
+    /// Install a timing model giving the cost of each instruction, or remove it with
+    /// None so that every instruction costs one cycle. Cycles already counted are kept.
+    pub fn set_timing(&mut self, timing: Option<Timing>) {
+        self.timing = timing;
+    }
+
+    /// Install the handler called by the trap instruction, or remove it with None
+    pub fn set_trap_handler(&mut self, handler: Option<TrapHandler>) {
+        self.trap_handler = handler;
+    }
+
+    /// Install the standard services as trap handler, with a bump allocator
+    /// handing out memory between `heap_start` and `heap_end` (excluded):
+    ///   - trap 1 (alloc): allocates r1 bytes aligned on 4 and stores their address
+    ///     in r1, or 0 if the heap is exhausted
+    ///   - trap 2 (reset heap): frees every allocation
+    ///
+    /// Since 0 means failure, the heap should not start at address 0.
+    /// Returns an error if the heap is not inside the machine memory.
+    pub fn install_default_services(&mut self, heap_start: u32, heap_end: u32) -> Result<(),MachineError> {
+        let start = heap_start.checked_next_multiple_of(4).ok_or(MachineError::OutOfMemory)?;
+        if heap_end as usize > MEMORY_SIZE || start > heap_end {
+            return Err(MachineError::OutOfMemory);
+        }
+        self.heap = Some(Heap { start, end: heap_end, next: start });
+        self.trap_handler = Some(default_services);
+        Ok(())
+    }
+
+    /// Number of cycles spent by the instructions executed so far
+    pub fn cycles(&self) -> u64 {
+        self.cycles
     }
 
     /// Check if index of registers does not exceed 15
@@ -88,8 +312,7 @@ impl Machine {
     /// Update instruction pointer with set_reg call
     pub fn update_ip(&mut self, adr: u32, inc_adr: u8) -> Result<(),MachineError> {
          
-        self.set_reg(IP, adr+inc_adr as u32) 
-        
+        self.set_reg_control_flow(self.ip_reg, adr+inc_adr as u32)
     }
 
     /// Similar to [step_on](Machine::step_on).
@@ -104,14 +327,53 @@ impl Machine {
     }
 
     /// Sets a register to the given value
-    /// Returns error if register index out of bounds
+    /// Returns error if register index out of bounds, or if the register is the
+    /// instruction pointer in strict mode and this is not a jump
     pub fn set_reg(&mut self, reg: usize, value: u32) -> Result<(),MachineError> {
             self.check_registers(reg as u8)?;
+            if self.strict && reg == self.ip_reg && !self.control_flow {
+                return Err(MachineError::IpWriteForbidden { at_ip: self.inst_ip });
+            }
             self.registers[reg] = value;
             Ok(())
             
     }
 
+    /// Same as [set_reg](Machine::set_reg) for instructions changing the control flow,
+    /// which may write the instruction pointer in strict mode
+    fn set_reg_control_flow(&mut self, reg: usize, value: u32) -> Result<(),MachineError> {
+        self.control_flow = true;
+        let result = self.set_reg(reg, value);
+        self.control_flow = false;
+        result
+    }
+
+    /// Current value of the instruction pointer
+    pub fn ip(&self) -> u32 {
+        self.registers[self.ip_reg]
+    }
+
+    /// Use register `reg` as instruction pointer instead of register 0. Its current
+    /// value becomes the address of the next instruction.
+    pub fn set_ip_register(&mut self, reg: usize) -> Result<(),MachineError> {
+        self.check_registers(reg as u8)?;
+        self.ip_reg = reg;
+        Ok(())
+    }
+
+    /// In strict mode, writing the instruction pointer with anything but a jump
+    /// (mov_if) fails with [MachineError::IpWriteForbidden], so that clobbering it
+    /// by mistake is detected at once. Strict mode is off by default.
+    pub fn set_strict(&mut self, on: bool) {
+        self.strict = on;
+    }
+
+    /// Carry flag, set by the arithmetic instructions (add, adc, sub, sbb) to
+    /// the carry or borrow of their result and preserved by every other instruction.
+    pub fn carry(&self) -> bool {
+        self.carry
+    }
+
     /// Reference onto the machine current memory.
     /// Returns false if execution was complete or a MachineError
     pub fn memory(&self) -> &[u8] {
@@ -128,7 +390,7 @@ impl Machine {
 
         if self.registers[reg_c as usize] != 0 {
             let reg_b = self.memory[(adr+2) as usize]; self.check_registers(reg_b)?;
-            self.set_reg(self.memory[(adr+1) as usize] as usize, self.registers[reg_b as usize])?;
+            self.set_reg_control_flow(self.memory[(adr+1) as usize] as usize, self.registers[reg_b as usize])?; //mov_if is the jump instruction
             Ok(false)
         } else {
             Ok(false) 
@@ -149,11 +411,10 @@ impl Machine {
         if addr >= 4093 {return Err(MachineError::OutOfMemory);}
 
         let val = self.registers[reg_b as usize];
-        let mut i = 0;
-        for word in val.to_ne_bytes() {
-            self.memory[(addr + i) as usize] = word;
-            i = i+1;
+        for (i, word) in val.to_ne_bytes().into_iter().enumerate() {
+            self.memory[addr as usize + i] = word;
         }
+        self.mark_dirty();
         Ok(false)
     }
 
@@ -190,10 +451,17 @@ impl Machine {
         Ok(false)
     }
 
-    /// Sub content of register B to register C and wrap result in case of overflow
+    /// Sub content of register C to register B, store it in register A and wrap result in case of overflow
+    /// The carry flag is set if a borrow occurred (B < C)
     /// Returns false if execution was complete or a MachineError
     pub fn sub(&mut self, adr:u32, inc:u8) -> Result<bool,MachineError> {
+        self.arith(adr, inc, |b, c, _| b.overflowing_sub(c))
+    }
 
+    /// Store in register A the result of `op` applied to registers B and C and the
+    /// carry flag, then set the carry flag to the carry returned by `op`
+    /// Returns false if execution was complete or a MachineError
+    pub fn arith(&mut self, adr: u32, inc: u8, op: fn(u32, u32, bool) -> (u32, bool)) -> Result<bool,MachineError> {
 
         self.update_ip(adr,inc)?;
 
@@ -201,7 +469,43 @@ impl Machine {
         let reg_b = self.memory[(adr+2) as usize]; self.check_registers(reg_b)?;
         let reg_c = self.memory[(adr+3) as usize]; self.check_registers(reg_c)?;
 
-        self.set_reg(reg_a as usize,  u32::wrapping_sub(self.registers[reg_b as usize],  self.registers[reg_c as usize] as u32))?;
+        let (val, carry) = op(self.registers[reg_b as usize], self.registers[reg_c as usize], self.carry);
+        self.set_reg(reg_a as usize, val)?;
+        self.carry = carry;
+        Ok(false)
+    }
+
+    /// Store the carry flag (0 or 1) in register A
+    /// Returns false if execution was complete or a MachineError
+    pub fn getflag(&mut self, adr: u32, inc: u8) -> Result<bool,MachineError> {
+
+        self.update_ip(adr,inc)?;
+
+        let reg_a = self.memory[(adr+1) as usize]; self.check_registers(reg_a)?;
+        self.set_reg(reg_a as usize, self.carry as u32)?;
+        Ok(false)
+    }
+
+    /// Store in register A the low 32 bits of the cycle counter, not counting this instruction
+    /// Returns false if execution was complete or a MachineError
+    pub fn rdcycle(&mut self, adr: u32, inc: u8) -> Result<bool,MachineError> {
+
+        self.update_ip(adr,inc)?;
+
+        let reg_a = self.memory[(adr+1) as usize]; self.check_registers(reg_a)?;
+        self.set_reg(reg_a as usize, self.cycles as u32)?;
+        Ok(false)
+    }
+
+    /// Call the trap handler with the trap number following the opcode
+    /// Returns false if execution was complete or a MachineError
+    pub fn trap(&mut self, adr: u32, inc: u8) -> Result<bool,MachineError> {
+
+        self.update_ip(adr,inc)?;
+
+        let number = self.memory[(adr+1) as usize];
+        let handler = self.trap_handler.ok_or(MachineError::UnhandledTrap(number))?;
+        handler(self, number)?;
         Ok(false)
     }
 
@@ -219,6 +523,7 @@ impl Machine {
         if let Err(e) = fd.write(unicode.as_bytes()) {
             return Err(MachineError::IoError(e));
         }
+        self.mark_dirty();
         Ok(false)
     }
 
@@ -244,6 +549,68 @@ impl Machine {
         if let Err(e) = fd.write(val.as_bytes()) {
             return Err(MachineError::IoError(e));
         }
+        self.mark_dirty();
         Ok(false)
     }
 }
+
+/// Check a program image and return its entry point and its code, see
+/// [load_image](Machine::load_image)
+pub fn parse_image(bytes: &[u8]) -> Result<(u16, &[u8]), MachineError> {
+    if bytes.len() < IMAGE_MAGIC.len() || &bytes[..IMAGE_MAGIC.len()] != IMAGE_MAGIC {
+        return Err(MachineError::BadMagic);
+    }
+    if bytes.len() < IMAGE_HEADER_LEN {
+        return Err(MachineError::TruncatedImage);
+    }
+    if bytes[4] != IMAGE_VERSION {
+        return Err(MachineError::UnsupportedVersion(bytes[4]));
+    }
+    let entry = u16::from_le_bytes([bytes[5], bytes[6]]);
+    let len = u16::from_le_bytes([bytes[7], bytes[8]]) as usize;
+    let code = bytes.get(IMAGE_HEADER_LEN..IMAGE_HEADER_LEN + len).ok_or(MachineError::TruncatedImage)?;
+    if len > MEMORY_SIZE {
+        return Err(MachineError::OutOfMemory);
+    }
+    if entry as usize >= len {
+        return Err(MachineError::EntryOutsideCode(entry));
+    }
+    Ok((entry, code))
+}
+
+/// Add b, c and the carry, returns the wrapped result and the new carry
+fn add_with_carry(b: u32, c: u32, carry: bool) -> (u32, bool) {
+    let (val, c1) = b.overflowing_add(c);
+    let (val, c2) = val.overflowing_add(carry as u32);
+    (val, c1 || c2)
+}
+
+/// Sub c and the borrow from b, returns the wrapped result and the new borrow
+fn sub_with_borrow(b: u32, c: u32, borrow: bool) -> (u32, bool) {
+    let (val, b1) = b.overflowing_sub(c);
+    let (val, b2) = val.overflowing_sub(borrow as u32);
+    (val, b1 || b2)
+}
+
+/// Trap handler of the standard services, see [install_default_services](Machine::install_default_services)
+fn default_services(machine: &mut Machine, number: u8) -> Result<(), MachineError> {
+    let mut heap = machine.heap.ok_or(MachineError::UnhandledTrap(number))?;
+    match number {
+        TRAP_ALLOC => {
+            let size = machine.registers[1];
+            let address = match size.checked_next_multiple_of(4).and_then(|size| heap.next.checked_add(size)) {
+                Some(next) if next <= heap.end => {
+                    let address = heap.next;
+                    heap.next = next;
+                    address
+                }
+                _ => 0, //exhausted heap
+            };
+            machine.set_reg(1, address)?;
+        }
+        TRAP_RESET_HEAP => heap.next = heap.start,
+        _ => return Err(MachineError::UnhandledTrap(number)),
+    }
+    machine.heap = Some(heap);
+    Ok(())
+}
@@ -1,4 +1,4 @@
-use interpreter::{Machine, MachineError};
+use interpreter::{run_conformance, Machine, MachineError, IMAGE_MAGIC};
 use std::fs::File;
 use std::io::Read;
 
@@ -6,13 +6,32 @@ fn main() -> Result<(), MachineError> {
     // Take a filename as argument on the command line
     let filename = std::env::args().nth(1).unwrap();
 
+    // `--conformance <dir>` runs the fixtures of dir instead of a program
+    if filename == "--conformance" {
+        let dir = std::env::args().nth(2).unwrap_or_else(|| "tests/conformance".to_string());
+        match run_conformance(std::path::Path::new(&dir)) {
+            Ok(passed) => println!("{passed} fixtures passed"),
+            Err(failures) => {
+                for failure in &failures {
+                    println!("{}: {}", failure.name, failure.reason);
+                }
+                std::process::exit(1);
+            }
+        }
+        return Ok(());
+    }
+
     // Read content to buffer
     let mut fs = File::open(&filename).unwrap();
     let mut buffer = Vec::new();
     fs.read_to_end(&mut buffer).unwrap();
 
-    // Create a machine with this memory content
-    let mut machine = Machine::new(&buffer);
+    // Create a machine from a program image, or with this memory content for raw files
+    let mut machine = if buffer.starts_with(IMAGE_MAGIC) {
+        Machine::load_image(&buffer)?
+    } else {
+        Machine::new(&buffer)
+    };
 
     // Run the machine until the end
     machine.run()
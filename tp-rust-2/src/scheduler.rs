@@ -0,0 +1,91 @@
+use crate::{Machine, MachineError};
+use std::io::Write;
+
+/// How a machine run by a [Scheduler] ended
+#[derive(Debug)]
+pub enum RunOutcome {
+    Exited, //The program ran an exit instruction
+    Failed(MachineError), //The program was retired because of this error
+}
+
+/// Run several machines in turn, sharing one output
+pub struct Scheduler {
+    machines: Vec<Machine>,
+    prefixes: Vec<String>,
+}
+
+impl Scheduler {
+    /// Create a scheduler owning `machines`. Output lines of machine `i`
+    /// are prefixed with `[i] ` by default.
+    pub fn new(machines: Vec<Machine>) -> Self {
+        let prefixes = (0..machines.len()).map(|i| format!("[{i}] ")).collect();
+        Self { machines, prefixes }
+    }
+
+    /// Replace the prefix of the output lines of machine `index`
+    ///
+    /// # Panics
+    /// This function panics when `index` is not the index of a machine.
+    pub fn set_prefix(&mut self, index: usize, prefix: &str) {
+        self.prefixes[index] = prefix.to_string();
+    }
+
+    /// Reference onto the scheduled machines.
+    pub fn machines(&self) -> &[Machine] {
+        &self.machines
+    }
+
+    /// Run every live machine for up to `quantum` instructions in turn, until all
+    /// of them have exited or failed. A failing machine is retired without
+    /// stopping the others.
+    ///
+    /// Output is written on `fd` line by line, each line being prefixed by
+    /// the prefix of the machine which printed it. The outcome of each
+    /// machine is returned in the same order as the machines.
+    /// An error is only returned if writing on `fd` fails.
+    pub fn run_round_robin<W: Write>(&mut self, fd: &mut W, quantum: u64) -> Result<Vec<RunOutcome>, MachineError> {
+        let mut outcomes: Vec<Option<RunOutcome>> = self.machines.iter().map(|_| None).collect();
+        let mut pending: Vec<Vec<u8>> = vec![Vec::new(); self.machines.len()]; //output not yet ended by a newline
+
+        while outcomes.iter().any(Option::is_none) {
+            for (i, machine) in self.machines.iter_mut().enumerate() {
+                if outcomes[i].is_some() {
+                    continue;
+                }
+                for _ in 0..quantum.max(1) {
+                    match machine.step_on(&mut pending[i]) {
+                        Ok(false) => {}
+                        Ok(true) => {
+                            outcomes[i] = Some(RunOutcome::Exited);
+                            break;
+                        }
+                        Err(e) => {
+                            outcomes[i] = Some(RunOutcome::Failed(e));
+                            break;
+                        }
+                    }
+                }
+                let finished = outcomes[i].is_some();
+                write_lines(fd, &self.prefixes[i], &mut pending[i], finished).map_err(MachineError::IoError)?;
+            }
+        }
+        Ok(outcomes.into_iter().flatten().collect())
+    }
+}
+
+/// Write the complete lines of `pending` on `fd` with a prefix, and also the
+/// last incomplete line if `flush` is true
+fn write_lines<W: Write>(fd: &mut W, prefix: &str, pending: &mut Vec<u8>, flush: bool) -> std::io::Result<()> {
+    let end = match pending.iter().rposition(|&b| b == b'\n') {
+        Some(_) if flush => pending.len(),
+        Some(last) => last + 1,
+        None if flush => pending.len(),
+        None => 0,
+    };
+    for line in pending[..end].split_inclusive(|&b| b == b'\n') {
+        fd.write_all(prefix.as_bytes())?;
+        fd.write_all(line)?;
+    }
+    pending.drain(..end);
+    Ok(())
+}
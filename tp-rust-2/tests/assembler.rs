@@ -0,0 +1,155 @@
+use interpreter::{assemble, AsmError, AsmErrorKind, Machine};
+
+/// Assemble and run a program, returns its output
+fn run(source: &str) -> String {
+    let code = assemble(source).unwrap();
+    let mut machine = Machine::new(&code);
+    let mut output = Vec::new();
+    machine.run_on(&mut output).unwrap();
+    String::from_utf8(output).unwrap()
+}
+
+fn error(line: usize, kind: AsmErrorKind) -> Result<Vec<u8>, AsmError> {
+    Err(AsmError { line, kind })
+}
+
+#[test]
+fn instructions_are_encoded() {
+    let source = "
+        loadimm r1, -1     ; sign extended by the machine
+        loadimm r2, 0xffff
+        mov_if r3, r1, r2
+        store r4, r5
+        trap 0b11
+        exit
+    ";
+    assert_eq!(
+        assemble(source).unwrap(),
+        [4, 1, 0xff, 0xff, 4, 2, 0xff, 0xff, 1, 3, 1, 2, 2, 4, 5, 14, 3, 7]
+    );
+}
+
+#[test]
+fn constants_in_immediates() {
+    let source = "
+        .equ NEWLINE 10
+        .equ LINE_FEED NEWLINE
+        loadimm r1, COUNT  ; used before its definition
+        loadimm r2, LINE_FEED
+        .equ COUNT -2
+        trap COUNT_TRAP
+        .equ COUNT_TRAP 0x7
+    ";
+    assert_eq!(assemble(source).unwrap(), [4, 1, 0xfe, 0xff, 4, 2, 10, 0, 14, 7]);
+}
+
+#[test]
+fn constants_as_register_aliases() {
+    let source = "
+        .equ SP r15
+        .equ TOP 0x100
+        loadimm SP, TOP
+        store SP, r1
+        exit
+    ";
+    assert_eq!(assemble(source).unwrap(), [4, 15, 0, 1, 2, 15, 1, 7]);
+    assert_eq!(
+        assemble(".equ SP r15\nloadimm r1, SP"),
+        error(2, AsmErrorKind::InvalidImmediate("SP".to_string()))
+    );
+    assert_eq!(
+        assemble(".equ ONE 1\nout ONE"),
+        error(2, AsmErrorKind::InvalidRegister("ONE".to_string()))
+    );
+}
+
+#[test]
+fn macro_used_twice() {
+    let source = "
+        .equ CHAR r1
+        .macro newline
+        loadimm CHAR, 10
+        out CHAR
+        .endm
+        newline
+        newline
+        exit
+    ";
+    assert_eq!(assemble(source).unwrap(), [4, 1, 10, 0, 6, 1, 4, 1, 10, 0, 6, 1, 7]);
+    assert_eq!(run(source), "\n\n");
+}
+
+#[test]
+fn macros_expand_before_labels() {
+    // Prints 3, 2 and 1, the loop address depends on the size of the macro body
+    let source = "
+        .macro decrement
+        loadimm r2, 1
+        sub r1, r1, r2
+        .endm
+        .macro print
+        out_number r1
+        .endm
+
+        loadimm r1, 3
+        loadimm r3, loop
+    loop:
+        print
+        decrement
+        mov_if r0, r3, r1 ; jump back while r1 is not 0
+        loadimm r4, end
+    end:
+        exit
+    ";
+    let code = assemble(source).unwrap();
+    assert_eq!(code[6..8], [8, 0]);
+    assert_eq!(code[code.len() - 3..code.len() - 1], [code.len() as u8 - 1, 0]);
+    assert_eq!(run(source), "321");
+}
+
+#[test]
+fn redefinitions_are_rejected() {
+    let redefinition = |line, name: &str| error(line, AsmErrorKind::Redefinition(name.to_string()));
+    assert_eq!(assemble(".equ A 1\n.equ A 2"), redefinition(2, "A"));
+    assert_eq!(assemble(".macro m\n.endm\n.macro m\n.endm"), redefinition(3, "m"));
+    assert_eq!(assemble(".equ m 1\n.macro m\n.endm"), redefinition(2, "m"));
+    assert_eq!(assemble("a: exit\na: exit"), redefinition(2, "a"));
+    assert_eq!(assemble(".equ a 1\na: exit"), redefinition(2, "a"));
+    // A label in a macro is defined again by every use
+    assert_eq!(assemble(".macro m\nhere: exit\n.endm\nm\nm"), redefinition(2, "here"));
+}
+
+#[test]
+fn recursive_macros_are_rejected() {
+    let recursive = |line, name: &str| error(line, AsmErrorKind::RecursiveMacro(name.to_string()));
+    assert_eq!(assemble(".macro m\nexit\nm\n.endm\nm"), recursive(3, "m"));
+    let source = "
+        .macro a
+        b
+        .endm
+        .macro b
+        exit
+        a
+        .endm
+        b
+    ";
+    // b uses a, whose body uses b again on line 3
+    assert_eq!(assemble(source), recursive(3, "b"));
+    // Using the same macro twice is not a recursion
+    assert!(assemble(".macro a\nexit\n.endm\n.macro b\na\na\n.endm\nb").is_ok());
+}
+
+#[test]
+fn malformed_programs() {
+    assert_eq!(assemble(".macro m\nexit"), error(1, AsmErrorKind::UnterminatedMacro("m".to_string())));
+    assert_eq!(assemble("exit\n.endm"), error(2, AsmErrorKind::UnexpectedEndm));
+    assert_eq!(assemble(".macro m\n.macro n"), error(2, AsmErrorKind::NestedMacro));
+    assert_eq!(assemble("jump r1"), error(1, AsmErrorKind::UnknownMnemonic("jump".to_string())));
+    assert_eq!(assemble("out r1, r2"), error(1, AsmErrorKind::OperandCount { expected: 1, found: 2 }));
+    assert_eq!(assemble("out r16"), error(1, AsmErrorKind::InvalidRegister("r16".to_string())));
+    assert_eq!(assemble("loadimm r1, nowhere"), error(1, AsmErrorKind::UndefinedSymbol("nowhere".to_string())));
+    assert_eq!(assemble("loadimm r1, 65536"), error(1, AsmErrorKind::ImmediateOutOfRange(65536)));
+    assert_eq!(assemble("loadimm r1, -32769"), error(1, AsmErrorKind::ImmediateOutOfRange(-32769)));
+    assert_eq!(assemble("trap 256"), error(1, AsmErrorKind::ImmediateOutOfRange(256)));
+    assert_eq!(assemble(&"exit\n".repeat(4097)), error(4097, AsmErrorKind::ProgramTooLarge));
+}
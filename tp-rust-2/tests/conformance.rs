@@ -0,0 +1,9 @@
+use interpreter::run_conformance;
+use std::path::Path;
+
+#[test]
+fn conformance_fixtures_pass() {
+    let result = run_conformance(Path::new("tests/conformance"));
+    assert!(result.is_ok(), "failing fixtures: {:?}", result.err());
+    assert!(result.unwrap() > 0);
+}
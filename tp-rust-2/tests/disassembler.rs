@@ -0,0 +1,179 @@
+use interpreter::{assemble, diff_programs, disassemble, Disassembled, Instruction, Mismatch, NOPCODES};
+
+/// Xorshift generator, enough to pick instructions without a fuzzing dependency
+struct Generator(u32);
+
+impl Generator {
+    fn next(&mut self) -> u32 {
+        self.0 ^= self.0 << 13;
+        self.0 ^= self.0 >> 17;
+        self.0 ^= self.0 << 5;
+        self.0
+    }
+
+    /// A register, r0 and r15 being picked more often
+    fn reg(&mut self) -> u8 {
+        match self.next() % 4 {
+            0 => 0,
+            1 => 15,
+            _ => (self.next() % 16) as u8,
+        }
+    }
+
+    /// A byte, boundary values being picked more often
+    fn byte(&mut self) -> u8 {
+        const BOUNDARIES: [u8; 5] = [0, 1, 0x7f, 0x80, 0xff];
+        match self.next() as usize % 8 {
+            i if i < BOUNDARIES.len() => BOUNDARIES[i],
+            _ => self.next() as u8,
+        }
+    }
+
+    /// A 16 bit immediate, boundary values being picked more often
+    fn imm16(&mut self) -> i16 {
+        const BOUNDARIES: [i16; 6] = [0, 1, -1, i16::MAX, i16::MIN, 0xff];
+        match self.next() as usize % 10 {
+            i if i < BOUNDARIES.len() => BOUNDARIES[i],
+            _ => self.next() as i16,
+        }
+    }
+
+    /// A random instruction of opcode `opcode`, from 1 to 14
+    fn instruction(&mut self, opcode: u8) -> Instruction {
+        use Instruction::*;
+        match opcode {
+            1 => MovIf(self.reg(), self.reg(), self.reg()),
+            2 => Store(self.reg(), self.reg()),
+            3 => Load(self.reg(), self.reg()),
+            4 => LoadImm(self.reg(), self.imm16()),
+            5 => Sub(self.reg(), self.reg(), self.reg()),
+            6 => Out(self.reg()),
+            7 => Exit,
+            8 => OutNumber(self.reg()),
+            9 => Add(self.reg(), self.reg(), self.reg()),
+            10 => Adc(self.reg(), self.reg(), self.reg()),
+            11 => Sbb(self.reg(), self.reg(), self.reg()),
+            12 => GetFlag(self.reg()),
+            13 => RdCycle(self.reg()),
+            14 => Trap(self.byte()),
+            _ => panic!("no opcode {opcode}"),
+        }
+    }
+
+    /// A sequence of `len` random instructions, each opcode being equally likely
+    fn program(&mut self, len: usize) -> Vec<Instruction> {
+        (0..len)
+            .map(|_| {
+                let opcode = (self.next() % (NOPCODES as u32 - 1)) as u8 + 1;
+                self.instruction(opcode)
+            })
+            .collect()
+    }
+}
+
+fn encode(instructions: &[Instruction]) -> Vec<u8> {
+    let mut code = Vec::new();
+    for instruction in instructions {
+        instruction.encode(&mut code);
+    }
+    code
+}
+
+/// Listing of a program as written by the disassembler
+fn listing(code: &[u8]) -> String {
+    disassemble(code).iter().map(|(_, item)| format!("{item}\n")).collect()
+}
+
+#[test]
+fn every_opcode_is_generated() {
+    let mut generator = Generator(1);
+    let mut seen = [false; NOPCODES];
+    for instruction in generator.program(1000) {
+        seen[instruction.opcode() as usize] = true;
+    }
+    assert!(seen[1..].iter().all(|&seen| seen));
+}
+
+#[test]
+fn boundary_immediates() {
+    use Instruction::*;
+    let instructions = [
+        LoadImm(0, 0),
+        LoadImm(15, i16::MAX),
+        LoadImm(1, i16::MIN),
+        LoadImm(2, -1),
+        Trap(0),
+        Trap(0x7f),
+        Trap(0x80),
+        Trap(0xff),
+    ];
+    let code = encode(&instructions);
+    assert_eq!(code[..12], [4, 0, 0, 0, 4, 15, 0xff, 0x7f, 4, 1, 0, 0x80]);
+    let items: Vec<_> = disassemble(&code).into_iter().map(|(_, item)| item).collect();
+    assert_eq!(items, instructions.map(Disassembled::Instruction));
+    assert_eq!(
+        listing(&code),
+        "loadimm r0, 0\nloadimm r15, 32767\nloadimm r1, -32768\nloadimm r2, -1\ntrap 0\ntrap 127\ntrap 128\ntrap 255\n"
+    );
+    assert_eq!(assemble(&listing(&code)).unwrap(), code);
+}
+
+#[test]
+fn random_programs_round_trip() {
+    let mut generator = Generator(0x5eed);
+    for len in 0..200 {
+        let instructions = generator.program(len);
+        let code = encode(&instructions);
+        let disassembled = disassemble(&code);
+        // Addresses follow the instruction sizes
+        let mut address = 0;
+        for (&(at, item), &instruction) in disassembled.iter().zip(&instructions) {
+            assert_eq!((at, item), (address, Disassembled::Instruction(instruction)));
+            assert_eq!(Instruction::decode(&code[at as usize..]).unwrap(), instruction);
+            address += instruction.size() as u32;
+        }
+        assert_eq!(disassembled.len(), instructions.len());
+        assert_eq!(assemble(&listing(&code)).unwrap(), code, "program {instructions:?}");
+    }
+}
+
+#[test]
+fn invalid_bytes_round_trip() {
+    // Opcode 0, register 16, opcode 15, then truncated loadimm, mov_if and store
+    let code = [0, 6, 16, 15, 7, 4, 1, 2];
+    let items: Vec<_> = disassemble(&code).into_iter().collect();
+    assert_eq!(
+        items,
+        [
+            (0, Disassembled::Byte(0)),
+            (1, Disassembled::Byte(6)),
+            (2, Disassembled::Byte(16)),
+            (3, Disassembled::Byte(15)),
+            (4, Disassembled::Instruction(Instruction::Exit)),
+            (5, Disassembled::Byte(4)),
+            (6, Disassembled::Byte(1)),
+            (7, Disassembled::Byte(2)),
+        ]
+        .map(|(at, item)| (at as u32, item))
+    );
+    assert_eq!(assemble(&listing(&code)).unwrap(), code);
+}
+
+#[test]
+fn diff_shows_mismatches_with_addresses() {
+    use Instruction::*;
+    let left = encode(&[LoadImm(1, 5), Out(1), Exit]);
+    let right = encode(&[LoadImm(1, 6), Out(1), Exit, Exit]);
+    assert!(diff_programs(&left, &left).is_empty());
+    assert_eq!(
+        diff_programs(&left, &right),
+        [
+            Mismatch {
+                index: 0,
+                left: Some((0, Disassembled::Instruction(LoadImm(1, 5)))),
+                right: Some((0, Disassembled::Instruction(LoadImm(1, 6)))),
+            },
+            Mismatch { index: 3, left: None, right: Some((7, Disassembled::Instruction(Exit))) },
+        ]
+    );
+}